@@ -0,0 +1,48 @@
+use chatclm::clm::clm_model::{ClmModel, Model};
+use chatclm::clm::ngram_model::BigramModel;
+use chatclm::clm::tokenizer::Tokenizer;
+use chatclm::clm::train_from_text;
+use chatclm::clm::training_options::TrainingOptions;
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use std::hint::black_box;
+
+#[path = "../src/clm/fixtures.rs"]
+mod fixtures;
+
+fn bench_encode(c: &mut Criterion) {
+    let text = fixtures::corpus(20_000);
+    let mut tokenizer = Tokenizer::new(5);
+    tokenizer.train(&text[..10_000], 40);
+
+    let mut group = c.benchmark_group("tokenizer");
+    group.throughput(Throughput::Bytes(text.len() as u64));
+    group.bench_function("encode_fast", |b| {
+        b.iter(|| tokenizer.encode_fast_opt(black_box(text.clone()), true))
+    });
+    group.finish();
+}
+
+fn bench_likelihoods(c: &mut Criterion) {
+    let text = fixtures::corpus(20_000);
+    let (model, tokenizer): (ClmModel, Tokenizer) = train_from_text(&text, fixtures::small_options()).unwrap();
+    let context = tokenizer.encode_fast_opt(text[..400].to_string(), true);
+    let all_tokens = tokenizer.get_tokens();
+
+    c.bench_function("clm_compute_likelihoods", |b| {
+        b.iter(|| model.compute_likelihoods(black_box(context.clone()), all_tokens).unwrap())
+    });
+}
+
+fn bench_bigram_training(c: &mut Criterion) {
+    let text = fixtures::corpus(20_000);
+    let mut tokenizer = Tokenizer::new(5);
+    tokenizer.train(&text[..10_000], 40);
+    let tokens = tokenizer.encode_fast_opt(text, true);
+
+    c.bench_function("bigram_train", |b| {
+        b.iter(|| BigramModel::train(black_box(tokens.clone()), TrainingOptions::default()).unwrap())
+    });
+}
+
+criterion_group!(benches, bench_encode, bench_likelihoods, bench_bigram_training);
+criterion_main!(benches);
@@ -1,23 +1,38 @@
-use chatclm::clm::evaluate::print_top_k_tokens;
-use chatclm::clm::inference::{decode_top_k_unweighted};
+use chatclm::clm::evaluate::{
+    compression_baseline, evaluate_with_options, print_top_k_tokens, score_candidates, EvalOptions,
+    InvalidDistributionHandling, ModelStats, StatsFormat,
+};
+use chatclm::clm::bench::{bench, BenchOptions};
+use chatclm::clm::inference::{Sampler, SamplingConfig, SamplingOverrides, StepRecord};
 use chatclm::clm::training_options::TrainingOptions;
 use std::collections::HashMap;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::sync::OnceLock;
 
 use chatclm::clm::clm_model::{ClmModel, Model};
 use chatclm::clm::tokenizer::{Token, Tokenizer};
 use dotenv::dotenv;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 
-use chatclm::clm::{save_run, uniform_model};
+use chatclm::clm::error::ClmError;
+use chatclm::clm::token_cache::TokenCache;
+use chatclm::clm::{find_model, latest_run, model_files, read_text_file, read_training_text, save_run, tokenizer_training_text, uniform_model};
 use clap::{Parser, Subcommand};
 
 const MODEL_PATH: &str = "./models/";
+/// Set from `--latest`, makes `load_model` pick the newest match
+static LATEST: OnceLock<bool> = OnceLock::new();
+const TOKEN_CACHE_PATH: &str = "./data/token_cache/";
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    /// Pick the newest matching model by the timestamp in its file name
+    #[arg(long, global = true)]
+    latest: bool,
 }
 
 #[derive(Subcommand)]
@@ -25,179 +40,642 @@ enum Commands {
     Train {
         #[arg(short, long, default_value_t = false)]
         use_default: bool,
+        /// The training and test files hold whitespace-separated token ids, skips training a tokenizer
+        #[arg(long)]
+        pretokenized: bool,
+        /// Pick the compression level from the corpus size, lower for large corpora
+        #[arg(long)]
+        auto_level: bool,
     },
     Evaluate {
         // this is not optional
         model: String,
+        /// Evaluate only this many randomly sampled positions
+        #[arg(long)]
+        sample: Option<usize>,
+        /// Seed for sampling the evaluated positions
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Score only this many consecutive positions, overrides the model's eval_max_positions
+        #[arg(long)]
+        max_positions: Option<usize>,
+        /// What to do with malformed distributions (fail, count, skip)
+        #[arg(long, default_value = "fail")]
+        on_invalid: InvalidDistributionHandling,
+        /// Record a histogram of the ground truth tokens' ranks
+        #[arg(long)]
+        record_ranks: bool,
+        /// Text separating documents in the test file, context is reset at each one
+        #[arg(long)]
+        document_delimiter: Option<String>,
+        /// Positions skipped at the start of each document
+        #[arg(long)]
+        document_warmup: Option<usize>,
+        /// How results are printed (json, csv)
+        #[arg(long, default_value = "json")]
+        output: StatsFormat,
     },
     Inference {
         model: String,
+        /// Overrides the model's default sampler: argmax, top-k:K, top-k-unweighted:K, top-p:P,
+        /// temperature:T or min-p:P
+        #[arg(long)]
+        sampler: Option<Sampler>,
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Print each step's top tokens and chosen token as a JSON line instead of text
+        #[arg(long)]
+        json_steps: bool,
+        /// Number of candidates shown per step, 0 hides them
+        #[arg(long, default_value_t = 10)]
+        show_top_k: usize,
+    },
+    /// Compares a model's bits per byte on its test file with plain zstd
+    Compare {
+        model: String,
+        /// Score only this many consecutive positions, overrides the model's eval_max_positions
+        #[arg(long)]
+        max_positions: Option<usize>,
+    },
+    /// Measures a model's inference throughput and latency on random contexts
+    Bench {
+        model: String,
+        /// Timed predictions
+        #[arg(long, default_value_t = 100)]
+        calls: usize,
+        /// Untimed predictions before the timed ones
+        #[arg(long, default_value_t = 5)]
+        warmup_calls: usize,
+        /// Tokens in each context
+        #[arg(long, default_value_t = 32)]
+        context_len: usize,
+    },
+    /// Tokenizes text with a model's tokenizer and reports how often greedy and BPE encoding agree
+    Encode {
+        model: String,
+        /// Text to encode, read from stdin if omitted
+        text: Option<String>,
+    },
+    /// Writes one of a model's dictionaries to a file usable with `zstd --dict`
+    ExportDictionary {
+        model: String,
+        /// Index of the dictionary in the ensemble
+        index: usize,
+        /// File the dictionary is written to
+        output: String,
+    },
+    /// Writes a model's encoded test corpus, or training corpus, to a file
+    Dump {
+        model: String,
+        /// File the encoded corpus is written to
+        output: String,
+        /// Dump the training files instead of the test file
+        #[arg(long)]
+        training: bool,
+        /// Write the tokens' text joined with a middle dot instead of token ids
+        #[arg(long)]
+        strings: bool,
+    },
+    /// Scores candidate continuations of a context, from most to least likely
+    Score {
+        model: String,
+        /// Text the candidates continue
+        context: String,
+        /// File with one candidate per line
+        candidates: String,
+    },
+    /// Reports statistics of the training text to help choose token_count and token_byte_size
+    Stats {
+        /// Training file, defaults to the one in the default training options
+        file: Option<String>,
+        /// Vocabulary size for the token count estimate
+        #[arg(long)]
+        token_count: Option<usize>,
+    },
+    /// Checks that a model loads, predicts valid distributions and round-trips text
+    #[command(alias = "doctor")]
+    SelfTest { model: String },
+    /// Keeps only the dictionaries compressing the test file best and saves the model as a new run
+    Prune {
+        model: String,
+        /// Number of dictionaries to keep
+        #[arg(long)]
+        keep: usize,
+    },
+    /// Compiles a model's dictionaries at another compression level and saves it as a new run,
+    /// without retraining them
+    Recompile {
+        model: String,
+        /// Level to compile at, must differ from the level the model uses now
+        #[arg(long, allow_hyphen_values = true)]
+        level: i32,
     },
 }
 
 fn main() {
     dotenv().ok();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let cli = Cli::parse();
+    LATEST.set(cli.latest).unwrap();
 
-    match &cli.command {
-        Some(Commands::Train { use_default }) => {
+    let result = match &cli.command {
+        Some(Commands::Train {
+            use_default,
+            pretokenized,
+            auto_level,
+        }) => {
             // Implement tuning logic here
-            train_model(use_default);
+            train_model(use_default, *pretokenized, *auto_level)
+        }
+        Some(Commands::Evaluate {
+            model,
+            sample,
+            seed,
+            max_positions,
+            on_invalid,
+            record_ranks,
+            document_delimiter,
+            document_warmup,
+            output,
+        }) => {
+            log::info!("Evaluating model: {}", model);
+            let eval_options = EvalOptions {
+                sample: *sample,
+                seed: *seed,
+                max_positions: *max_positions,
+                invalid_distributions: *on_invalid,
+                record_ranks: *record_ranks,
+                document_delimiter: document_delimiter.clone(),
+                document_warmup: *document_warmup,
+                ..EvalOptions::default()
+            };
+            eval_model(model, &eval_options, *output)
         }
-        Some(Commands::Evaluate { model }) => {
-            println!("Evaluating model: {}", model);
-            eval_model(model);
+        Some(Commands::Inference {
+            model,
+            sampler,
+            seed,
+            json_steps,
+            show_top_k,
+        }) => {
+            let overrides = SamplingOverrides {
+                sampler: *sampler,
+                seed: *seed,
+            };
+            inference(model, &overrides, *json_steps, *show_top_k)
         }
-        Some(Commands::Inference { model }) => inference(model),
+        Some(Commands::Compare { model, max_positions }) => compare(model, *max_positions),
+        Some(Commands::Bench {
+            model,
+            calls,
+            warmup_calls,
+            context_len,
+        }) => {
+            let bench_options = BenchOptions {
+                calls: *calls,
+                warmup_calls: *warmup_calls,
+                context_len: *context_len,
+                ..BenchOptions::default()
+            };
+            bench_model(model, &bench_options)
+        }
+        Some(Commands::Encode { model, text }) => encode(model, text.as_deref()),
+        Some(Commands::Prune { model, keep }) => prune_model(model, *keep),
+        Some(Commands::Recompile { model, level }) => recompile_model(model, *level),
+        Some(Commands::ExportDictionary { model, index, output }) => export_dictionary(model, *index, output),
+        Some(Commands::Dump {
+            model,
+            output,
+            training,
+            strings,
+        }) => dump(model, output, *training, *strings),
+        Some(Commands::SelfTest { model }) => self_test(model),
+        Some(Commands::Stats { file, token_count }) => corpus_stats(file.as_deref(), *token_count),
+        Some(Commands::Score {
+            model,
+            context,
+            candidates,
+        }) => score(model, context, candidates),
         None => {
             println!("No command provided, do something for real!");
+            Ok(())
         }
+    };
+
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
     }
 }
 
-fn load_train_tokens(training_options: &TrainingOptions, tokenizer: &Tokenizer) -> Vec<Token> {
-    // read training file
-    let train_text = read_file(&training_options.training_file);
-    // tokenize the text
-    let train_text_chars = (train_text.len() as f64 * training_options.dataset_percentage) as usize;
-    let train_text = train_text[..train_text_chars].to_string();
-    tokenizer.encode_fast(train_text)
+fn load_train_tokens(training_options: &TrainingOptions, tokenizer: &Tokenizer) -> Result<Vec<Token>, ClmError> {
+    TokenCache::new(TOKEN_CACHE_PATH).get_or_encode(training_options, tokenizer, || {
+        // read training files
+        let train_text = read_training_text(training_options)?;
+        if training_options.pretokenized {
+            let tokens = tokenizer.encode_token_ids(&train_text)?;
+            let train_tokens = (tokens.len() as f64 * training_options.dataset_percentage) as usize;
+            return Ok(tokens[..train_tokens].to_vec());
+        }
+        // tokenize the text
+        let train_text_chars = (train_text.len() as f64 * training_options.dataset_percentage) as usize;
+        let train_text = train_text[..train_text_chars].to_string();
+        Ok(tokenizer.encode_fast(train_text))
+    })
 }
 
-fn train_model(use_default: &bool) {
+fn train_model(use_default: &bool, pretokenized: bool, auto_level: bool) -> Result<(), ClmError> {
     // read training options JSON from stdin after program start
-    let training_options = if *use_default {
+    let mut training_options: TrainingOptions = if *use_default {
         TrainingOptions::default()
     } else {
         let mut input = String::new();
-        std::io::stdin()
-            .read_line(&mut input)
-            .expect("Failed to read line from stdin");
-        serde_json::from_str(&input).expect("Failed to parse JSON")
+        std::io::stdin().read_line(&mut input)?;
+        serde_json::from_str(&input)?
     };
+    training_options.pretokenized |= pretokenized;
+    training_options.auto_compression_level |= auto_level;
 
     println!("Training options: {:?}", training_options);
 
-    let train_text = read_file(&training_options.training_file);
+    let train_text = read_training_text(&training_options)?;
 
-    // train a tokenizer
-    println!("Training tokenizer...");
-    let mut tokenizer = Tokenizer::new(training_options.token_byte_size);
-    let tokenizer_training_count = std::cmp::min(train_text.len(), 50_000);
-    tokenizer.train(&train_text[..tokenizer_training_count], training_options.token_count);
+    let tokenizer = if training_options.pretokenized {
+        // the vocabulary covers every id in the training text, and at least token_count ids
+        let max_id = train_text
+            .split_whitespace()
+            .map(|id| id.parse::<usize>().map_err(|_| ClmError::Config(format!("invalid token id: {}", id))))
+            .try_fold(0, |max, id| id.map(|id| max.max(id)))?;
+        Tokenizer::for_token_ids(training_options.token_count.max(max_id + 1), training_options.token_byte_size)?
+    } else {
+        // train a tokenizer
+        let mut tokenizer = Tokenizer::for_training(&training_options);
+        let tokenizer_text = tokenizer_training_text(&train_text, &training_options);
+        println!("Training tokenizer on {} bytes...", tokenizer_text.len());
+        tokenizer.train(&tokenizer_text, training_options.token_count);
+        tokenizer
+    };
     println!("Tokenizing input...");
 
-    let train_tokens = load_train_tokens(&training_options, &tokenizer);
+    let train_tokens = load_train_tokens(&training_options, &tokenizer)?;
     println!("Training on {} tokens", train_tokens.len());
     println!("Training model...");
-    let model = ClmModel::train(train_tokens, training_options.clone());
-    save_run(MODEL_PATH, &model, tokenizer.clone());
+    let mut model = ClmModel::train(train_tokens, training_options.clone())?;
+    model.set_token_lengths(&tokenizer);
+    if training_options.auto_compression_level {
+        println!("Trained at compression level {}", model.options.train_compression_level);
+    }
+    let path = save_run(MODEL_PATH, &model, tokenizer)?;
+    println!("Saved model to {}", path);
     println!("Evaluating model...");
+    // evaluate the saved file, so a broken save shows up right away
+    let (model, tokenizer) = chatclm::clm::load(&path)?;
     // evaluate the model
-    let test_text = read_file(&training_options.test_file);
-    let stats = chatclm::clm::evaluate::evaluate(&model, test_text, &tokenizer);
-    println!("{:?}", serde_json::to_string(&stats).unwrap());
+    let test_text = read_text_file(&training_options.test_file, training_options.utf8_handling)?;
+    let eval_options = EvalOptions::from_training_options(&training_options);
+    let stats = evaluate_with_options(&model, test_text, &tokenizer, &eval_options)?;
+    println!("{:?}", serde_json::to_string(&stats)?);
     // save the model
+    Ok(())
 }
 
-fn inference(model_name: &str) {
+fn inference(model_name: &str, overrides: &SamplingOverrides, json_steps: bool, show_top_k: usize) -> Result<(), ClmError> {
     // create Vec<String> for all filenames in the model directory
-    let (model_files, chosen_model) = load_model(model_name);
+    let (model_files, chosen_model) = load_model(model_name)?;
 
     if let Some(file_name) = chosen_model {
         println!("Loading model: {}", file_name);
         let path = format!("{}{}", MODEL_PATH, file_name);
-        let ( model, tokenizer) = chatclm::clm::load(&path);
+        let ( model, tokenizer) = chatclm::clm::load(&path)?;
         let all_tokens = tokenizer.get_tokens();
+        let sampling = SamplingConfig::resolve(&model.options, overrides);
+        println!("Sampling: {:?}", sampling);
+        let mut rng = match sampling.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
 
         println!("Prompt: ");
         let mut input = String::new();
-        std::io::stdin()
-            .read_line(&mut input)
-            .expect("Failed to read line from stdin");
+        std::io::stdin().read_line(&mut input)?;
 
         let mut tokens = tokenizer.encode_fast_opt(input, true);
-        loop {
+        let reverse_tokens = tokenizer.reverse_map();
+        for step in 0.. {
             let likelihoods: HashMap<Token, f32> =
-                model.compute_likelihoods(tokens.clone(), &all_tokens);
-            print_top_k_tokens(&tokenizer, &likelihoods, 10);
+                model.compute_likelihoods(tokens.clone(), all_tokens)?;
+            if !json_steps {
+                print_top_k_tokens(&tokenizer, &likelihoods, show_top_k);
+            }
 
-            let next_token = decode_top_k_unweighted(&likelihoods, 1);
+            let next_token = sampling.sample(&likelihoods, &mut rng);
+            if json_steps {
+                let record = StepRecord::new(step, reverse_tokens, &likelihoods, show_top_k, &next_token);
+                println!("{}", serde_json::to_string(&record)?);
+            }
             tokens.push(next_token);
-            let text = tokenizer.decode_with_delimiters(&tokens);
-            println!("{}", text);
+            if !json_steps {
+                let text = tokenizer.decode_with_delimiters(&tokens);
+                println!("{}", text);
+            }
         }
     } else {
         println!("Model not found, available models: {:?}", model_files);
     }
+    Ok(())
 }
 
-fn eval_model(model_name: &str) {
+fn eval_model(model_name: &str, eval_options: &EvalOptions, format: StatsFormat) -> Result<(), ClmError> {
     // create Vec<String> for all filenames in the model directory
-    let (model_files, chosen_model) = load_model(model_name);
+    let (model_files, chosen_model) = load_model(model_name)?;
     if let Some(file_name) = chosen_model {
         let path = format!("{}{}", MODEL_PATH, file_name);
-        let (model, tokenizer) = chatclm::clm::load(&path);
-        let test_text = read_file(&model.options.test_file);
-        // evaluate the model
-        let stats = chatclm::clm::evaluate::evaluate(&model, test_text.clone(), &tokenizer);
-        println!("{:?}", serde_json::to_string(&stats).unwrap());
+        let (model, tokenizer) = chatclm::clm::load(&path)?;
+        let eval_options = &EvalOptions {
+            max_positions: eval_options.max_positions.or(model.options.eval_max_positions),
+            pretokenized: model.options.pretokenized,
+            ..eval_options.clone()
+        };
+        let test_text = read_text_file(&model.options.test_file, model.options.utf8_handling)?;
 
+        // train the baselines with the same options
+        log::info!("Loading training tokens...");
+        let training_tokens = load_train_tokens(&model.options, &tokenizer)?;
+        log::info!("Training baseline models...");
+        let uniform_model = uniform_model::UniformModel::train(training_tokens.clone(), model.options.clone())?;
+        let ngram_model = chatclm::clm::ngram_model::BigramModel::train(training_tokens.clone(), model.options.clone())?;
+        let unigram_model = chatclm::clm::ngram_model::UnigramModel::train(training_tokens.clone(), model.options.clone())?;
+        let interpolated_model = chatclm::clm::ngram_model::InterpolatedModel::train(training_tokens, model.options.clone())?;
+        // every model skips the longest warmup, so the diffs cover the same positions
+        let eval_options = &eval_options.clone().with_shared_warmup([
+            model.recommended_warmup(),
+            uniform_model.recommended_warmup(),
+            ngram_model.recommended_warmup(),
+            unigram_model.recommended_warmup(),
+            interpolated_model.recommended_warmup(),
+        ]);
 
-        // train a ngram model with the same options
-        println!("Loading training tokens...");
-        let training_tokens = load_train_tokens(&model.options, &tokenizer);
-        
-        println!("Evaluating uniform model...");
-        let uniform_model = uniform_model::UniformModel::train(
-            training_tokens.clone(),
-            model.options.clone(),
-        );
-        let uniform_stats = chatclm::clm::evaluate::evaluate(&uniform_model, test_text.clone(), &tokenizer);
-        println!("{:?}", serde_json::to_string(&uniform_stats).unwrap());
-        println!("Evaluating bigram model...");
-        let ngram_model = chatclm::clm::ngram_model::BigramModel::train(
-            training_tokens.clone(),
-            model.options.clone(),
-        );
-        let ngram_stats = chatclm::clm::evaluate::evaluate(&ngram_model, test_text.clone(), &tokenizer);
-        println!("{:?}", serde_json::to_string(&ngram_stats).unwrap());
+        // evaluate the model
+        let stats = evaluate_with_options(&model, test_text.clone(), &tokenizer, eval_options)?;
+        // Prints a baseline's stats, compared to the model's in JSON mode
+        let report = |name: String, baseline: &ModelStats| -> Result<(), ClmError> {
+            match format {
+                StatsFormat::Json => {
+                    println!("{}: {:?}", name, serde_json::to_string(baseline)?);
+                    if name != model.name() {
+                        println!("{} vs {}: {}", model.name(), name, stats.diff(baseline));
+                    }
+                }
+                StatsFormat::Csv => println!("{}", baseline.to_csv_row(&name, &model.options)),
+            }
+            Ok(())
+        };
+        if format == StatsFormat::Csv {
+            println!("{}", ModelStats::csv_header());
+        }
+        report(model.name(), &stats)?;
 
-        println!("Training unigram model...");
-        let unigram_model = chatclm::clm::ngram_model::UnigramModel::train(
-            training_tokens,
-            model.options.clone(),
-        );
-        let unigram_stats = chatclm::clm::evaluate::evaluate(&unigram_model, test_text, &tokenizer);
-        println!("{:?}", serde_json::to_string(&unigram_stats).unwrap());
+        log::info!("Evaluating uniform model...");
+        let uniform_stats = evaluate_with_options(&uniform_model, test_text.clone(), &tokenizer, eval_options)?;
+        report(uniform_model.name(), &uniform_stats)?;
+        log::info!("Evaluating bigram model...");
+        let ngram_stats = evaluate_with_options(&ngram_model, test_text.clone(), &tokenizer, eval_options)?;
+        report(ngram_model.name(), &ngram_stats)?;
+        log::info!("Evaluating unigram model...");
+        let unigram_stats = evaluate_with_options(&unigram_model, test_text.clone(), &tokenizer, eval_options)?;
+        report(unigram_model.name(), &unigram_stats)?;
+        log::info!("Evaluating interpolated model...");
+        let interpolated_stats = evaluate_with_options(&interpolated_model, test_text, &tokenizer, eval_options)?;
+        report(interpolated_model.name(), &interpolated_stats)?;
         
     } else {
         println!("Model not found, available models: {:?}", model_files);
     }
     // load the model
+    Ok(())
 }
 
-fn load_model(model_name: &str) -> (Vec<String>, Option<String>) {
-    let mut model_files = Vec::new();
-    let paths = std::fs::read_dir(MODEL_PATH).unwrap();
-    let mut chosen_model = None;
-    for path in paths {
-        let path = path.unwrap().path();
-        if path.is_file() {
-            let file_name = path.file_name().unwrap().to_str().unwrap();
-            model_files.push(file_name.to_string());
-            if file_name.contains(model_name) {
-                chosen_model = Some(file_name.to_string());
+fn compare(model_name: &str, max_positions: Option<usize>) -> Result<(), ClmError> {
+    let (model_files, chosen_model) = load_model(model_name)?;
+    if let Some(file_name) = chosen_model {
+        let path = format!("{}{}", MODEL_PATH, file_name);
+        let (model, tokenizer) = chatclm::clm::load(&path)?;
+        let test_text = read_text_file(&model.options.test_file, model.options.utf8_handling)?;
+        let eval_options = EvalOptions {
+            max_positions: max_positions.or(model.options.eval_max_positions),
+            ..EvalOptions::from_training_options(&model.options)
+        };
+        let stats = evaluate_with_options(&model, test_text.clone(), &tokenizer, &eval_options)?;
+        let baseline = compression_baseline(&test_text, model.options.inference_level())?;
+        println!("{}: {:.4} bits per byte", model.name(), stats.bits_per_byte);
+        println!(
+            "zstd: {:.4} bits per byte (ratio {:.2}, level {})",
+            baseline.bits_per_byte, baseline.ratio, model.options.inference_level()
+        );
+    } else {
+        println!("Model not found, available models: {:?}", model_files);
+    }
+    Ok(())
+}
+
+fn bench_model(model_name: &str, bench_options: &BenchOptions) -> Result<(), ClmError> {
+    let (model_files, chosen_model) = load_model(model_name)?;
+    if let Some(file_name) = chosen_model {
+        let path = format!("{}{}", MODEL_PATH, file_name);
+        let (model, tokenizer) = chatclm::clm::load(&path)?;
+        let stats = bench(&model, tokenizer.get_tokens(), bench_options)?;
+        println!("{}: {}", model.name(), serde_json::to_string(&stats)?);
+    } else {
+        println!("Model not found, available models: {:?}", model_files);
+    }
+    Ok(())
+}
+
+fn export_dictionary(model_name: &str, index: usize, output: &str) -> Result<(), ClmError> {
+    let (model_files, chosen_model) = load_model(model_name)?;
+    if let Some(file_name) = chosen_model {
+        let path = format!("{}{}", MODEL_PATH, file_name);
+        let (model, _) = chatclm::clm::load(&path)?;
+        model.export_dictionary(index, output)?;
+        println!("Wrote dictionary {} to {}", index, output);
+    } else {
+        println!("Model not found, available models: {:?}", model_files);
+    }
+    Ok(())
+}
+
+fn dump(model_name: &str, output: &str, training: bool, strings: bool) -> Result<(), ClmError> {
+    let (model_files, chosen_model) = load_model(model_name)?;
+    if let Some(file_name) = chosen_model {
+        let path = format!("{}{}", MODEL_PATH, file_name);
+        let (model, tokenizer) = chatclm::clm::load(&path)?;
+        let tokens = if training {
+            load_train_tokens(&model.options, &tokenizer)?
+        } else {
+            let test_text = read_text_file(&model.options.test_file, model.options.utf8_handling)?;
+            if model.options.pretokenized {
+                tokenizer.encode_token_ids(&test_text)?
+            } else {
+                tokenizer.encode_fast(test_text)
+            }
+        };
+        let mut out = std::io::BufWriter::new(std::fs::File::create(output)?);
+        if strings {
+            writeln!(out, "{}", tokenizer.decode_with_delimiters(&tokens))?;
+        } else {
+            tokenizer.write_token_ids(&tokens, &mut out)?;
+        }
+        out.flush()?;
+        println!("Wrote {} tokens to {}", tokens.len(), output);
+    } else {
+        println!("Model not found, available models: {:?}", model_files);
+    }
+    Ok(())
+}
+
+fn encode(model_name: &str, text: Option<&str>) -> Result<(), ClmError> {
+    let (model_files, chosen_model) = load_model(model_name)?;
+    if let Some(file_name) = chosen_model {
+        let path = format!("{}{}", MODEL_PATH, file_name);
+        let (_, tokenizer) = chatclm::clm::load(&path)?;
+        let text = match text {
+            Some(text) => text.to_string(),
+            None => {
+                let mut input = String::new();
+                std::io::stdin().read_to_string(&mut input)?;
+                input
             }
+        };
+        let tokens = tokenizer.encode_fast_opt(text.clone(), true);
+        println!("{}", tokenizer.decode_with_delimiters(&tokens));
+        println!("Tokens: {}", tokens.len());
+        println!("Chars per token: {:.3}", tokenizer.chars_per_token(&text));
+        println!(
+            "Greedy/BPE agreement: {:.2}%",
+            tokenizer.tokenization_agreement(&text) * 100.0
+        );
+    } else {
+        println!("Model not found, available models: {:?}", model_files);
+    }
+    Ok(())
+}
+
+fn score(model_name: &str, context: &str, candidates_file: &str) -> Result<(), ClmError> {
+    let (model_files, chosen_model) = load_model(model_name)?;
+    if let Some(file_name) = chosen_model {
+        let path = format!("{}{}", MODEL_PATH, file_name);
+        let (model, tokenizer) = chatclm::clm::load(&path)?;
+        let candidates: Vec<String> = read_text_file(candidates_file, model.options.utf8_handling)?
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect();
+        for score in score_candidates(&model, &tokenizer, context, &candidates)? {
+            println!("{:.6} {:.4} {:?}", score.probability, score.log_prob, score.candidate);
+        }
+    } else {
+        println!("Model not found, available models: {:?}", model_files);
+    }
+    Ok(())
+}
+
+fn corpus_stats(file: Option<&str>, token_count: Option<usize>) -> Result<(), ClmError> {
+    let defaults = TrainingOptions::default();
+    let options = TrainingOptions {
+        training_file: file.map_or(defaults.training_file.clone(), String::from),
+        token_count: token_count.unwrap_or(defaults.token_count),
+        ..defaults
+    };
+    let text = read_training_text(&options)?;
+    let stats = chatclm::clm::corpus_stats(&text, &options);
+    println!("Characters: {}", stats.chars);
+    println!("Unique characters: {}", stats.unique_chars);
+    println!("Most frequent characters:");
+    for (c, count) in &stats.top_chars {
+        println!(" {:?}: {}", c, count);
+    }
+    println!(
+        "Estimated tokens at vocab size {}: {}",
+        stats.vocab_size, stats.estimated_tokens
+    );
+    Ok(())
+}
+
+/// Bytes of the test file used as the self-test sample
+const SELF_TEST_SAMPLE_BYTES: usize = 4096;
+
+fn self_test(model_name: &str) -> Result<(), ClmError> {
+    let (model_files, chosen_model) = load_model(model_name)?;
+    if let Some(file_name) = chosen_model {
+        let path = format!("{}{}", MODEL_PATH, file_name);
+        let (model, tokenizer) = chatclm::clm::load(&path)?;
+        // Fall back to the vocabulary if the test file isn't available on this machine
+        let sample = match read_text_file(&model.options.test_file, model.options.utf8_handling) {
+            Ok(text) => text.chars().scan(0, |bytes, c| {
+                *bytes += c.len_utf8();
+                (*bytes <= SELF_TEST_SAMPLE_BYTES).then_some(c)
+            }).collect(),
+            Err(_) => tokenizer.get_str_tokens().join(""),
+        };
+        let report = chatclm::clm::self_test::self_test(&model, &tokenizer, &sample);
+        println!("{}: {}", model.name(), report);
+        if !report.passed {
+            return Err(ClmError::Evaluation(format!("{} failed the self-test", file_name)));
         }
+    } else {
+        println!("Model not found, available models: {:?}", model_files);
     }
-    (model_files, chosen_model)
+    Ok(())
 }
 
-fn read_file(file_path: &str) -> String {
-    let mut file = std::fs::File::open(file_path).expect(&format!("Could not open {}", file_path));
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)
-        .expect("Failed to read file");
-    contents
+/// Tokens of the test file the dictionaries are ranked on when pruning
+const PRUNE_SAMPLE_TOKENS: usize = 16_384;
+
+fn prune_model(model_name: &str, keep: usize) -> Result<(), ClmError> {
+    let (model_files, chosen_model) = load_model(model_name)?;
+    if let Some(file_name) = chosen_model {
+        let path = format!("{}{}", MODEL_PATH, file_name);
+        let (mut model, tokenizer) = chatclm::clm::load(&path)?;
+        // Rank the dictionaries on the start of the test file, which they weren't trained on
+        let test_text = read_text_file(&model.options.test_file, model.options.utf8_handling)?;
+        let mut held_out = if model.options.pretokenized {
+            tokenizer.encode_token_ids(&test_text)?
+        } else {
+            tokenizer.encode_fast(test_text)
+        };
+        held_out.truncate(PRUNE_SAMPLE_TOKENS);
+        for stat in model.dictionary_stats(&held_out)? {
+            println!("{:?}", stat);
+        }
+        model.keep_top(keep, &held_out)?;
+        println!("Keeping {} dictionaries", model.options.ensemble_size);
+        let path = save_run(MODEL_PATH, &model, tokenizer)?;
+        println!("Saved pruned model to {}", path);
+    } else {
+        println!("Model not found, available models: {:?}", model_files);
+    }
+    Ok(())
+}
+
+fn recompile_model(model_name: &str, level: i32) -> Result<(), ClmError> {
+    let (model_files, chosen_model) = load_model(model_name)?;
+    if let Some(file_name) = chosen_model {
+        let path = format!("{}{}", MODEL_PATH, file_name);
+        let (mut model, tokenizer) = chatclm::clm::load(&path)?;
+        println!("Recompiling at level {}, was {}", level, model.options.inference_level());
+        model.recompile(level)?;
+        let path = save_run(MODEL_PATH, &model, tokenizer)?;
+        println!("Saved recompiled model to {}", path);
+    } else {
+        println!("Model not found, available models: {:?}", model_files);
+    }
+    Ok(())
+}
+
+fn load_model(model_name: &str) -> Result<(Vec<String>, Option<String>), ClmError> {
+    // --latest resolves several matches by their timestamps instead of failing
+    if LATEST.get().copied().unwrap_or(false) {
+        return Ok((model_files(MODEL_PATH)?, latest_run(MODEL_PATH, model_name)?));
+    }
+    find_model(MODEL_PATH, model_name)
 }
@@ -0,0 +1,115 @@
+use crate::clm::clm_model::Model;
+use crate::clm::error::ClmError;
+use crate::clm::tokenizer::Token;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// The synthetic workload `bench` times
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Timed `compute_likelihoods` calls, each predicts one token
+    pub calls: usize,
+    /// Untimed calls before the timed ones, e.g. to warm caches
+    pub warmup_calls: usize,
+    /// Tokens in each context
+    pub context_len: usize,
+    /// Seed for drawing the contexts from the vocabulary, so runs are comparable
+    pub seed: u64,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            calls: 100,
+            warmup_calls: 5,
+            context_len: 32,
+            seed: 0,
+        }
+    }
+}
+
+/// Inference throughput of a model, without the warmup calls
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BenchStats {
+    pub calls: usize,
+    /// Predicted tokens per second, i.e. calls per second
+    pub tokens_per_second: f64,
+    pub median_latency_ms: f64,
+    pub p95_latency_ms: f64,
+}
+
+/// Times `compute_likelihoods` on random contexts over `all_tokens`, independent of any
+/// test text. Unlike `evaluate` this measures only speed, not how good the predictions are.
+pub fn bench<M: Model>(model: &M, all_tokens: &[Token], options: &BenchOptions) -> Result<BenchStats, ClmError> {
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let mut random_context = || -> Vec<Token> {
+        if all_tokens.is_empty() {
+            return Vec::new();
+        }
+        (0..options.context_len)
+            .map(|_| all_tokens[rng.random_range(0..all_tokens.len())].clone())
+            .collect()
+    };
+
+    for _ in 0..options.warmup_calls {
+        model.compute_likelihoods(random_context(), all_tokens)?;
+    }
+
+    let mut latencies: Vec<Duration> = Vec::with_capacity(options.calls);
+    for _ in 0..options.calls {
+        let context = random_context();
+        let start = Instant::now();
+        // Dropped after the timing, freeing the map isn't part of the prediction
+        let _likelihoods = model.compute_likelihoods(context, all_tokens)?;
+        latencies.push(start.elapsed());
+    }
+
+    let total: Duration = latencies.iter().sum();
+    latencies.sort_unstable();
+    let percentile = |p: f64| {
+        latencies
+            .get(((latencies.len() as f64 * p).ceil() as usize).saturating_sub(1))
+            .map_or(0.0, |latency| latency.as_secs_f64() * 1000.0)
+    };
+    Ok(BenchStats {
+        calls: latencies.len(),
+        tokens_per_second: if total.is_zero() { 0.0 } else { latencies.len() as f64 / total.as_secs_f64() },
+        median_latency_ms: percentile(0.5),
+        p95_latency_ms: percentile(0.95),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clm::train_from_text;
+    use crate::clm::training_options::TrainingOptions;
+
+    #[test]
+    fn test_bench_reports_throughput() {
+        let text = "the cat sat on the mat and the dog ran far away. ".repeat(200);
+        let options = TrainingOptions {
+            token_count: 40,
+            k: 64,
+            ensemble_size: 1,
+            training_chunk_size: 64,
+            nb_threads: 4,
+            ..TrainingOptions::default()
+        };
+        let (model, tokenizer) = train_from_text(&text, options).unwrap();
+
+        let bench_options = BenchOptions {
+            calls: 10,
+            warmup_calls: 2,
+            context_len: 8,
+            seed: 1,
+        };
+        let stats = bench(&model, tokenizer.get_tokens(), &bench_options).unwrap();
+        assert_eq!(stats.calls, 10);
+        assert!(stats.tokens_per_second > 0.0);
+        assert!(stats.median_latency_ms > 0.0);
+        assert!(stats.p95_latency_ms >= stats.median_latency_ms);
+    }
+}
@@ -0,0 +1,182 @@
+use crate::clm::clm_model::Model;
+use crate::clm::error::ClmError;
+use crate::clm::tokenizer::Token;
+use crate::clm::training_options::TrainingOptions;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// Number of contexts `CachingModel::train` keeps
+const DEFAULT_CACHE_CAPACITY: usize = 4096;
+
+/// The context, cut to the window, and a hash of the tokens it was predicted over
+type CacheKey = (Vec<Token>, u64);
+
+/// Wraps a model and memoizes its likelihoods, e.g. for repeated queries or evaluating
+/// the same text twice. Contexts that agree on their last `context_window` tokens share
+/// an entry, so the window must cover everything the wrapped model looks at.
+pub struct CachingModel<M: Model> {
+    model: M,
+    context_window: Option<usize>,
+    capacity: usize,
+    cache: Mutex<LikelihoodCache>,
+}
+
+#[derive(Default)]
+struct LikelihoodCache {
+    entries: HashMap<CacheKey, HashMap<Token, f32>>,
+    /// Keys from oldest to newest, the oldest is evicted first
+    order: VecDeque<CacheKey>,
+}
+
+impl<M: Model> CachingModel<M> {
+    /// Caches at most `capacity` contexts, keyed by their last `context_window` tokens
+    /// or the whole context if none
+    pub fn new(model: M, context_window: Option<usize>, capacity: usize) -> Self {
+        CachingModel {
+            model,
+            context_window,
+            capacity,
+            cache: Mutex::new(LikelihoodCache::default()),
+        }
+    }
+
+    /// The wrapped model
+    pub fn inner(&self) -> &M {
+        &self.model
+    }
+
+    /// Number of cached contexts
+    pub fn cached_contexts(&self) -> usize {
+        self.cache.lock().unwrap().entries.len()
+    }
+
+    fn key(&self, current_text: &[Token], all_tokens: &[Token]) -> CacheKey {
+        let start = self
+            .context_window
+            .map_or(0, |window| current_text.len().saturating_sub(window));
+        let mut hasher = DefaultHasher::new();
+        all_tokens.hash(&mut hasher);
+        (current_text[start..].to_vec(), hasher.finish())
+    }
+}
+
+impl<M: Model> Model for CachingModel<M> {
+    /// Trains the wrapped model, keying the cache by its `context_window`
+    fn train(tokens: Vec<Token>, options: TrainingOptions) -> Result<Self, ClmError> {
+        let context_window = options.context_window;
+        Ok(CachingModel::new(M::train(tokens, options)?, Some(context_window), DEFAULT_CACHE_CAPACITY))
+    }
+
+    fn compute_likelihoods(
+        &self,
+        current_text: Vec<Token>,
+        all_tokens: &[Token],
+    ) -> Result<HashMap<Token, f32>, ClmError> {
+        let key = self.key(&current_text, all_tokens);
+        if let Some(likelihoods) = self.cache.lock().unwrap().entries.get(&key) {
+            return Ok(likelihoods.clone());
+        }
+
+        let likelihoods = self.model.compute_likelihoods(current_text, all_tokens)?;
+        if self.capacity > 0 {
+            let mut cache = self.cache.lock().unwrap();
+            while cache.entries.len() >= self.capacity {
+                let Some(oldest) = cache.order.pop_front() else {
+                    break;
+                };
+                cache.entries.remove(&oldest);
+            }
+            if cache.entries.insert(key.clone(), likelihoods.clone()).is_none() {
+                cache.order.push_back(key);
+            }
+        }
+        Ok(likelihoods)
+    }
+
+    fn memory_footprint(&self) -> usize {
+        let cache = self.cache.lock().unwrap();
+        let cached_bytes: usize = cache
+            .entries
+            .iter()
+            .map(|((context, _), likelihoods)| {
+                context.iter().map(|token| token.capacity()).sum::<usize>()
+                    + likelihoods.capacity() * std::mem::size_of::<(Token, f32)>()
+                    + likelihoods.keys().map(|token| token.capacity()).sum::<usize>()
+            })
+            .sum();
+        std::mem::size_of::<Self>() + self.model.memory_footprint() + cached_bytes
+    }
+
+    fn name(&self) -> String {
+        format!("cached {}", self.model.name())
+    }
+
+    fn recommended_warmup(&self) -> usize {
+        self.model.recommended_warmup()
+    }
+
+    /// Forwards the prior and forgets everything predicted without it
+    fn with_prior(&mut self, prior: HashMap<Token, f32>) {
+        self.model.with_prior(prior);
+        *self.cache.lock().unwrap() = LikelihoodCache::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Predicts more weight for the token after the last one and counts its calls
+    struct CountingModel {
+        calls: AtomicUsize,
+    }
+
+    impl Model for CountingModel {
+        fn train(_tokens: Vec<Token>, _options: TrainingOptions) -> Result<Self, ClmError> {
+            Ok(CountingModel { calls: AtomicUsize::new(0) })
+        }
+
+        fn compute_likelihoods(&self, current_text: Vec<Token>, all_tokens: &[Token]) -> Result<HashMap<Token, f32>, ClmError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let last = current_text.last().map_or(0, |token| token[0]);
+            let weights: Vec<f32> = all_tokens.iter().map(|token| if token[0] == last + 1 { 3.0 } else { 1.0 }).collect();
+            let sum: f32 = weights.iter().sum();
+            Ok(all_tokens.iter().cloned().zip(weights.into_iter().map(|weight| weight / sum)).collect())
+        }
+
+        fn memory_footprint(&self) -> usize {
+            0
+        }
+
+        fn name(&self) -> String {
+            String::from("counting")
+        }
+    }
+
+    #[test]
+    fn test_repeated_contexts_hit_the_cache() {
+        let all_tokens: Vec<Token> = vec![vec![1], vec![2], vec![3]];
+        let model = CachingModel::new(CountingModel::train(Vec::new(), TrainingOptions::default()).unwrap(), Some(2), 2);
+
+        let first = model.compute_likelihoods(vec![vec![1], vec![2]], &all_tokens).unwrap();
+        let second = model.compute_likelihoods(vec![vec![1], vec![2]], &all_tokens).unwrap();
+        assert_eq!(first, second);
+        // Only the last two tokens matter
+        assert_eq!(model.compute_likelihoods(vec![vec![3], vec![1], vec![2]], &all_tokens).unwrap(), first);
+        assert_eq!(model.inner().calls.load(Ordering::SeqCst), 1);
+
+        // A different vocabulary is a different query
+        model.compute_likelihoods(vec![vec![1], vec![2]], &all_tokens[..2]).unwrap();
+        assert_eq!(model.inner().calls.load(Ordering::SeqCst), 2);
+
+        // The oldest context is evicted once the capacity is reached
+        model.compute_likelihoods(vec![vec![2]], &all_tokens).unwrap();
+        assert_eq!(model.cached_contexts(), 2);
+        model.compute_likelihoods(vec![vec![1], vec![2]], &all_tokens).unwrap();
+        assert_eq!(model.inner().calls.load(Ordering::SeqCst), 4);
+        assert_eq!(model.name(), "cached counting");
+    }
+}
@@ -1,27 +1,334 @@
-use crate::clm::tokenizer::Token;
+use crate::clm::tokenizer::{Token, Tokenizer};
+use crate::clm::error::ClmError;
+use crate::clm::progress::ProgressBarBuilder;
 use crate::clm::trainer::train_model;
-use crate::clm::training_options::TrainingOptions;
+use crate::clm::training_options::{
+    auto_compression_level, ContextWindowUnit, InferenceMode, RegularizationMode, TrainingOptions,
+};
 use rayon::prelude::*;
-use core::panic;
 use std::cmp::min;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use human_bytes::human_bytes;
 
+/// Positions at the start of the text, or of each document, that only serve as context
+/// unless the model recommends otherwise
+pub const DEFAULT_EVAL_WARMUP: usize = 32;
+
+/// Fewest positions skipped in evaluation, so no context is empty
+pub const MIN_EVAL_WARMUP: usize = 2;
+
 pub trait Model {
     /// Trains a new model on the provided data with the given parameters
-    fn train(tokens: Vec<Token>, options: TrainingOptions) -> Self;
+    fn train(tokens: Vec<Token>, options: TrainingOptions) -> Result<Self, ClmError>
+    where
+        Self: Sized;
 
-    /// Computes the likelihood of each possible next token
+    /// Computes the likelihood of each possible next token.
+    /// Returns an empty map if `all_tokens` is empty.
     fn compute_likelihoods(
         &self,
         current_text: Vec<Token>,
         all_tokens: &[Token],
-    ) -> HashMap<Token, f32>;
+    ) -> Result<HashMap<Token, f32>, ClmError>;
+
+    /// Computes the likelihoods for many contexts at once, allowing models to share work between them
+    fn compute_likelihoods_batch(
+        &self,
+        contexts: &[Vec<Token>],
+        all_tokens: &[Token],
+    ) -> Result<Vec<HashMap<Token, f32>>, ClmError> {
+        contexts
+            .iter()
+            .map(|context| self.compute_likelihoods(context.clone(), all_tokens))
+            .collect()
+    }
+
+    /// Encodes `text` with the tokenizer and computes the likelihoods of the token after it
+    fn likelihoods_for_text(
+        &self,
+        tokenizer: &Tokenizer,
+        text: &str,
+        all_tokens: &[Token],
+    ) -> Result<HashMap<Token, f32>, ClmError> {
+        self.compute_likelihoods(tokenizer.encode_fast_opt(text.to_string(), true), all_tokens)
+    }
+
+    /// Computes the likelihoods as a dense vector in the order of `all_tokens`,
+    /// e.g. the stable order of `Tokenizer::get_tokens`
+    fn compute_likelihoods_vec(
+        &self,
+        current_text: Vec<Token>,
+        all_tokens: &[Token],
+    ) -> Result<Vec<f32>, ClmError> {
+        let likelihoods = self.compute_likelihoods(current_text, all_tokens)?;
+        Ok(all_tokens
+            .iter()
+            .map(|token| likelihoods.get(token).copied().unwrap_or(0.0))
+            .collect())
+    }
+
+    /// Computes the natural log of each possible next token's likelihood in f64.
+    /// Models that can should compute it in log space, so tiny likelihoods don't underflow to 0.
+    fn compute_log_likelihoods(
+        &self,
+        current_text: Vec<Token>,
+        all_tokens: &[Token],
+    ) -> Result<HashMap<Token, f64>, ClmError> {
+        Ok(to_log_likelihoods(self.compute_likelihoods(current_text, all_tokens)?))
+    }
+
+    /// Computes the log-likelihoods for many contexts at once, like `compute_likelihoods_batch`
+    fn compute_log_likelihoods_batch(
+        &self,
+        contexts: &[Vec<Token>],
+        all_tokens: &[Token],
+    ) -> Result<Vec<HashMap<Token, f64>>, ClmError> {
+        contexts
+            .iter()
+            .map(|context| self.compute_log_likelihoods(context.clone(), all_tokens))
+            .collect()
+    }
+
+    /// Computes the log-likelihoods like `compute_log_likelihoods`, letting models keep
+    /// work in `cache` that the next, usually one token longer, context can reuse
+    fn compute_log_likelihoods_incremental(
+        &self,
+        current_text: &[Token],
+        all_tokens: &[Token],
+        _cache: &mut CompressionCache,
+    ) -> Result<HashMap<Token, f64>, ClmError> {
+        self.compute_log_likelihoods(current_text.to_vec(), all_tokens)
+    }
+
+    /// Computes the likelihoods of only the allowed tokens, normalized over them,
+    /// e.g. for constrained generation
+    fn compute_likelihoods_masked(
+        &self,
+        current_text: Vec<Token>,
+        allowed_tokens: &[Token],
+    ) -> Result<HashMap<Token, f32>, ClmError> {
+        let mut likelihoods = self.compute_likelihoods(current_text, allowed_tokens)?;
+        let allowed: std::collections::HashSet<&Token> = allowed_tokens.iter().collect();
+        likelihoods.retain(|token, _| allowed.contains(token));
+        let sum: f32 = likelihoods.values().sum();
+        if sum > 0.0 {
+            likelihoods.values_mut().for_each(|likelihood| *likelihood /= sum);
+        }
+        Ok(likelihoods)
+    }
+
+    /// Estimates the memory used by the model in bytes
+    fn memory_footprint(&self) -> usize;
+
+    /// A short label for the model and its key options, used in evaluation output
+    fn name(&self) -> String;
+
+    /// Positions at the start of each evaluated document that only serve as context,
+    /// enough for the model to see its full context. Evaluation skips them unless
+    /// `EvalOptions::document_warmup` is set.
+    fn recommended_warmup(&self) -> usize {
+        DEFAULT_EVAL_WARMUP
+    }
+
+    /// Injects a prior distribution that is blended into every prediction with weight
+    /// `TrainingOptions::prior_weight`, so all models can be regularized the same way.
+    /// Models that don't support a prior ignore it.
+    fn with_prior(&mut self, _prior: HashMap<Token, f32>) {}
+}
+
+/// Caches compressed sizes between consecutive predictions.
+/// Only the sizes of the previous and the current prediction are kept, since
+/// the context of one prediction is usually a candidate text of the one before.
+/// Once the context fills the model's window it slides with every token, so the next
+/// context is never a candidate text and candidate sizes aren't kept anymore.
+#[derive(Default)]
+pub struct CompressionCache {
+    previous: HashMap<(usize, Vec<u8>), usize>,
+    current: HashMap<(usize, Vec<u8>), usize>,
+    hits: usize,
+    misses: usize,
+}
+
+impl CompressionCache {
+    /// Starts a new prediction, forgetting everything but the last one
+    fn advance(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+
+    /// Looks up the size of `text` compressed with dictionary `index`, compressing it on a
+    /// miss. The size is only kept for the next prediction if `store` is set.
+    fn get_or_compress(
+        &mut self,
+        index: usize,
+        text: Vec<u8>,
+        store: bool,
+        compress: impl FnOnce(&[u8]) -> Result<usize, ClmError>,
+    ) -> Result<usize, ClmError> {
+        let key = (index, text);
+        if let Some(size) = self.current.get(&key) {
+            self.hits += 1;
+            return Ok(*size);
+        }
+        let size = match self.previous.remove_entry(&key) {
+            Some((_, size)) => {
+                self.hits += 1;
+                size
+            }
+            None => {
+                self.misses += 1;
+                compress(&key.1)?
+            }
+        };
+        if store {
+            self.current.insert(key, size);
+        }
+        Ok(size)
+    }
+
+    /// Sizes found in the cache so far
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Sizes that had to be compressed so far
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    /// Sizes currently kept for the next prediction
+    pub fn stored(&self) -> usize {
+        self.current.len()
+    }
+}
+
+fn to_log_likelihoods(likelihoods: HashMap<Token, f32>) -> HashMap<Token, f64> {
+    likelihoods
+        .into_iter()
+        .map(|(token, likelihood)| (token, (likelihood as f64).ln()))
+        .collect()
+}
+
+/// ln(e^a + e^b) without leaving log space
+fn log_add_exp(a: f64, b: f64) -> f64 {
+    let max = a.max(b);
+    if max == f64::NEG_INFINITY {
+        return max;
+    }
+    max + ((a - max).exp() + (b - max).exp()).ln()
+}
+
+/// Shifts the log-likelihoods so their likelihoods sum to 1
+fn log_normalize(log_likelihoods: &mut HashMap<Token, f64>) {
+    let log_sum = log_likelihoods.values().fold(f64::NEG_INFINITY, |sum, &l| log_add_exp(sum, l));
+    log_likelihoods.values_mut().for_each(|l| *l -= log_sum);
+}
+
+/// Sets likelihoods in `[-epsilon, 0)`, which only come from rounding, to 0 and renormalizes
+/// if any changed. More negative values are left alone, so real errors are still caught.
+pub fn clamp_rounding_errors(likelihoods: &mut HashMap<Token, f32>, epsilon: f32) {
+    let mut clamped = false;
+    for likelihood in likelihoods.values_mut() {
+        if *likelihood < 0.0 && *likelihood >= -epsilon {
+            *likelihood = 0.0;
+            clamped = true;
+        }
+    }
+    if clamped {
+        let sum: f32 = likelihoods.values().sum();
+        if sum > 0.0 {
+            likelihoods.values_mut().for_each(|likelihood| *likelihood /= sum);
+        }
+    }
+}
+
+/// Mixes a prior injected with `Model::with_prior` into a distribution as
+/// (1 - weight) * likelihood + weight * prior, renormalized over the predicted tokens.
+/// `TrainingOptions::validate_inference` keeps the weight within `0..=1`; rounding errors of the
+/// inputs are clamped like `clamp_rounding_errors`.
+pub fn blend_prior(likelihoods: &mut HashMap<Token, f32>, prior: &HashMap<Token, f32>, weight: f32, clamp_epsilon: f32) {
+    if prior.is_empty() {
+        return;
+    }
+    for (token, likelihood) in likelihoods.iter_mut() {
+        *likelihood = (1.0 - weight) * *likelihood + weight * prior.get(token).copied().unwrap_or(0.0);
+    }
+    clamp_rounding_errors(likelihoods, clamp_epsilon);
+    let sum: f32 = likelihoods.values().sum();
+    if sum > 0.0 {
+        likelihoods.values_mut().for_each(|likelihood| *likelihood /= sum);
+    }
+}
+
+/// Concatenates the token codes into the byte stream seen by the compressor,
+/// optionally marking token boundaries with a delimiter byte after each token
+pub fn flatten_tokens(tokens: &[Token], delimiter: Option<u8>) -> Vec<u8> {
+    match delimiter {
+        Some(delimiter) => tokens
+            .iter()
+            .flat_map(|token| token.iter().copied().chain(std::iter::once(delimiter)))
+            .collect(),
+        None => tokens.iter().flatten().copied().collect(),
+    }
+}
+
+/// Dictionary sizes tried when `TrainingOptions::tune_dictionary_size` is set,
+/// as fractions of the chunk size
+pub const DICTIONARY_SIZE_CANDIDATES: [f64; 4] = [0.02, 0.04, 0.08, 0.16];
+
+/// Share of the probability mass spread over all tokens when predicting training frequencies
+const UNSEEN_TOKEN_SHARE: f64 = 1e-3;
+
+/// Weight every context length gets in `TrainingOptions::adaptive_context_lengths` on top of
+/// its compressibility, so contexts that don't compress at all still count a little
+const ADAPTIVE_CONTEXT_WEIGHT_FLOOR: f64 = 0.01;
+
+/// Most validation positions scored per candidate by `ClmModel::tune_dictionary_size`
+const TUNE_VALIDATION_POSITIONS: usize = 200;
+
+/// Length in tokens of the n-grams `TrainingOptions::dedup_chunks` compares chunks by
+const SHINGLE_TOKENS: usize = 4;
+
+/// Smallest shingle hashes kept per chunk to estimate how similar two chunks are
+const SHINGLE_SKETCH_SIZE: usize = 256;
+
+/// Estimated share of shared shingles from which `dedup_chunks` treats two chunks as repeats
+const DEDUP_SIMILARITY: f64 = 0.8;
+
+/// How `ClmModel::train` splits `TrainingOptions::thread_budget` between chunks trained
+/// in parallel and the threads fastCover uses for each chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadPlan {
+    /// Chunks trained at the same time
+    pub chunk_workers: usize,
+    /// `nb_threads` passed to fastCover for each chunk
+    pub zdict_threads: u32,
+}
+
+impl ThreadPlan {
+    /// Prefers parallel chunks, which scale better than fastCover's threads, and gives
+    /// the rest of the budget to fastCover, never using more than `thread_budget` threads
+    pub fn new(thread_budget: usize, chunks: usize, nb_threads: u32) -> Self {
+        let thread_budget = thread_budget.max(1);
+        let chunk_workers = chunks.clamp(1, thread_budget);
+        let zdict_threads = (thread_budget / chunk_workers).clamp(1, nb_threads.max(1) as usize) as u32;
+        ThreadPlan {
+            chunk_workers,
+            zdict_threads,
+        }
+    }
 }
 
 pub struct ClmModel {
     _dictionaries: Vec<Vec<u8>>,
     zstd_cdicts: Vec<*mut zstd_sys::ZSTD_CDict>,
+    /// Relative frequency of each token in the training data, used by `RegularizationMode::UnigramPrior`
+    unigram_prior: HashMap<Token, f64>,
+    /// Prior injected with `Model::with_prior`, empty if none
+    prior: HashMap<Token, f32>,
+    /// Length in characters of each token's text, used by `TrainingOptions::length_normalize`
+    token_chars: HashMap<Token, usize>,
     pub options: TrainingOptions,
 }
 
@@ -37,65 +344,426 @@ impl Drop for ClmModel {
 }
 
 impl Model for ClmModel {
-    fn train(tokens: Vec<Token>, options: TrainingOptions) -> Self {
+    /// Trains one dictionary per chunk of `ensemble_size` chunks, failing if one can't be trained.
+    ///
+    /// Each chunk is trained on its own and the results are collected in chunk order, so the
+    /// number of rayon workers doesn't change the dictionaries. fastCover itself has no random
+    /// seed; its only source of nondeterminism is its internal multithreading, which the
+    /// `deterministic` option turns off.
+    ///
+    /// With `train_time_budget_secs` set, chunks are dispatched in waves of one chunk per worker
+    /// thread and no wave starts after the budget elapsed, so the model keeps the dictionaries of
+    /// the waves that started in time, at least the first one. A wave that started in time still
+    /// finishes, so training can take up to one wave longer than the budget.
+    fn train(tokens: Vec<Token>, mut options: TrainingOptions) -> Result<Self, ClmError> {
+        options.validate()?;
+        if options.auto_compression_level {
+            let corpus_bytes: usize = tokens.iter().map(|token| token.len()).sum();
+            options.train_compression_level = auto_compression_level(corpus_bytes);
+            log::info!(
+                "Auto-selected compression level {} for {} of training data",
+                options.train_compression_level,
+                human_bytes(corpus_bytes as f64)
+            );
+        }
+        if let Some(delimiter) = options.token_delimiter {
+            // The delimiter only marks token boundaries if the codes themselves never contain it
+            let clashes = tokens.iter().filter(|token| token.contains(&delimiter)).count();
+            if clashes > 0 {
+                log::warn!(
+                    "token_delimiter {:#04x} occurs inside {} of {} tokens, train the tokenizer with the same delimiter",
+                    delimiter,
+                    clashes,
+                    tokens.len()
+                );
+            }
+        }
+        if options.tune_dictionary_size && tokens.len() >= 100 {
+            // Tune on a held-out tail, which is still used for the final ensemble
+            let split = tokens.len() - (tokens.len() / 20).min(TUNE_VALIDATION_POSITIONS + 1);
+            options.dictionary_size_percentage = Self::tune_dictionary_size(
+                &tokens[..split],
+                &tokens[split..],
+                &options,
+                &DICTIONARY_SIZE_CANDIDATES,
+            )?;
+            log::info!("Tuned dictionary size percentage: {}", options.dictionary_size_percentage);
+        }
+
         // split up the tokens into options.ensemble_size chunks
         let chunk_size = (tokens.len() as f64 / options.ensemble_size as f64)
             .ceil() as usize;
 
-        let chunks = tokens.chunks(chunk_size);
+        let mut chunks: Vec<&[Token]> = tokens.chunks(chunk_size).collect();
+        if options.dedup_chunks {
+            // Repeated chunks, even shifted or slightly edited, would train near identical dictionaries
+            let mut kept: Vec<Vec<u64>> = Vec::new();
+            let before = chunks.len();
+            chunks.retain(|chunk| {
+                let sketch = shingle_sketch(chunk);
+                if kept.iter().any(|other| sketch_similarity(&sketch, other) >= DEDUP_SIMILARITY) {
+                    return false;
+                }
+                kept.push(sketch);
+                true
+            });
+            if chunks.len() < before {
+                log::info!("Skipping {} duplicate chunks", before - chunks.len());
+            }
+        }
 
         // Create a progress bar for training chunks
-        let progress_bar = indicatif::ProgressBar::new(options.ensemble_size as u64);
-        progress_bar.set_style(
-            indicatif::ProgressStyle::default_bar()
-                .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} chunks ({msg})")
-                .unwrap()
-                .progress_chars("#>-")
-        );
+        let progress_bar = ProgressBarBuilder::new(chunks.len() as u64).unit("chunks").build();
 
-        // Train each chunk
-        let chunk_results: Vec<Vec<u8>> = chunks
-            .enumerate()
-            .collect::<Vec<_>>()
-            .into_par_iter()
-            .map(|(i, chunk)| {
-            progress_bar.set_message(format!("Chunk {}: {}", i, human_bytes(chunk.len() as f64)));
-            let dict = train_model(chunk, &options);
-            progress_bar.inc(1);
-            dict
-            })
-            .collect();
+        let start = std::time::Instant::now();
+        let budget = options.train_time_budget_secs.map(std::time::Duration::from_secs_f64);
+
+        // Split the thread budget between parallel chunks and fastCover's own threads
+        let thread_plan = options
+            .thread_budget
+            .map(|thread_budget| ThreadPlan::new(thread_budget, chunks.len(), options.nb_threads));
+        let chunk_options = match thread_plan {
+            Some(plan) => TrainingOptions {
+                nb_threads: plan.zdict_threads,
+                ..options.clone()
+            },
+            None => options.clone(),
+        };
+
+        // Train each chunk. A parallel iterator starts a chunk on every worker right away, so
+        // with a budget the chunks are dispatched in waves and the budget is checked in between.
+        let train_chunks = || -> Vec<Result<Vec<u8>, ClmError>> {
+            let wave_size = match budget {
+                Some(_) => rayon::current_num_threads(),
+                None => chunks.len(),
+            };
+            let mut results = Vec::with_capacity(chunks.len());
+            for (wave, wave_chunks) in chunks.chunks(wave_size.max(1)).enumerate() {
+                // The first wave is always trained, so the model has at least one dictionary
+                if wave > 0 && budget.is_some_and(|budget| start.elapsed() >= budget) {
+                    progress_bar.inc((chunks.len() - results.len()) as u64);
+                    break;
+                }
+                let offset = results.len();
+                results.par_extend(wave_chunks.par_iter().enumerate().map(|(i, chunk)| {
+                    progress_bar.set_message(format!("Chunk {}: {}", offset + i, human_bytes(chunk.len() as f64)));
+                    let dict = train_model(chunk, &chunk_options);
+                    progress_bar.inc(1);
+                    dict
+                }));
+            }
+            results
+        };
+        let chunk_results = match thread_plan {
+            Some(plan) => {
+                log::info!(
+                    "Training {} chunks in parallel with {} fastCover threads each",
+                    plan.chunk_workers, plan.zdict_threads
+                );
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(plan.chunk_workers)
+                    .build()
+                    .map_err(|err| ClmError::Config(format!("failed to build thread pool: {}", err)))?;
+                pool.install(train_chunks)
+            }
+            None => train_chunks(),
+        };
 
         progress_bar.finish_with_message("Training complete");
+        let chunk_results = chunk_results.into_iter().collect::<Result<Vec<_>, _>>()?;
+        if budget.is_some() {
+            log::info!(
+                "Trained {} dictionaries in {:.1}s",
+                chunk_results.len(),
+                start.elapsed().as_secs_f64()
+            );
+        }
+
+        log::info!("Training complete. Creating compression dictionaries...");
+
+        // The token count may not split into exactly ensemble_size chunks, and a time budget
+        // may have stopped training early
+        let options = TrainingOptions {
+            ensemble_size: chunk_results.len(),
+            ..options
+        };
+
+        let mut model = Self::from_dictionaries(chunk_results, options)?;
+        model.unigram_prior = unigram_frequencies(&tokens);
+        Ok(model)
+    }
 
-        println!("Training complete. Creating compression dictionaries...");
+    fn compute_likelihoods(
+        &self,
+        current_text: Vec<Token>,
+        all_tokens: &[Token],
+    ) -> Result<HashMap<Token, f32>, ClmError> {
+        self.compute_likelihoods_cached(&current_text, all_tokens, &mut CompressionCache::default())
+    }
+
+    fn compute_likelihoods_batch(
+        &self,
+        contexts: &[Vec<Token>],
+        all_tokens: &[Token],
+    ) -> Result<Vec<HashMap<Token, f32>>, ClmError> {
+        let mut cache = CompressionCache::default();
+        contexts
+            .iter()
+            .map(|context| self.compute_likelihoods_cached(context, all_tokens, &mut cache))
+            .collect()
+    }
+
+    fn compute_log_likelihoods(
+        &self,
+        current_text: Vec<Token>,
+        all_tokens: &[Token],
+    ) -> Result<HashMap<Token, f64>, ClmError> {
+        self.compute_log_likelihoods_cached(&current_text, all_tokens, &mut CompressionCache::default())
+    }
 
-        let zstd_cdicts = chunk_results
+    fn compute_log_likelihoods_batch(
+        &self,
+        contexts: &[Vec<Token>],
+        all_tokens: &[Token],
+    ) -> Result<Vec<HashMap<Token, f64>>, ClmError> {
+        let mut cache = CompressionCache::default();
+        contexts
             .iter()
-            .map(|dict| unsafe {
-                zstd_sys::ZSTD_createCDict(
-                    dict.as_ptr() as *const _,
-                    dict.len(),
-                    options.train_compression_level as i32,
-                )
+            .map(|context| self.compute_log_likelihoods_cached(context, all_tokens, &mut cache))
+            .collect()
+    }
+
+    fn compute_log_likelihoods_incremental(
+        &self,
+        current_text: &[Token],
+        all_tokens: &[Token],
+        cache: &mut CompressionCache,
+    ) -> Result<HashMap<Token, f64>, ClmError> {
+        self.compute_log_likelihoods_cached(current_text, all_tokens, cache)
+    }
+
+    /// Only compresses the allowed tokens, so a small mask is much faster than the full vocabulary
+    fn compute_likelihoods_masked(
+        &self,
+        current_text: Vec<Token>,
+        allowed_tokens: &[Token],
+    ) -> Result<HashMap<Token, f32>, ClmError> {
+        self.compute_likelihoods_cached(&current_text, allowed_tokens, &mut CompressionCache::default())
+    }
+
+    fn memory_footprint(&self) -> usize {
+        let dictionary_bytes: usize = self._dictionaries.iter().map(|dict| dict.capacity()).sum();
+        let cdict_bytes: usize = self
+            .zstd_cdicts
+            .iter()
+            .map(|cdict| unsafe { zstd_sys::ZSTD_sizeof_CDict(*cdict) })
+            .sum();
+        std::mem::size_of::<Self>() + dictionary_bytes + cdict_bytes
+    }
+
+    fn name(&self) -> String {
+        format!("clm[ens={},tbs={}]", self.options.ensemble_size, self.options.token_byte_size)
+    }
+
+    /// The context window, or longest adaptive context, in tokens
+    fn recommended_warmup(&self) -> usize {
+        let window = match self.options.adaptive_context_lengths.iter().max() {
+            Some(&longest) => longest,
+            None => match self.options.context_window_unit {
+                ContextWindowUnit::Tokens => self.options.context_window,
+                ContextWindowUnit::Bytes => self.options.context_window.div_ceil(self.options.token_byte_size.max(1)),
+            },
+        };
+        window.max(MIN_EVAL_WARMUP)
+    }
+
+    fn with_prior(&mut self, prior: HashMap<Token, f32>) {
+        self.prior = prior;
+    }
+}
+
+impl ClmModel {
+    /// Trains one dictionary per candidate `dictionary_size_percentage` on the first ensemble
+    /// chunk of `tokens` and returns the candidate whose single-dictionary model has the lowest
+    /// cross-entropy on `validation`. Ties go to the earlier, usually smaller, candidate.
+    pub fn tune_dictionary_size(
+        tokens: &[Token],
+        validation: &[Token],
+        options: &TrainingOptions,
+        candidates: &[f64],
+    ) -> Result<f64, ClmError> {
+        if candidates.is_empty() {
+            return Err(ClmError::Config("no dictionary size candidates to tune".to_string()));
+        }
+        let chunk_size = (tokens.len() as f64 / options.ensemble_size.max(1) as f64).ceil() as usize;
+        let chunk = &tokens[..chunk_size.min(tokens.len())];
+
+        let mut vocabulary: Vec<Token> = tokens.iter().chain(validation).cloned().collect();
+        vocabulary.sort_unstable();
+        vocabulary.dedup();
+        let contexts: Vec<Vec<Token>> = (1..validation.len().min(TUNE_VALIDATION_POSITIONS + 1))
+            .map(|pos| validation[..pos].to_vec())
+            .collect();
+
+        let cross_entropies = candidates
+            .par_iter()
+            .map(|&percentage| {
+                let options = TrainingOptions {
+                    dictionary_size_percentage: percentage,
+                    ensemble_size: 1,
+                    ..options.clone()
+                };
+                let model = Self::from_dictionaries(vec![train_model(chunk, &options)?], options)?;
+                let log_likelihoods = model.compute_log_likelihoods_batch(&contexts, &vocabulary)?;
+                let total: f64 = log_likelihoods
+                    .iter()
+                    .zip(&validation[1..])
+                    .map(|(log_likelihoods, token)| -log_likelihoods[token])
+                    .sum();
+                Ok(total / contexts.len().max(1) as f64)
             })
-            .collect::<Vec<*mut zstd_sys::ZSTD_CDict>>();
+            .collect::<Result<Vec<f64>, ClmError>>()?;
 
-        ClmModel {
-            _dictionaries: chunk_results,
-            zstd_cdicts,
-            options,
+        let best = cross_entropies
+            .iter()
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(index, _)| index)
+            .unwrap_or(0);
+        Ok(candidates[best])
+    }
+
+    /// Computes the likelihoods like `compute_likelihoods`, reusing compressed sizes
+    /// from the previous prediction stored in `cache`
+    pub fn compute_likelihoods_cached(
+        &self,
+        current_text: &[Token],
+        all_tokens: &[Token],
+        cache: &mut CompressionCache,
+    ) -> Result<HashMap<Token, f32>, ClmError> {
+        // Exponentials are never negative, so there are no rounding errors to clamp
+        Ok(self
+            .compute_log_likelihoods_cached(current_text, all_tokens, cache)?
+            .into_iter()
+            .map(|(token, log_likelihood)| (token, log_likelihood.exp() as f32))
+            .collect())
+    }
+
+    /// Computes the log-likelihoods like `compute_log_likelihoods`, reusing compressed sizes
+    /// from the previous prediction stored in `cache`
+    pub fn compute_log_likelihoods_cached(
+        &self,
+        current_text: &[Token],
+        all_tokens: &[Token],
+        cache: &mut CompressionCache,
+    ) -> Result<HashMap<Token, f64>, ClmError> {
+        let mut log_likelihoods = self.compressed_log_likelihoods(current_text, all_tokens, cache)?;
+        if !self.prior.is_empty() {
+            // The log-space version of `blend_prior`
+            let weight = self.options.prior_weight;
+            for (token, log_likelihood) in log_likelihoods.iter_mut() {
+                let prior = self.prior.get(token).copied().unwrap_or(0.0) as f64;
+                *log_likelihood = log_add_exp((1.0 - weight).ln() + *log_likelihood, (weight * prior).ln());
+            }
+            log_normalize(&mut log_likelihoods);
         }
+        Ok(log_likelihoods)
     }
 
-    fn compute_likelihoods(
+    fn compressed_log_likelihoods(
         &self,
-        current_text: Vec<Token>,
+        current_text: &[Token],
+        all_tokens: &[Token],
+        cache: &mut CompressionCache,
+    ) -> Result<HashMap<Token, f64>, ClmError> {
+        cache.advance();
+        if all_tokens.is_empty() {
+            return Ok(HashMap::new());
+        }
+        if !self.options.adaptive_context_lengths.is_empty() {
+            return self.adaptive_context_log_likelihoods(current_text, all_tokens, cache);
+        }
+        let start = self.context_start(current_text);
+        let context = &current_text[start..];
+        // In bytes a context just short of the window may still not grow by a long token,
+        // which only keeps a few sizes that won't be looked up
+        let grows = start == 0
+            && match self.options.context_window_unit {
+                ContextWindowUnit::Tokens => context.len() < self.options.context_window,
+                ContextWindowUnit::Bytes => context.iter().map(|token| token.len()).sum::<usize>() < self.options.context_window,
+            };
+        self.context_log_likelihoods(context, grows, all_tokens, cache)
+    }
+
+    /// Blends the distributions predicted from each of `adaptive_context_lengths` last tokens,
+    /// weighting a context by the fraction of its bytes the dictionaries save when compressing
+    /// it. A context the dictionaries know well compresses well, so its prediction is trusted more.
+    fn adaptive_context_log_likelihoods(
+        &self,
+        current_text: &[Token],
+        all_tokens: &[Token],
+        cache: &mut CompressionCache,
+    ) -> Result<HashMap<Token, f64>, ClmError> {
+        let lengths = adaptive_context_lengths(&self.options.adaptive_context_lengths, current_text.len());
+        let mut weighted: Vec<(f64, HashMap<Token, f64>)> = Vec::with_capacity(lengths.len());
+        for (length, grows) in lengths {
+            let context = &current_text[current_text.len() - length..];
+            let base_text = flatten_tokens(context, self.options.token_delimiter);
+            let raw_size = base_text.len();
+            let compressed_size = self
+                .zstd_cdicts
+                .iter()
+                .enumerate()
+                .map(|(index, cdict)| cache.get_or_compress(index, base_text.clone(), true, |text| ClmModel::compress(cdict, text)))
+                .sum::<Result<usize, ClmError>>()? as f64
+                / self.zstd_cdicts.len() as f64;
+            let saved_fraction = if raw_size == 0 {
+                0.0
+            } else {
+                (1.0 - compressed_size / raw_size as f64).max(0.0)
+            };
+            weighted.push((saved_fraction + ADAPTIVE_CONTEXT_WEIGHT_FLOOR, self.context_log_likelihoods(context, grows, all_tokens, cache)?));
+        }
+
+        let total_weight: f64 = weighted.iter().map(|(weight, _)| weight).sum();
+        let mut log_likelihoods: HashMap<Token, f64> = HashMap::with_capacity(all_tokens.len());
+        for (weight, distribution) in weighted {
+            let ln_weight = (weight / total_weight).ln();
+            for (token, log_likelihood) in distribution {
+                let entry = log_likelihoods.entry(token).or_insert(f64::NEG_INFINITY);
+                *entry = log_add_exp(*entry, ln_weight + log_likelihood);
+            }
+        }
+        log_normalize(&mut log_likelihoods);
+        Ok(log_likelihoods)
+    }
+
+    /// Predicts the next token after exactly `context`.
+    ///
+    /// With `InferenceMode::MarginalSize` a token's score is the bytes it adds to the compressed
+    /// context, an estimate of -log p(token | context) in units of `inference_basis`.
+    ///
+    /// With `InferenceMode::Predictive` the dictionary is used as a predictor primed by the
+    /// context: the bytes the token compresses to alone, with only the dictionary, estimate
+    /// -log p(token), so the difference to the bytes it adds after the context measures how
+    /// much less surprising the context made it, i.e. log p(token | context) - log p(token).
+    /// Bayes' rule turns this ratio back into a distribution by adding the token's training
+    /// frequency as log p(token), which drops the dictionary's own, poorer, estimate of it.
+    ///
+    /// `grows` tells whether the next prediction's context is this one plus a token, i.e.
+    /// whether the candidate sizes are worth keeping in `cache`.
+    fn context_log_likelihoods(
+        &self,
+        context: &[Token],
+        grows: bool,
         all_tokens: &[Token],
-    ) -> HashMap<Token, f32> {
-        let context_size = min(current_text.len(), self.options.context_window);
-        let context_start = current_text.len() - context_size;
-        let context = current_text[context_start..].to_vec();
+        cache: &mut CompressionCache,
+    ) -> Result<HashMap<Token, f64>, ClmError> {
+        // Without a context the dictionaries only see the token itself, so they'd score its literal size
+        if context.is_empty() && !self.unigram_prior.is_empty() {
+            return Ok(self.marginal_log_likelihoods(all_tokens));
+        }
+        let context = context.to_vec();
 
         let mut scores: HashMap<Token, f64> = HashMap::new();
         
@@ -104,58 +772,232 @@ impl Model for ClmModel {
             scores.insert(token.clone(), 0.0);
         }
 
-        for cdict in &self.zstd_cdicts {
-            let base_text = context.iter().flatten().copied().collect::<Vec<u8>>();
-            let base_size = ClmModel::compress(cdict, base_text);
-            for token in all_tokens.iter() {
-                let mut new_text = context.clone();
-                new_text.push(token.clone());
-                let raw_new_text = new_text.iter().flatten().copied().collect::<Vec<u8>>();
+        let base_text = flatten_tokens(&context, self.options.token_delimiter);
+        let base_sizes: Vec<usize> = self
+            .zstd_cdicts
+            .iter()
+            .enumerate()
+            .map(|(index, cdict)| cache.get_or_compress(index, base_text.clone(), true, |text| ClmModel::compress(cdict, text)))
+            .collect::<Result<_, _>>()?;
+        let weights = self.ensemble_weights(&base_sizes);
 
-                let compressed_size = ClmModel::compress(cdict, raw_new_text);
-                
-                // Add the compressed size to the token's total score
-                *scores.get_mut(token).unwrap() += (compressed_size as f64 - base_size as f64) / self.zstd_cdicts.len() as f64;
+        for (index, cdict) in self.zstd_cdicts.iter().enumerate() {
+            let base_size = base_sizes[index];
+            let mut size_deltas: Vec<f64> = all_tokens
+                .iter()
+                .map(|token| {
+                    let mut new_text = context.clone();
+                    new_text.push(token.clone());
+                    let raw_new_text = flatten_tokens(&new_text, self.options.token_delimiter);
+
+                    let compressed_size = cache.get_or_compress(index, raw_new_text, grows, |text| ClmModel::compress(cdict, text))?;
+                    let mut size_delta = compressed_size as f64 - base_size as f64;
+                    if self.options.inference_mode == InferenceMode::Predictive {
+                        // The same for every context, so it's kept however the window slides
+                        let token_text = flatten_tokens(std::slice::from_ref(token), self.options.token_delimiter);
+                        let alone_size = cache.get_or_compress(index, token_text, true, |text| ClmModel::compress(cdict, text))?;
+                        size_delta -= alone_size as f64;
+                    }
+                    // Longer tokens naturally add more bytes, so optionally score them per character
+                    Ok(if self.options.length_normalize {
+                        size_delta / self.token_chars(token) as f64
+                    } else {
+                        size_delta
+                    })
+                })
+                .collect::<Result<_, ClmError>>()?;
+            if self.options.per_dict_normalize {
+                standardize(&mut size_deltas);
+            }
+
+            // Add the compressed size to the token's total score
+            for (token, size_delta) in all_tokens.iter().zip(size_deltas) {
+                *scores.get_mut(token).unwrap() += size_delta * weights[index];
             }
         }
 
-        let inverted_scores: HashMap<Token, f64> = scores
-            .iter()
-            .map(|(k, v)| (k.clone(), self.options.inference_basis.powf(- v)))
+        // basis^-score normalized over the tokens, computed as a log-softmax so tokens
+        // far behind the best one keep a finite log-likelihood instead of underflowing
+        let ln_basis = self.options.inference_basis.ln();
+        let mut log_likelihoods: HashMap<Token, f64> = scores
+            .into_iter()
+            .map(|(k, v)| (k, -v * ln_basis))
             .collect();
-        let sum: f64 = inverted_scores.values().cloned().sum();
-        let softmax_scores: HashMap<Token, f32> = inverted_scores
+        if self.options.inference_mode == InferenceMode::Predictive && !self.unigram_prior.is_empty() {
+            // Without a prior the base rate is uniform, which normalizing leaves as is
+            for (token, base_rate) in self.marginal_log_likelihoods(all_tokens) {
+                *log_likelihoods.get_mut(&token).unwrap() += base_rate;
+            }
+        }
+        log_normalize(&mut log_likelihoods);
+
+        if self.options.regularization_mode == RegularizationMode::None {
+            return Ok(log_likelihoods);
+        }
+
+        // regularize the scores, without a prior (e.g. for models from other tools) fall back to a uniform floor
+        let floor = |token: &Token| match self.options.regularization_mode {
+            RegularizationMode::UnigramPrior if !self.unigram_prior.is_empty() => {
+                self.unigram_prior.get(token).copied().unwrap_or(0.0)
+            }
+            _ => 1.0 / all_tokens.len() as f64,
+        };
+        for (k, v) in log_likelihoods.iter_mut() {
+            *v = log_add_exp(*v, (self.options.regularization * floor(k)).ln());
+        }
+
+        // normalize the scores
+        log_normalize(&mut log_likelihoods);
+        Ok(log_likelihoods)
+    }
+
+    /// The training frequency of each token, for an empty context. Without a context zstd
+    /// mostly stores the token as literals, so every size delta would be close to the
+    /// token's byte length. Tokens never seen in training keep a small share.
+    fn marginal_log_likelihoods(&self, all_tokens: &[Token]) -> HashMap<Token, f64> {
+        let floor = UNSEEN_TOKEN_SHARE / all_tokens.len() as f64;
+        let mut log_likelihoods: HashMap<Token, f64> = all_tokens
             .iter()
-            .map(|(k, v)| (k.clone(), (*v / sum) as f32))
+            .map(|token| {
+                let frequency = self.unigram_prior.get(token).copied().unwrap_or(0.0);
+                (token.clone(), (frequency + floor).ln())
+            })
             .collect();
+        log_normalize(&mut log_likelihoods);
+        log_likelihoods
+    }
 
-        // regularize the scores
-        let mut regularized_scores: HashMap<Token, f32> = HashMap::new();
-        for (k, v) in softmax_scores.iter() {
-            let regularized_score =
-                v + (self.options.regularization as f32 / all_tokens.len() as f32);
-            regularized_scores.insert(k.clone(), regularized_score);
+    /// How many bytes each dictionary needs to append `token` to the context, i.e. every
+    /// dictionary's `compressed_size - base_size`, minus the token's size alone with
+    /// `InferenceMode::Predictive`. `compute_likelihoods` averages these (weighted with
+    /// `adaptive_ensemble`, rescaled with `length_normalize` or `per_dict_normalize`) into the
+    /// token's score, so a dictionary with a smaller delta pushes the token up.
+    /// Predictions that don't come from a single context's deltas, for an empty context or
+    /// with `adaptive_context_lengths`, can't be explained this way.
+    pub fn explain_prediction(&self, context: &[Token], token: &Token) -> Result<Vec<f64>, ClmError> {
+        if !self.options.adaptive_context_lengths.is_empty() {
+            return Err(ClmError::Config(
+                "predictions blended over adaptive_context_lengths have no single set of deltas".to_string(),
+            ));
+        }
+        let context = &context[self.context_start(context)..];
+        if context.is_empty() && !self.unigram_prior.is_empty() {
+            return Err(ClmError::Config(
+                "an empty context predicts the training frequencies, not the dictionaries".to_string(),
+            ));
         }
+        let base_text = flatten_tokens(context, self.options.token_delimiter);
+        let mut new_text = context.to_vec();
+        new_text.push(token.clone());
+        let raw_new_text = flatten_tokens(&new_text, self.options.token_delimiter);
+        let token_text = flatten_tokens(std::slice::from_ref(token), self.options.token_delimiter);
 
-        // normalize the scores
-        let sum: f32 = regularized_scores.values().cloned().sum();
-        let softmax_scores: HashMap<Token, f32> = regularized_scores
+        self.zstd_cdicts
             .iter()
-            .map(|(k, v)| (k.clone(), (*v / sum)))
+            .map(|cdict| {
+                let base_size = ClmModel::compress(cdict, &base_text)?;
+                let compressed_size = ClmModel::compress(cdict, &raw_new_text)?;
+                let mut size_delta = compressed_size as f64 - base_size as f64;
+                if self.options.inference_mode == InferenceMode::Predictive {
+                    size_delta -= ClmModel::compress(cdict, &token_text)? as f64;
+                }
+                Ok(size_delta)
+            })
+            .collect()
+    }
+
+    /// Index of the first token of the context used for predicting after `current_text`
+    fn context_start(&self, current_text: &[Token]) -> usize {
+        match self.options.context_window_unit {
+            ContextWindowUnit::Tokens => {
+                current_text.len() - min(current_text.len(), self.options.context_window)
+            }
+            ContextWindowUnit::Bytes => {
+                let mut start = current_text.len();
+                let mut bytes = 0;
+                while start > 0 && bytes + current_text[start - 1].len() <= self.options.context_window {
+                    bytes += current_text[start - 1].len();
+                    start -= 1;
+                }
+                start
+            }
+        }
+    }
+
+    /// Computes the weight of each dictionary in the ensemble average.
+    /// With `adaptive_ensemble` the weights follow how well each dictionary compresses
+    /// the context, i.e. a dictionary saving one more byte gets `inference_basis` times the weight.
+    fn ensemble_weights(&self, base_sizes: &[usize]) -> Vec<f64> {
+        if !self.options.adaptive_ensemble {
+            return vec![1.0 / base_sizes.len() as f64; base_sizes.len()];
+        }
+        let min_size = base_sizes.iter().copied().min().unwrap_or(0);
+        let unnormalized: Vec<f64> = base_sizes
+            .iter()
+            .map(|&size| self.options.inference_basis.powf(-((size - min_size) as f64)))
             .collect();
+        let sum: f64 = unnormalized.iter().sum();
+        unnormalized.iter().map(|weight| weight / sum).collect()
+    }
+}
 
-        softmax_scores
+impl ClmModel {
+    /// Reports the size and compression quality of each dictionary in the ensemble.
+    /// The ratio is measured on `held_out` tokens the dictionaries weren't trained on,
+    /// e.g. a sample of the test file, so a degenerate dictionary shows up with a ratio close to 1.
+    pub fn dictionary_stats(&self, held_out: &[Token]) -> Result<Vec<DictStat>, ClmError> {
+        if held_out.is_empty() {
+            return Err(ClmError::Config("no held-out tokens to measure the dictionaries on".to_string()));
+        }
+        let sample = flatten_tokens(held_out, self.options.token_delimiter);
+        self.zstd_cdicts
+            .iter()
+            .enumerate()
+            .map(|(index, cdict)| {
+                Ok(DictStat {
+                    index,
+                    byte_len: self._dictionaries[index].len(),
+                    avg_compression_ratio: sample.len() as f64 / ClmModel::compress(cdict, &sample)?.max(1) as f64,
+                })
+            })
+            .collect()
     }
 
-    
+    /// Drops all but the `n` dictionaries with the best compression ratio on `held_out`, keeping
+    /// their order. Inference time grows linearly with the ensemble size, so this trades a little
+    /// quality for speed.
+    pub fn keep_top(&mut self, n: usize, held_out: &[Token]) -> Result<(), ClmError> {
+        if n >= self.zstd_cdicts.len() {
+            return Ok(());
+        }
+        let mut stats = self.dictionary_stats(held_out)?;
+        stats.sort_by(|a, b| b.avg_compression_ratio.total_cmp(&a.avg_compression_ratio));
+        let kept: Vec<usize> = stats.iter().take(n).map(|stat| stat.index).collect();
+
+        let dictionaries = std::mem::take(&mut self._dictionaries);
+        let cdicts = std::mem::take(&mut self.zstd_cdicts);
+        for (index, (dict, cdict)) in dictionaries.into_iter().zip(cdicts).enumerate() {
+            if kept.contains(&index) {
+                self._dictionaries.push(dict);
+                self.zstd_cdicts.push(cdict);
+            } else {
+                unsafe {
+                    zstd_sys::ZSTD_freeCDict(cdict);
+                }
+            }
+        }
+        self.options.ensemble_size = self.zstd_cdicts.len();
+        Ok(())
+    }
 }
 
 impl ClmModel {
-    fn compress(cdict: &*mut zstd_sys::ZSTD_CDict_s, raw_new_text: Vec<u8>) -> usize {
-        let compressed_size = unsafe {
+    /// The size of `raw_new_text` compressed with `cdict`
+    fn compress(cdict: &*mut zstd_sys::ZSTD_CDict_s, raw_new_text: &[u8]) -> Result<usize, ClmError> {
+        unsafe {
             let cctx = zstd_sys::ZSTD_createCCtx();
             if cctx.is_null() {
-                panic!("Failed to create ZSTD compression context");
+                return Err(ClmError::Zstd("failed to create a compression context".to_string()));
             }
         
             let mut dst = vec![0u8; zstd_sys::ZSTD_compressBound(raw_new_text.len())];
@@ -173,12 +1015,12 @@ impl ClmModel {
         
             // Check for errors
             if zstd_sys::ZSTD_isError(compressed_size_val) != 0 {
-                panic!("Compression failed");
+                let name = std::ffi::CStr::from_ptr(zstd_sys::ZSTD_getErrorName(compressed_size_val));
+                Err(ClmError::Zstd(format!("compression failed: {}", name.to_string_lossy())))
             } else {
-                compressed_size_val
+                Ok(compressed_size_val)
             }
-        };
-        compressed_size
+        }
     }
 }
 
@@ -187,27 +1029,1007 @@ impl ClmModel {
         serde_json::to_string(&self._dictionaries).unwrap()
     }
 
-    pub fn load_from_string(dict_string: String, options: TrainingOptions) -> Self {
-        let dictionaries: Vec<Vec<u8>> =
-            serde_json::from_str(&dict_string).expect("Failed to parse dictionary string");
+    /// Loads the dictionaries saved by `to_save_string`, failing if they can't be parsed or compiled
+    pub fn load_from_string(dict_string: String, options: TrainingOptions) -> Result<Self, ClmError> {
+        let dictionaries: Vec<Vec<u8>> = serde_json::from_str(&dict_string)?;
+        let model = Self::from_dictionaries(dictionaries, options)?;
 
-        let zstd_cdicts = dictionaries
-            .iter()
-            .map(|dict| unsafe {
-                zstd_sys::ZSTD_createCDict(
-                    dict.as_ptr() as *const _,
-                    dict.len(),
-                    options.train_compression_level as i32,
-                )
-            })
-            .collect::<Vec<*mut zstd_sys::ZSTD_CDict>>();
+        log::debug!("Selected {} dictionaries", model.zstd_cdicts.len());
 
-        print!("Selected {} dictionaries...\r", zstd_cdicts.len());
+        Ok(model)
+    }
 
-        ClmModel {
+    /// Builds a model from dictionaries produced elsewhere, e.g. by zstd's own trainer.
+    /// Dictionaries without the zstd magic number are used as raw content.
+    pub fn from_dictionaries(dictionaries: Vec<Vec<u8>>, options: TrainingOptions) -> Result<Self, ClmError> {
+        let zstd_cdicts = compile_dictionaries(&dictionaries, options.inference_level())?;
+        Ok(ClmModel {
             _dictionaries: dictionaries,
             zstd_cdicts,
+            unigram_prior: HashMap::new(),
+            prior: HashMap::new(),
+            token_chars: HashMap::new(),
             options,
+        })
+    }
+
+    /// Compiles the stored dictionaries again at `compression_level` and uses it for inference
+    /// from now on, without retraining them. Keeps the old ones if zstd refuses the level, and
+    /// rejects the level they are already compiled at.
+    pub fn recompile(&mut self, compression_level: i32) -> Result<(), ClmError> {
+        if compression_level == self.options.inference_level() {
+            return Err(ClmError::Config(format!(
+                "the dictionaries are already compiled at level {}",
+                compression_level
+            )));
+        }
+        let zstd_cdicts = compile_dictionaries(&self._dictionaries, compression_level)?;
+        for cdict in std::mem::replace(&mut self.zstd_cdicts, zstd_cdicts) {
+            unsafe {
+                zstd_sys::ZSTD_freeCDict(cdict);
+            }
+        }
+        self.options.inference_compression_level = Some(compression_level);
+        Ok(())
+    }
+
+    /// Writes the raw bytes of one ensemble member's dictionary to `path`, e.g. a `.dict`
+    /// file for `zstd --dict` or other tools
+    pub fn export_dictionary(&self, index: usize, path: &str) -> Result<(), ClmError> {
+        let dictionary = self._dictionaries.get(index).ok_or_else(|| {
+            ClmError::Config(format!(
+                "dictionary {} doesn't exist, the ensemble has {}",
+                index,
+                self._dictionaries.len()
+            ))
+        })?;
+        std::fs::write(path, dictionary)?;
+        Ok(())
+    }
+
+    /// Token frequencies used by `RegularizationMode::UnigramPrior`
+    pub fn unigram_prior(&self) -> &HashMap<Token, f64> {
+        &self.unigram_prior
+    }
+
+    pub fn set_unigram_prior(&mut self, prior: HashMap<Token, f64>) {
+        self.unigram_prior = prior;
+    }
+
+    /// Takes the text length of every token from the tokenizer the model was trained with
+    pub fn set_token_lengths(&mut self, tokenizer: &Tokenizer) {
+        self.token_chars = tokenizer
+            .reverse_map()
+            .iter()
+            .map(|(token, text)| (token.clone(), text.chars().count()))
+            .collect();
+    }
+
+    /// Length in characters of the token's text, or of its code for tokens the tokenizer doesn't know
+    fn token_chars(&self, token: &Token) -> usize {
+        self.token_chars.get(token).copied().unwrap_or(token.len()).max(1)
+    }
+}
+
+/// Rescales the values to zero mean and unit variance, so dictionaries whose size deltas
+/// differ in scale contribute equally to the ensemble. Constant values become all zeros.
+fn standardize(values: &mut [f64]) {
+    let mean = values.iter().sum::<f64>() / values.len().max(1) as f64;
+    let variance = values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / values.len().max(1) as f64;
+    let std_dev = variance.sqrt();
+    for value in values.iter_mut() {
+        *value = if std_dev > 0.0 { (*value - mean) / std_dev } else { 0.0 };
+    }
+}
+
+/// The distinct context lengths in tokens `TrainingOptions::adaptive_context_lengths` use for a
+/// text of `text_len` tokens, in ascending order, with whether the context grows into the next
+/// prediction's context because the text is shorter than a configured length
+fn adaptive_context_lengths(configured: &[usize], text_len: usize) -> Vec<(usize, bool)> {
+    let mut lengths: Vec<(usize, bool)> = configured
+        .iter()
+        .map(|&length| (length.min(text_len), text_len < length))
+        .collect();
+    lengths.sort_unstable();
+    // Lengths cut to the same context keep it if any of them grows
+    lengths.dedup_by(|later, earlier| {
+        if later.0 == earlier.0 {
+            earlier.1 |= later.1;
+            true
+        } else {
+            false
+        }
+    });
+    lengths
+}
+
+/// The `SHINGLE_SKETCH_SIZE` smallest distinct hashes of the chunk's `SHINGLE_TOKENS`-grams,
+/// in ascending order, a bottom-k sketch of its shingle set
+fn shingle_sketch(chunk: &[Token]) -> Vec<u64> {
+    let mut sketch = std::collections::BTreeSet::new();
+    for shingle in chunk.windows(SHINGLE_TOKENS.min(chunk.len()).max(1)) {
+        let mut hasher = DefaultHasher::new();
+        shingle.hash(&mut hasher);
+        let hash = hasher.finish();
+        if sketch.len() < SHINGLE_SKETCH_SIZE || sketch.last().is_some_and(|&largest| hash < largest) {
+            sketch.insert(hash);
+            if sketch.len() > SHINGLE_SKETCH_SIZE {
+                sketch.pop_last();
+            }
+        }
+    }
+    sketch.into_iter().collect()
+}
+
+/// Estimates the Jaccard similarity of two shingle sets from their sketches: the share of
+/// the smallest hashes of the union that both sets contain
+fn sketch_similarity(a: &[u64], b: &[u64]) -> f64 {
+    let (mut i, mut j, mut union, mut shared) = (0, 0, 0, 0);
+    while union < SHINGLE_SKETCH_SIZE && (i < a.len() || j < b.len()) {
+        match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) if x == y => {
+                shared += 1;
+                i += 1;
+                j += 1;
+            }
+            (Some(x), Some(y)) if x < y => i += 1,
+            (Some(_), None) => i += 1,
+            _ => j += 1,
+        }
+        union += 1;
+    }
+    shared as f64 / union.max(1) as f64
+}
+
+/// Relative frequency of each distinct token
+fn unigram_frequencies(tokens: &[Token]) -> HashMap<Token, f64> {
+    let mut counts: HashMap<Token, f64> = HashMap::new();
+    for token in tokens {
+        *counts.entry(token.clone()).or_default() += 1.0;
+    }
+    for count in counts.values_mut() {
+        *count /= tokens.len() as f64;
+    }
+    counts
+}
+
+/// Size and quality of one dictionary in the ensemble
+#[derive(Debug, Clone, PartialEq)]
+pub struct DictStat {
+    pub index: usize,
+    pub byte_len: usize,
+    /// Ratio of raw to compressed size of the held-out tokens, higher is better
+    pub avg_compression_ratio: f64,
+}
+
+/// A dictionary that zstd refused to compile
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("zstd could not create a compression dictionary from dictionary {index}")]
+pub struct InvalidDictionary {
+    pub index: usize,
+}
+
+/// Creates a ZSTD_CDict for each dictionary, freeing all of them again if one fails
+fn compile_dictionaries(
+    dictionaries: &[Vec<u8>],
+    compression_level: i32,
+) -> Result<Vec<*mut zstd_sys::ZSTD_CDict>, InvalidDictionary> {
+    let mut zstd_cdicts = Vec::with_capacity(dictionaries.len());
+    for (index, dict) in dictionaries.iter().enumerate() {
+        let cdict = unsafe {
+            zstd_sys::ZSTD_createCDict(dict.as_ptr() as *const _, dict.len(), compression_level)
+        };
+        if cdict.is_null() {
+            for cdict in zstd_cdicts {
+                unsafe {
+                    zstd_sys::ZSTD_freeCDict(cdict);
+                }
+            }
+            return Err(InvalidDictionary { index });
+        }
+        zstd_cdicts.push(cdict);
+    }
+    Ok(zstd_cdicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn training_tokens() -> Vec<Token> {
+        let words = ["the", "cat", "sat", "on", "mat", "and", "dog", "ran", "far", "away"];
+        (0..4000)
+            .map(|i| words[(i * 7 + i / 3) % words.len()].as_bytes().to_vec())
+            .collect()
+    }
+
+    fn test_options() -> TrainingOptions {
+        TrainingOptions {
+            k: 64,
+            ensemble_size: 2,
+            training_chunk_size: 64,
+            nb_threads: 4,
+            train_compression_level: 3,
+            ..TrainingOptions::default()
+        }
+    }
+
+    /// Records every log message together with the thread that logged it
+    struct TestLogger {
+        records: std::sync::Mutex<Vec<(std::thread::ThreadId, log::Level, String)>>,
+    }
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.records
+                .lock()
+                .unwrap()
+                .push((std::thread::current().id(), record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger {
+        records: std::sync::Mutex::new(Vec::new()),
+    };
+    static INSTALL_LOGGER: std::sync::Once = std::sync::Once::new();
+
+    /// Installs `LOGGER` once for all tests, since a logger can only be set once per process
+    fn test_logger() -> &'static TestLogger {
+        INSTALL_LOGGER.call_once(|| {
+            log::set_logger(&LOGGER).expect("no other logger is installed in tests");
+            log::set_max_level(log::LevelFilter::Info);
+        });
+        &LOGGER
+    }
+
+    #[test]
+    fn test_training_logs_completion() {
+        let logger = test_logger();
+
+        ClmModel::train(training_tokens(), test_options()).unwrap();
+        // Other tests train in parallel, so only look at this thread's messages
+        let this_thread = std::thread::current().id();
+        let records = logger.records.lock().unwrap();
+        assert!(records.iter().any(|(thread, level, message)| {
+            *thread == this_thread && *level == log::Level::Info && message.to_lowercase().contains("training complete")
+        }));
+    }
+
+    #[test]
+    fn test_deterministic_training_is_reproducible() {
+        let options = TrainingOptions {
+            deterministic: true,
+            ..test_options()
+        };
+        let first = ClmModel::train(training_tokens(), options.clone()).unwrap();
+        let second = ClmModel::train(training_tokens(), options).unwrap();
+        assert_eq!(first._dictionaries, second._dictionaries);
+    }
+
+    #[test]
+    fn test_auto_compression_level_shrinks_with_corpus() {
+        let small = auto_compression_level(training_tokens().iter().map(|token| token.len()).sum());
+        let large = auto_compression_level(4 << 30);
+        assert!(large < small);
+        assert!(auto_compression_level(200 << 20) <= small && auto_compression_level(200 << 20) >= large);
+
+        let model = ClmModel::train(training_tokens(), TrainingOptions {
+            auto_compression_level: true,
+            ensemble_size: 1,
+            ..test_options()
+        }).unwrap();
+        assert_eq!(model.options.train_compression_level, small);
+    }
+
+    #[test]
+    fn test_thread_plan_respects_budget() {
+        for thread_budget in [1, 2, 7, 12, 64] {
+            for chunks in [1, 3, 15, 100] {
+                for nb_threads in [1, 4, 12] {
+                    let plan = ThreadPlan::new(thread_budget, chunks, nb_threads);
+                    assert!(plan.chunk_workers >= 1 && plan.zdict_threads >= 1);
+                    assert!(plan.chunk_workers * plan.zdict_threads as usize <= thread_budget);
+                    assert!(plan.chunk_workers <= chunks && plan.zdict_threads <= nb_threads);
+                }
+            }
+        }
+        assert_eq!(ThreadPlan::new(12, 3, 12), ThreadPlan { chunk_workers: 3, zdict_threads: 4 });
+        assert_eq!(ThreadPlan::new(12, 15, 12), ThreadPlan { chunk_workers: 12, zdict_threads: 1 });
+
+        let model = ClmModel::train(training_tokens(), TrainingOptions {
+            thread_budget: Some(2),
+            ..test_options()
+        }).unwrap();
+        assert_eq!(model.options.ensemble_size, 2);
+    }
+
+    #[test]
+    fn test_training_is_independent_of_worker_threads() {
+        let options = TrainingOptions {
+            deterministic: true,
+            ensemble_size: 4,
+            ..test_options()
+        };
+        let train_with_threads = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| ClmModel::train(training_tokens(), options.clone()).unwrap()._dictionaries.clone())
+        };
+        assert_eq!(train_with_threads(1), train_with_threads(4));
+    }
+
+    #[test]
+    fn test_adaptive_ensemble_prefers_specialized_dictionary() {
+        // The first half of the tokens only contains letters, the second half only digits,
+        // so each dictionary is specialized on one of them
+        let letters: Vec<Token> = training_tokens();
+        let digits: Vec<Token> = (0..4000).map(|i| format!("{}", (i * 37) % 1000).into_bytes()).collect();
+        let tokens = [letters.clone(), digits].concat();
+        let options = TrainingOptions {
+            adaptive_ensemble: true,
+            ..test_options()
+        };
+        let model = ClmModel::train(tokens, options).unwrap();
+
+        let context = letters[..20].iter().flatten().copied().collect::<Vec<u8>>();
+        let base_sizes: Vec<usize> = model
+            .zstd_cdicts
+            .iter()
+            .map(|cdict| ClmModel::compress(cdict, &context).unwrap())
+            .collect();
+        let weights = model.ensemble_weights(&base_sizes);
+        assert!(weights[0] > 0.9, "weights: {:?}", weights);
+        assert!((weights.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_length_normalization_favors_long_tokens() {
+        let text = crate::clm::fixtures::corpus(3000);
+        let (mut model, tokenizer) = crate::clm::train_from_text(&text, crate::clm::fixtures::small_options()).unwrap();
+        let context = tokenizer.encode_fast_opt(text[..100].to_string(), true);
+        let all_tokens = tokenizer.get_tokens();
+        // Every code has the same width, only the texts differ in length
+        assert!(all_tokens.iter().all(|token| token.len() == model.options.token_byte_size));
+        let reverse_map = tokenizer.reverse_map();
+        let chars = |token: &Token| reverse_map[token].chars().count();
+        let short = all_tokens.iter().min_by_key(|token| chars(token)).unwrap();
+        let long = all_tokens.iter().max_by_key(|token| chars(token)).unwrap();
+        assert!(chars(long) > chars(short));
+
+        let plain_likelihoods = model.compute_likelihoods(context.clone(), all_tokens).unwrap();
+        model.options.length_normalize = true;
+        let normalized_likelihoods = model.compute_likelihoods(context, all_tokens).unwrap();
+
+        assert!(
+            normalized_likelihoods[long] / normalized_likelihoods[short]
+                > plain_likelihoods[long] / plain_likelihoods[short]
+        );
+        let sum: f32 = normalized_likelihoods.values().sum();
+        assert!((sum - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_explain_prediction_matches_scores() {
+        let tokens = training_tokens();
+        let context = tokens[..10].to_vec();
+        let all_tokens: Vec<Token> = ["the", "cat", "dog", "far"].iter().map(|t| t.as_bytes().to_vec()).collect();
+        let mut model = ClmModel::train(tokens, test_options()).unwrap();
+        model.options.regularization_mode = RegularizationMode::None;
+
+        for mode in [InferenceMode::MarginalSize, InferenceMode::Predictive] {
+            model.options.inference_mode = mode;
+            let scores: Vec<f64> = all_tokens
+                .iter()
+                .map(|token| {
+                    let deltas = model.explain_prediction(&context, token).unwrap();
+                    assert_eq!(deltas.len(), model.zstd_cdicts.len());
+                    deltas.iter().sum::<f64>() / deltas.len() as f64
+                })
+                .collect();
+            // Predictive mode adds the training frequencies as the base rate
+            let base_rates = match mode {
+                InferenceMode::MarginalSize => HashMap::new(),
+                InferenceMode::Predictive => model.marginal_log_likelihoods(&all_tokens),
+            };
+            let base_rate = |token: &Token| base_rates.get(token).copied().unwrap_or(0.0);
+            // Without regularization the log-likelihoods are -score * ln(basis) up to a shared constant
+            let log_likelihoods = model.compute_log_likelihoods(context.clone(), &all_tokens).unwrap();
+            let ln_basis = model.options.inference_basis.ln();
+            for i in 1..all_tokens.len() {
+                let difference = log_likelihoods[&all_tokens[i]] - log_likelihoods[&all_tokens[0]];
+                let expected = -(scores[i] - scores[0]) * ln_basis + base_rate(&all_tokens[i]) - base_rate(&all_tokens[0]);
+                assert!((difference - expected).abs() < 1e-9, "{:?}", mode);
+            }
+        }
+
+        // Predictions that don't come from one context's deltas are rejected
+        assert!(matches!(model.explain_prediction(&[], &all_tokens[0]), Err(ClmError::Config(_))));
+        model.options.adaptive_context_lengths = vec![1, 4];
+        assert!(matches!(model.explain_prediction(&context, &all_tokens[0]), Err(ClmError::Config(_))));
+    }
+
+    #[test]
+    fn test_token_delimiter_changes_size_deltas() {
+        let tokens = training_tokens();
+        assert_eq!(flatten_tokens(&tokens[..2], Some(0)).len(), tokens[0].len() + tokens[1].len() + 2);
+
+        let context = tokens[..10].to_vec();
+        let all_tokens: Vec<Token> = ["the", "cat", "dog", "far"].iter().map(|t| t.as_bytes().to_vec()).collect();
+        let plain = ClmModel::train(tokens.clone(), test_options()).unwrap();
+        let delimited = ClmModel::train(tokens, TrainingOptions {
+            token_delimiter: Some(0),
+            ..test_options()
+        }).unwrap();
+
+        let plain_likelihoods = plain.compute_likelihoods(context.clone(), &all_tokens).unwrap();
+        let delimited_likelihoods = delimited.compute_likelihoods(context, &all_tokens).unwrap();
+        assert_ne!(plain_likelihoods, delimited_likelihoods);
+        let sum: f32 = delimited_likelihoods.values().sum();
+        assert!((sum - 1.0).abs() < 1e-5);
+        assert!(delimited_likelihoods.values().all(|p| *p > 0.0));
+    }
+
+    #[test]
+    fn test_predictive_mode_gives_distinct_distribution() {
+        let tokens = training_tokens();
+        let mut vocabulary = tokens.clone();
+        vocabulary.sort();
+        vocabulary.dedup();
+        let context = tokens[200..260].to_vec();
+        let mut model = ClmModel::train(tokens, test_options()).unwrap();
+        let marginal = model.compute_log_likelihoods(context.clone(), &vocabulary).unwrap();
+
+        model.options.inference_mode = InferenceMode::Predictive;
+        let predictive = model.compute_log_likelihoods(context, &vocabulary).unwrap();
+        assert_eq!(predictive.len(), vocabulary.len());
+        assert!(predictive.values().all(|log_likelihood| log_likelihood.is_finite()));
+        let sum: f64 = predictive.values().map(|log_likelihood| log_likelihood.exp()).sum();
+        assert!((sum - 1.0).abs() < 1e-9);
+        assert!(vocabulary.iter().any(|token| (predictive[token] - marginal[token]).abs() > 1e-6));
+    }
+
+    #[test]
+    fn test_empty_context_predicts_training_frequencies() {
+        let tokens = training_tokens();
+        let model = ClmModel::train(tokens.clone(), test_options()).unwrap();
+        let mut vocabulary = tokens.clone();
+        vocabulary.sort();
+        vocabulary.dedup();
+        let unseen = b"zq".to_vec();
+        vocabulary.push(unseen.clone());
+
+        let likelihoods = model.compute_likelihoods(Vec::new(), &vocabulary).unwrap();
+        assert!((likelihoods.values().sum::<f32>() - 1.0).abs() < 1e-4);
+        // Literal sizes would favor the short unseen token, frequencies don't
+        for (token, frequency) in model.unigram_prior() {
+            assert!((likelihoods[token] as f64 - frequency).abs() < 1e-3);
+        }
+        assert!(likelihoods[&unseen] > 0.0);
+        assert!(likelihoods[&unseen] < 1e-3);
+    }
+
+    #[test]
+    fn test_one_token_context_depends_on_token() {
+        let text = crate::clm::fixtures::corpus(3000);
+        let (model, tokenizer) = crate::clm::train_from_text(&text, crate::clm::fixtures::small_options()).unwrap();
+        let vocabulary = tokenizer.get_tokens();
+        let empty = model.compute_likelihoods(Vec::new(), vocabulary).unwrap();
+
+        // Two codes are long enough for the dictionaries to match what followed the token in training
+        let mut first_tokens = tokenizer.encode_fast_opt(text[..200].to_string(), true);
+        first_tokens.dedup();
+        let predictions: Vec<HashMap<Token, f32>> = first_tokens[..3]
+            .iter()
+            .map(|token| model.compute_likelihoods(vec![token.clone()], vocabulary).unwrap())
+            .collect();
+        assert!(predictions.iter().all(|prediction| *prediction != empty));
+        assert!(vocabulary.iter().any(|token| (predictions[0][token] - predictions[1][token]).abs() > 1e-4));
+        assert!(vocabulary.iter().any(|token| (predictions[1][token] - predictions[2][token]).abs() > 1e-4));
+    }
+
+    #[test]
+    fn test_adaptive_context_lengths_only_merge_equal_lengths() {
+        // 16 and 32 both cut to the whole text and grow, 4 and 8 don't
+        assert_eq!(adaptive_context_lengths(&[8, 32, 4, 16], 10), vec![(4, false), (8, false), (10, true)]);
+        assert_eq!(adaptive_context_lengths(&[4, 4, 16], 10), vec![(4, false), (10, true)]);
+        assert_eq!(adaptive_context_lengths(&[10, 16], 10), vec![(10, true)]);
+    }
+
+    #[test]
+    fn test_adaptive_context_lengths() {
+        let tokens = training_tokens();
+        let mut model = ClmModel::train(tokens.clone(), test_options()).unwrap();
+        let mut vocabulary = tokens.clone();
+        vocabulary.sort();
+        vocabulary.dedup();
+        let context = tokens[100..140].to_vec();
+
+        let fixed = model.compute_likelihoods(context.clone(), &vocabulary).unwrap();
+        model.options.adaptive_context_lengths = vec![1, 4, 16];
+        let adaptive = model.compute_likelihoods(context.clone(), &vocabulary).unwrap();
+
+        assert_eq!(adaptive.len(), vocabulary.len());
+        assert!(adaptive.values().all(|&likelihood| likelihood.is_finite() && likelihood >= 0.0));
+        assert!((adaptive.values().sum::<f32>() - 1.0).abs() < 1e-4);
+        assert!(vocabulary.iter().any(|token| (adaptive[token] - fixed[token]).abs() > 1e-4));
+        // Lengths beyond the text and an empty text still predict
+        let short = model.compute_likelihoods(context[..2].to_vec(), &vocabulary).unwrap();
+        assert!((short.values().sum::<f32>() - 1.0).abs() < 1e-4);
+        let empty = model.compute_likelihoods(Vec::new(), &vocabulary).unwrap();
+        assert!((empty.values().sum::<f32>() - 1.0).abs() < 1e-4);
+        assert_eq!(model.recommended_warmup(), 16);
+    }
+
+    #[test]
+    fn test_exported_dictionary_is_valid() {
+        let model = ClmModel::train(training_tokens(), test_options()).unwrap();
+        let path = std::env::temp_dir().join(format!("chatclm-export-{}.dict", std::process::id()));
+        let path = path.to_str().unwrap();
+        model.export_dictionary(1, path).unwrap();
+        let dictionary = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(dictionary, model._dictionaries[1]);
+        // Trained dictionaries start with zstd's dictionary magic number
+        assert_eq!(dictionary[..4], 0xEC30A437u32.to_le_bytes());
+        let cdict = unsafe { zstd_sys::ZSTD_createCDict(dictionary.as_ptr() as *const _, dictionary.len(), 3) };
+        assert!(!cdict.is_null());
+        unsafe {
+            zstd_sys::ZSTD_freeCDict(cdict);
+        }
+
+        assert!(matches!(model.export_dictionary(2, path), Err(ClmError::Config(_))));
+    }
+
+    #[test]
+    fn test_recompile_keeps_dictionaries() {
+        let tokens = training_tokens();
+        let all_tokens: Vec<Token> = tokens.iter().cloned().collect::<std::collections::HashSet<_>>().into_iter().collect();
+        let context = tokens[100..400].to_vec();
+        let mut model = ClmModel::train(tokens, test_options()).unwrap();
+        let saved = model.to_save_string();
+        let before = model.compute_likelihoods(context.clone(), &all_tokens).unwrap();
+
+        model.recompile(-5).unwrap();
+        assert_eq!(model.to_save_string(), saved);
+        assert_eq!(model.options.inference_level(), -5);
+        assert!(matches!(model.recompile(-5), Err(ClmError::Config(_))));
+        assert_ne!(model.compute_likelihoods(context.clone(), &all_tokens).unwrap(), before);
+
+        // A model loaded with the new options compiles at the new level too
+        let loaded = ClmModel::load_from_string(saved, model.options.clone()).unwrap();
+        assert_eq!(
+            loaded.compute_likelihoods(context.clone(), &all_tokens).unwrap(),
+            model.compute_likelihoods(context, &all_tokens).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_invalid_dictionary_is_rejected() {
+        // Starts with the zstd dictionary magic number but has no valid entropy tables
+        let corrupt = [vec![0x37, 0xA4, 0x30, 0xEC], vec![0xFF; 60]].concat();
+        let dicts = serde_json::to_string(&vec![vec![1u8; 300], corrupt]).unwrap();
+
+        let result = ClmModel::load_from_string(dicts, test_options());
+        assert!(matches!(
+            result,
+            Err(ClmError::InvalidDictionary(InvalidDictionary { index: 1 }))
+        ));
+
+        let result = ClmModel::load_from_string("not json".to_string(), test_options());
+        assert!(matches!(result, Err(ClmError::Serde(_))));
+    }
+
+    #[test]
+    fn test_compression_failure_is_an_error() {
+        // zstd rejects a missing dictionary instead of compressing without one
+        let result = ClmModel::compress(&std::ptr::null_mut(), b"some text");
+        assert!(matches!(result, Err(ClmError::Zstd(_))), "{:?}", result);
+
+        let mut cache = CompressionCache::default();
+        let result = cache.get_or_compress(0, b"some text".to_vec(), true, |text| ClmModel::compress(&std::ptr::null_mut(), text));
+        assert!(matches!(result, Err(ClmError::Zstd(_))));
+        assert_eq!(cache.stored(), 0);
+    }
+
+    #[test]
+    fn test_keep_top_prunes_ensemble() {
+        let tokens = training_tokens();
+        let (train, held_out) = tokens.split_at(3600);
+        let all_tokens: Vec<Token> = tokens.iter().cloned().collect::<std::collections::HashSet<_>>().into_iter().collect();
+        let mut model = ClmModel::train(train.to_vec(), TrainingOptions {
+            ensemble_size: 4,
+            ..test_options()
+        }).unwrap();
+        let stats = model.dictionary_stats(held_out).unwrap();
+        assert_eq!(stats.len(), 4);
+        assert!(stats.iter().all(|stat| stat.byte_len > 0 && stat.avg_compression_ratio > 0.0));
+        assert!(matches!(model.dictionary_stats(&[]), Err(ClmError::Config(_))));
+
+        model.keep_top(2, held_out).unwrap();
+        assert_eq!(model.dictionary_stats(held_out).unwrap().len(), 2);
+        assert_eq!(model.options.ensemble_size, 2);
+
+        let likelihoods = model.compute_likelihoods(tokens[..10].to_vec(), &all_tokens).unwrap();
+        let sum: f32 = likelihoods.values().sum();
+        assert!((sum - 1.0).abs() < 1e-5);
+
+        // The pruned ensemble is what gets saved
+        let reloaded = ClmModel::load_from_string(model.to_save_string(), model.options.clone()).unwrap();
+        assert_eq!(reloaded.dictionary_stats(held_out).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_keep_top_ranks_on_held_out_tokens() {
+        let tokens = training_tokens();
+        let (train, held_out) = tokens.split_at(3600);
+        let unrelated: Vec<Token> = (0..4000u32).map(|i| i.wrapping_mul(2654435761).to_le_bytes().to_vec()).collect();
+        let options = TrainingOptions {
+            ensemble_size: 1,
+            ..test_options()
+        };
+        let dictionaries = vec![
+            train_model(&unrelated, &options).unwrap(),
+            train_model(train, &options).unwrap(),
+        ];
+        let good_dictionary = dictionaries[1].clone();
+        let mut model = ClmModel::from_dictionaries(dictionaries, test_options()).unwrap();
+
+        let stats = model.dictionary_stats(held_out).unwrap();
+        assert!(stats[1].avg_compression_ratio > stats[0].avg_compression_ratio);
+        model.keep_top(1, held_out).unwrap();
+        assert_eq!(model._dictionaries, vec![good_dictionary]);
+    }
+
+    #[test]
+    fn test_memory_footprint_grows_with_ensemble_size() {
+        let small = ClmModel::train(training_tokens(), test_options()).unwrap();
+        let large = ClmModel::train(training_tokens(), TrainingOptions {
+            ensemble_size: 4,
+            ..test_options()
+        }).unwrap();
+        assert!(small.memory_footprint() > small._dictionaries.iter().map(Vec::len).sum());
+        assert!(large.memory_footprint() > small.memory_footprint());
+    }
+
+    #[test]
+    fn test_from_dictionaries() {
+        let dictionaries = vec![
+            b"the cat sat on the mat and the dog ran far away".repeat(8),
+            b"the dog sat on the mat and the cat ran away".repeat(8),
+        ];
+        let model = ClmModel::from_dictionaries(dictionaries, TrainingOptions {
+            ensemble_size: 2,
+            ..test_options()
+        })
+        .unwrap();
+
+        let all_tokens: Vec<Token> = ["cat sat on the mat", "qzx vjk wfp yhb gmu"].iter().map(|text| text.as_bytes().to_vec()).collect();
+        let context: Vec<Token> = b"the dog ran far away the ".iter().map(|byte| vec![*byte]).collect();
+        let likelihoods = model.compute_likelihoods(context, &all_tokens).unwrap();
+        let sum: f32 = likelihoods.values().sum();
+        assert!((sum - 1.0).abs() < 1e-5);
+        assert!(likelihoods[&all_tokens[0]] > likelihoods[&all_tokens[1]]);
+    }
+
+    #[test]
+    fn test_regularization_modes() {
+        let tokens = training_tokens();
+        let all_tokens: Vec<Token> = tokens.iter().cloned().collect::<std::collections::HashSet<_>>().into_iter().collect();
+        let context = tokens[..12].to_vec();
+        let likelihoods = |regularization, regularization_mode| {
+            let mut model = ClmModel::train(tokens.clone(), TrainingOptions {
+                deterministic: true,
+                ..test_options()
+            }).unwrap();
+            model.options.regularization = regularization;
+            model.options.regularization_mode = regularization_mode;
+            model.compute_likelihoods(context.clone(), &all_tokens).unwrap()
+        };
+
+        let raw = likelihoods(0.0, RegularizationMode::UniformFloor);
+        let unregularized = likelihoods(0.5, RegularizationMode::None);
+        for token in &all_tokens {
+            assert!((raw[token] - unregularized[token]).abs() < 1e-6);
+        }
+
+        let floored = likelihoods(0.5, RegularizationMode::UniformFloor);
+        let prior = likelihoods(0.5, RegularizationMode::UnigramPrior);
+        assert_ne!(floored, prior);
+        let sum: f32 = prior.values().sum();
+        assert!((sum - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_clamp_rounding_errors() {
+        let mut likelihoods: HashMap<Token, f32> =
+            [(vec![1], 0.6), (vec![2], 0.4000001), (vec![3], -1e-7)].into_iter().collect();
+        assert!(crate::clm::evaluate::check_distribution(&likelihoods).is_err());
+        clamp_rounding_errors(&mut likelihoods, 1e-6);
+        crate::clm::evaluate::check_distribution(&likelihoods).unwrap();
+        assert_eq!(likelihoods[&vec![3]], 0.0);
+        assert!((likelihoods.values().sum::<f32>() - 1.0).abs() < 1e-6);
+
+        // Values beyond epsilon are real errors and stay visible
+        let mut likelihoods: HashMap<Token, f32> = [(vec![1], 1.1), (vec![2], -0.1)].into_iter().collect();
+        clamp_rounding_errors(&mut likelihoods, 1e-6);
+        assert_eq!(likelihoods[&vec![2]], -0.1);
+    }
+
+    #[test]
+    fn test_tuned_dictionary_size_is_a_candidate() {
+        let tokens = training_tokens();
+        let (train, validation) = tokens.split_at(3800);
+        let best = ClmModel::tune_dictionary_size(train, validation, &test_options(), &DICTIONARY_SIZE_CANDIDATES).unwrap();
+        assert!(DICTIONARY_SIZE_CANDIDATES.contains(&best));
+
+        let model = ClmModel::train(tokens.clone(), TrainingOptions {
+            tune_dictionary_size: true,
+            ..test_options()
+        })
+        .unwrap();
+        assert!(DICTIONARY_SIZE_CANDIDATES.contains(&model.options.dictionary_size_percentage));
+        let all_tokens: Vec<Token> = ["the", "cat", "dog", "far"].iter().map(|t| t.as_bytes().to_vec()).collect();
+        let likelihoods = model.compute_likelihoods(tokens[..10].to_vec(), &all_tokens).unwrap();
+        crate::clm::evaluate::check_distribution(&likelihoods).unwrap();
+    }
+
+    #[test]
+    fn test_log_likelihoods_do_not_underflow() {
+        let tokens = training_tokens();
+        let all_tokens: Vec<Token> = tokens.iter().cloned().collect::<std::collections::HashSet<_>>().into_iter().collect();
+        let context = tokens[..12].to_vec();
+        let mut model = ClmModel::train(tokens.clone(), test_options()).unwrap();
+        // Every byte of size delta costs a factor of 1e30, far below f32's smallest value
+        model.options.inference_basis = 1e30;
+        model.options.regularization_mode = RegularizationMode::None;
+
+        let likelihoods = model.compute_likelihoods(context.clone(), &all_tokens).unwrap();
+        let log_likelihoods = model.compute_log_likelihoods(context, &all_tokens).unwrap();
+        assert!(likelihoods.values().any(|&likelihood| likelihood == 0.0));
+        assert!(log_likelihoods.values().all(|log_likelihood| log_likelihood.is_finite()));
+        assert!(log_likelihoods.values().any(|&log_likelihood| log_likelihood < -100.0));
+        let sum: f64 = log_likelihoods.values().map(|l| l.exp()).sum();
+        assert!((sum - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_time_budget_limits_ensemble() {
+        // Two chunks are trained at a time, and the first two take longer than the budget
+        let options = TrainingOptions {
+            ensemble_size: 8,
+            thread_budget: Some(2),
+            train_time_budget_secs: Some(1e-6),
+            ..test_options()
+        };
+        let model = ClmModel::train(training_tokens(), options).unwrap();
+        assert_eq!(model.options.ensemble_size, 2);
+        assert_eq!(model.zstd_cdicts.len(), 2);
+
+        let options = TrainingOptions {
+            ensemble_size: 4,
+            train_time_budget_secs: Some(600.0),
+            ..test_options()
+        };
+        assert_eq!(ClmModel::train(training_tokens(), options).unwrap().zstd_cdicts.len(), 4);
+
+        for budget in [-1.0, f64::NAN, f64::INFINITY] {
+            let options = TrainingOptions {
+                train_time_budget_secs: Some(budget),
+                ..test_options()
+            };
+            assert!(matches!(ClmModel::train(training_tokens(), options), Err(ClmError::Config(_))));
+        }
+    }
+
+    /// `length` tokens drawn pseudo-randomly from a few hundred words
+    fn random_region(seed: u32, length: usize) -> Vec<Token> {
+        (0..length as u32)
+            .map(|i| format!("w{}", (i ^ seed).wrapping_mul(2654435761) >> 24).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_dedup_chunks_skips_repeated_regions() {
+        let region = random_region(1, 1000);
+        let options = TrainingOptions {
+            ensemble_size: 4,
+            dedup_chunks: true,
+            ..test_options()
+        };
+        let ensemble_size = |tokens: Vec<Token>| ClmModel::train(tokens, options.clone()).unwrap().options.ensemble_size;
+
+        let tokens = [region.as_slice(); 4].concat();
+        let model = ClmModel::train(tokens.clone(), TrainingOptions {
+            dedup_chunks: false,
+            ..options.clone()
+        }).unwrap();
+        assert_eq!(model.options.ensemble_size, 4);
+        assert_eq!(ensemble_size(tokens), 1);
+
+        // Repeats that don't line up with the chunks, so every chunk starts elsewhere in the region
+        let shifted: Vec<Token> = region[..990].iter().cycle().take(4000).cloned().collect();
+        assert_eq!(ensemble_size(shifted), 1);
+
+        // Repeats with every 100th token replaced
+        let perturbed: Vec<Token> = (0..4000)
+            .map(|i| if i % 100 == 99 { format!("edit{}", i).into_bytes() } else { region[i % 1000].clone() })
+            .collect();
+        assert_eq!(ensemble_size(perturbed), 1);
+
+        // Unrelated chunks over the same words are all kept
+        let distinct = (0..4).flat_map(|seed| random_region(seed * 7919 + 3, 1000)).collect();
+        assert_eq!(ensemble_size(distinct), 4);
+    }
+
+    #[test]
+    fn test_prior_shifts_log_likelihoods() {
+        let tokens = training_tokens();
+        let all_tokens: Vec<Token> = tokens.iter().cloned().collect::<std::collections::HashSet<_>>().into_iter().collect();
+        let context = tokens[100..140].to_vec();
+        let mut model = ClmModel::train(tokens.clone(), TrainingOptions {
+            prior_weight: 0.5,
+            ..test_options()
+        }).unwrap();
+        let before = model.compute_log_likelihoods(context.clone(), &all_tokens).unwrap();
+
+        let favored = b"dog".to_vec();
+        model.with_prior(HashMap::from([(favored.clone(), 1.0)]));
+        let after = model.compute_log_likelihoods(context, &all_tokens).unwrap();
+        // Half of the mass moves to the favored token, the rest keeps its proportions
+        let expected = (0.5 * before[&favored].exp() + 0.5).ln();
+        assert!((after[&favored] - expected).abs() < 1e-6);
+        for token in all_tokens.iter().filter(|token| **token != favored) {
+            assert!((after[token] - (before[token] + 0.5f64.ln())).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_masked_likelihoods() {
+        let tokens = training_tokens();
+        let model = ClmModel::train(tokens.clone(), test_options()).unwrap();
+        let allowed: Vec<Token> = ["cat", "dog", "mat"].iter().map(|word| word.as_bytes().to_vec()).collect();
+
+        let likelihoods = model.compute_likelihoods_masked(tokens[..10].to_vec(), &allowed).unwrap();
+        let mut keys: Vec<Token> = likelihoods.keys().cloned().collect();
+        keys.sort();
+        let mut expected = allowed.clone();
+        expected.sort();
+        assert_eq!(keys, expected);
+        let sum: f32 = likelihoods.values().sum();
+        assert!((sum - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_per_dict_normalize_balances_scales() {
+        let tokens = training_tokens();
+        let all_tokens: Vec<Token> = tokens.iter().cloned().collect::<std::collections::HashSet<_>>().into_iter().collect();
+        let context = tokens[100..140].to_vec();
+        let model = ClmModel::train(tokens.clone(), TrainingOptions {
+            per_dict_normalize: true,
+            ..test_options()
+        }).unwrap();
+        let likelihoods = model.compute_likelihoods(context.clone(), &all_tokens).unwrap();
+
+        // Each dictionary's deltas over the vocabulary are standardized before averaging
+        let deltas: Vec<Vec<f64>> = all_tokens
+            .iter()
+            .map(|token| model.explain_prediction(&context, token).unwrap())
+            .collect();
+        let mut per_dict: Vec<Vec<f64>> = (0..model.options.ensemble_size)
+            .map(|index| deltas.iter().map(|token_deltas| token_deltas[index]).collect())
+            .collect();
+        assert!(per_dict.iter().all(|deltas| deltas.iter().any(|&delta| delta != deltas[0])));
+        per_dict.iter_mut().for_each(|deltas| standardize(deltas));
+        let scores: Vec<f64> = (0..all_tokens.len())
+            .map(|token| per_dict.iter().map(|deltas| deltas[token]).sum::<f64>() / per_dict.len() as f64)
+            .collect();
+        let inverted: Vec<f64> = scores.iter().map(|score| model.options.inference_basis.powf(-score)).collect();
+        let sum: f64 = inverted.iter().sum();
+        for (token, expected) in all_tokens.iter().zip(&inverted) {
+            assert!((likelihoods[token] as f64 - expected / sum).abs() < 1e-5);
+        }
+
+        // Standardized scores are bounded, so no dictionary can make a token near certain
+        let raw_model = ClmModel::load_from_string(model.to_save_string(), test_options()).unwrap();
+        let raw = raw_model.compute_likelihoods(context, &all_tokens).unwrap();
+        assert!(raw.values().cloned().fold(0.0, f32::max) > likelihoods.values().cloned().fold(0.0, f32::max));
+    }
+
+    #[test]
+    fn test_name_contains_key_options() {
+        let model = ClmModel::train(training_tokens(), TrainingOptions {
+            token_byte_size: 3,
+            ..test_options()
+        }).unwrap();
+        assert_eq!(model.name(), "clm[ens=2,tbs=3]");
+    }
+
+    #[test]
+    fn test_empty_vocabulary_gives_empty_distribution() {
+        let model = ClmModel::train(training_tokens(), test_options()).unwrap();
+        assert!(model.compute_likelihoods(training_tokens()[..5].to_vec(), &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_byte_context_window() {
+        let text: Vec<Token> = ["a", "bbbb", "cc", "dddddd", "e"].iter().map(|token| token.as_bytes().to_vec()).collect();
+        let model = |context_window_unit| {
+            ClmModel::from_dictionaries(vec![vec![1u8; 300]], TrainingOptions {
+                ensemble_size: 1,
+                context_window: 4,
+                context_window_unit,
+                ..test_options()
+            })
+            .unwrap()
+        };
+
+        // The last 4 tokens
+        assert_eq!(model(ContextWindowUnit::Tokens).context_start(&text), 1);
+        // "e" fits into 4 bytes, "dddddd" doesn't anymore
+        assert_eq!(model(ContextWindowUnit::Bytes).context_start(&text), 4);
+        assert_eq!(model(ContextWindowUnit::Bytes).context_start(&text[..3]), 2);
+    }
+
+    #[test]
+    fn test_batch_likelihoods_match_single() {
+        let tokens = training_tokens();
+        let model = ClmModel::train(tokens.clone(), test_options()).unwrap();
+        let all_tokens: Vec<Token> = ["the", "cat", "dog", "far"].iter().map(|t| t.as_bytes().to_vec()).collect();
+
+        let contexts: Vec<Vec<Token>> = (1..6).map(|i| tokens[..i].to_vec()).collect();
+        let batch = model.compute_likelihoods_batch(&contexts, &all_tokens).unwrap();
+        for (context, likelihoods) in contexts.iter().zip(batch) {
+            let single = model.compute_likelihoods(context.clone(), &all_tokens).unwrap();
+            for token in &all_tokens {
+                assert!((likelihoods[token] - single[token]).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cache_beyond_context_window() {
+        let tokens = training_tokens();
+        let mut all_tokens = tokens.clone();
+        all_tokens.sort();
+        all_tokens.dedup();
+        let mut model = ClmModel::train(tokens.clone(), TrainingOptions {
+            context_window: 8,
+            ..test_options()
+        }).unwrap();
+        let ensemble = model.options.ensemble_size;
+
+        let mut cache = CompressionCache::default();
+        for len in 4..20 {
+            let hits = cache.hits();
+            let cached = model.compute_log_likelihoods_cached(&tokens[..len], &all_tokens, &mut cache).unwrap();
+            let uncached = model.compute_log_likelihoods(tokens[..len].to_vec(), &all_tokens).unwrap();
+            for token in &all_tokens {
+                assert!((cached[token] - uncached[token]).abs() < 1e-12);
+            }
+            if len > 4 && len <= 8 {
+                // The context is the previous context plus a token, one of its candidates
+                assert_eq!(cache.hits() - hits, ensemble);
+            }
+            if len >= 8 {
+                // The window is full and slides, only the base sizes are kept
+                assert_eq!(cache.stored(), ensemble);
+            }
+        }
+
+        // The sizes of the tokens alone don't depend on the window, they're still reused
+        model.options.inference_mode = InferenceMode::Predictive;
+        let mut cache = CompressionCache::default();
+        model.compute_log_likelihoods_cached(&tokens[..30], &all_tokens, &mut cache).unwrap();
+        for len in 31..40 {
+            let hits = cache.hits();
+            model.compute_log_likelihoods_cached(&tokens[..len], &all_tokens, &mut cache).unwrap();
+            assert_eq!(cache.hits() - hits, all_tokens.len() * ensemble);
         }
     }
 }
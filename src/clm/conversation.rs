@@ -0,0 +1,130 @@
+use crate::clm::clm_model::{CompressionCache, Model};
+use crate::clm::error::ClmError;
+use crate::clm::inference::SamplingConfig;
+use crate::clm::tokenizer::{Token, Tokenizer};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How `ConversationState::generate_assistant` produces a reply
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GenerationConfig {
+    pub sampling: SamplingConfig,
+    /// Tokens generated per reply
+    pub max_tokens: usize,
+}
+
+/// The token history of a chat session, so it can be continued turn by turn without
+/// re-encoding it, and saved and resumed later. With a seed, each generated token is drawn
+/// with an RNG seeded by the seed and the token's position in the session, so a resumed
+/// session generates exactly what an uninterrupted one would.
+#[derive(Default, Serialize, Deserialize)]
+pub struct ConversationState {
+    tokens: Vec<Token>,
+    /// Tokens generated over all replies
+    generated: u64,
+    /// Compressed sizes of the last prediction, rebuilt after resuming
+    #[serde(skip)]
+    cache: CompressionCache,
+}
+
+impl ConversationState {
+    /// An empty conversation
+    pub fn new() -> Self {
+        ConversationState::default()
+    }
+
+    /// The tokens of all turns so far
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    /// Adds the user's message to the history
+    pub fn append_user(&mut self, tokenizer: &Tokenizer, text: &str) {
+        self.tokens.extend(tokenizer.encode_fast_opt(text.to_string(), true));
+    }
+
+    /// Generates the assistant's reply after the history, appends it and returns its text
+    pub fn generate_assistant<M: Model>(
+        &mut self,
+        model: &M,
+        tokenizer: &Tokenizer,
+        config: &GenerationConfig,
+    ) -> Result<String, ClmError> {
+        let all_tokens = tokenizer.get_tokens();
+        let start = self.tokens.len();
+        for _ in 0..config.max_tokens {
+            let likelihoods: HashMap<Token, f32> = model
+                .compute_log_likelihoods_incremental(&self.tokens, all_tokens, &mut self.cache)?
+                .into_iter()
+                .map(|(token, log_likelihood)| (token, log_likelihood.exp() as f32))
+                .collect();
+            if likelihoods.is_empty() {
+                break;
+            }
+            let mut rng = match config.sampling.seed {
+                Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(self.generated)),
+                None => StdRng::from_os_rng(),
+            };
+            self.tokens.push(config.sampling.sample(&likelihoods, &mut rng));
+            self.generated += 1;
+        }
+        Ok(tokenizer.decode(&self.tokens[start..]))
+    }
+
+    /// Writes the state as JSON
+    pub fn save(&self, path: &str) -> Result<(), ClmError> {
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Reads a state written by `save`
+    pub fn load(path: &str) -> Result<Self, ClmError> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clm::inference::Sampler;
+    use crate::clm::ngram_model::BigramModel;
+    use crate::clm::training_options::TrainingOptions;
+
+    #[test]
+    fn test_resumed_conversation_matches_uninterrupted() {
+        let text = "the cat sat on the mat and the dog sat on the cat. ".repeat(20);
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(&text, 30);
+        let model = BigramModel::train(tokenizer.encode_fast_opt(text, true), TrainingOptions::default()).unwrap();
+        let config = GenerationConfig {
+            sampling: SamplingConfig {
+                sampler: Sampler::TopK(4),
+                seed: Some(11),
+            },
+            max_tokens: 8,
+        };
+
+        let mut uninterrupted = ConversationState::new();
+        uninterrupted.append_user(&tokenizer, "the cat");
+        let first_reply = uninterrupted.generate_assistant(&model, &tokenizer, &config).unwrap();
+        uninterrupted.append_user(&tokenizer, "and the dog");
+        let second_reply = uninterrupted.generate_assistant(&model, &tokenizer, &config).unwrap();
+
+        let mut interrupted = ConversationState::new();
+        interrupted.append_user(&tokenizer, "the cat");
+        assert_eq!(interrupted.generate_assistant(&model, &tokenizer, &config).unwrap(), first_reply);
+        let path = std::env::temp_dir().join(format!("chatclm-conversation-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        interrupted.save(path).unwrap();
+        drop(interrupted);
+
+        let mut resumed = ConversationState::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        resumed.append_user(&tokenizer, "and the dog");
+        assert_eq!(resumed.generate_assistant(&model, &tokenizer, &config).unwrap(), second_reply);
+        assert_eq!(resumed.tokens(), uninterrupted.tokens());
+        assert!(!second_reply.is_empty());
+    }
+}
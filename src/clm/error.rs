@@ -0,0 +1,32 @@
+use crate::clm::ValidationError;
+use crate::clm::clm_model::InvalidDictionary;
+use thiserror::Error;
+
+/// Errors produced by the clm module
+#[derive(Debug, Error)]
+pub enum ClmError {
+    /// Reading or writing a file failed
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    /// (De)serializing a saved run or its parts failed
+    #[error("serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+    /// zstd reported an error while training or compressing
+    #[error("zstd error: {0}")]
+    Zstd(String),
+    /// zstd couldn't compile a stored dictionary
+    #[error(transparent)]
+    InvalidDictionary(#[from] InvalidDictionary),
+    /// The training options or inputs are unusable
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    /// A saved run is internally inconsistent
+    #[error("invalid saved run: {0}")]
+    Validation(#[from] ValidationError),
+    /// A model name matches several saved models and none of them exactly
+    #[error("{name} matches several models, pick one of: {}", candidates.join(", "))]
+    AmbiguousModel { name: String, candidates: Vec<String> },
+    /// A model produced output that can't be evaluated
+    #[error("evaluation error: {0}")]
+    Evaluation(String),
+}
@@ -1,36 +1,224 @@
 use std::time::Duration;
 
-use crate::clm::clm_model::Model;
+use crate::clm::clm_model::{CompressionCache, Model, MIN_EVAL_WARMUP};
+use crate::clm::error::ClmError;
+use crate::clm::progress::ProgressBarBuilder;
 use crate::clm::tokenizer::Tokenizer;
-use indicatif::{ProgressBar, ProgressStyle};
+use crate::clm::training_options::TrainingOptions;
 use num::Signed;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 use serde::{Deserialize, Serialize};
 
 use crate::clm::tokenizer::Token;
 
+/// Number of positions whose likelihoods are computed together in `evaluate`
+const EVAL_BATCH_SIZE: usize = 32;
+
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ModelStats {
     pub average_likelihood: f64,
     pub cross_entropy: f64,
+    pub cross_entropy_bits: f64,
     pub perplexity: f64,
     pub perplexity_stderr: f64,
     pub time_per_token: f64,
     pub ppt: f64,
     pub ppt_stderr: f64,
+    pub bits_per_byte: f64,
+    /// Number of scored positions
+    #[serde(default)]
+    pub positions: usize,
+    /// Number of positions where the model predicted a malformed distribution
+    #[serde(default)]
+    pub invalid_distribution_count: usize,
+    /// How often the ground truth was the i-th most likely token, if `EvalOptions::record_ranks` is set
+    #[serde(default)]
+    pub rank_histogram: Vec<usize>,
+}
+
+/// Columns of `ModelStats::to_csv_row` after the model name and options
+const CSV_STATS_COLUMNS: [&str; 11] = [
+    "average_likelihood",
+    "cross_entropy",
+    "cross_entropy_bits",
+    "perplexity",
+    "perplexity_stderr",
+    "time_per_token",
+    "ppt",
+    "ppt_stderr",
+    "bits_per_byte",
+    "positions",
+    "invalid_distribution_count",
+];
+
+/// Columns describing the evaluated model in `ModelStats::to_csv_row`
+const CSV_MODEL_COLUMNS: [&str; 6] = [
+    "model",
+    "model_id",
+    "ensemble_size",
+    "token_count",
+    "token_byte_size",
+    "context_window",
+];
+
+impl ModelStats {
+    /// Header matching `to_csv_row`. The rank histogram has no fixed width and is left out.
+    pub fn csv_header() -> String {
+        CSV_MODEL_COLUMNS.iter().chain(CSV_STATS_COLUMNS.iter()).copied().collect::<Vec<_>>().join(",")
+    }
+
+    /// One CSV row with the model's name, its key options and the stats,
+    /// so rows from many runs can be collected in one file
+    pub fn to_csv_row(&self, model_name: &str, options: &TrainingOptions) -> String {
+        let model_columns = [
+            csv_field(model_name),
+            csv_field(options.model_id.as_deref().unwrap_or("")),
+            options.ensemble_size.to_string(),
+            options.token_count.to_string(),
+            options.token_byte_size.to_string(),
+            options.context_window.to_string(),
+        ];
+        let stats_columns = [
+            self.average_likelihood.to_string(),
+            self.cross_entropy.to_string(),
+            self.cross_entropy_bits.to_string(),
+            self.perplexity.to_string(),
+            self.perplexity_stderr.to_string(),
+            self.time_per_token.to_string(),
+            self.ppt.to_string(),
+            self.ppt_stderr.to_string(),
+            self.bits_per_byte.to_string(),
+            self.positions.to_string(),
+            self.invalid_distribution_count.to_string(),
+        ];
+        model_columns.into_iter().chain(stats_columns).collect::<Vec<_>>().join(",")
+    }
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// How the CLI prints evaluation results
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// One JSON object per model, with comparisons against the baselines
+    #[default]
+    Json,
+    /// A header and one row per model, see `ModelStats::to_csv_row`
+    Csv,
+}
+
+impl std::str::FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(StatsFormat::Json),
+            "csv" => Ok(StatsFormat::Csv),
+            _ => Err(format!("unknown output format: {}", s)),
+        }
+    }
+}
+
+/// The change of a single metric relative to a baseline
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MetricDiff {
+    pub absolute: f64,
+    /// Absolute change divided by the baseline value, 0 if the baseline is 0
+    pub relative: f64,
+}
+
+impl MetricDiff {
+    fn new(value: f64, baseline: f64) -> Self {
+        let absolute = value - baseline;
+        let relative = if baseline != 0.0 { absolute / baseline } else { 0.0 };
+        MetricDiff { absolute, relative }
+    }
+}
+
+/// Differences between two evaluations, positive values mean the metric grew
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsDiff {
+    pub perplexity: MetricDiff,
+    pub cross_entropy: MetricDiff,
+    pub ppt: MetricDiff,
+    pub bits_per_byte: MetricDiff,
+    pub time_per_token: MetricDiff,
+    /// Whether the perplexity difference exceeds two combined standard errors
+    pub significant: bool,
+}
+
+impl ModelStats {
+    /// Compares these stats against a baseline evaluation
+    pub fn diff(&self, baseline: &ModelStats) -> StatsDiff {
+        let combined_stderr = (self.perplexity_stderr.powi(2) + baseline.perplexity_stderr.powi(2)).sqrt();
+        let perplexity = MetricDiff::new(self.perplexity, baseline.perplexity);
+        StatsDiff {
+            perplexity,
+            cross_entropy: MetricDiff::new(self.cross_entropy, baseline.cross_entropy),
+            ppt: MetricDiff::new(self.ppt, baseline.ppt),
+            bits_per_byte: MetricDiff::new(self.bits_per_byte, baseline.bits_per_byte),
+            time_per_token: MetricDiff::new(self.time_per_token, baseline.time_per_token),
+            significant: perplexity.absolute.abs() > 2.0 * combined_stderr,
+        }
+    }
+}
+
+impl std::fmt::Display for StatsDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let direction = if self.perplexity.absolute <= 0.0 { "improved" } else { "worsened" };
+        write!(
+            f,
+            "perplexity {} {:.1}% ({})",
+            direction,
+            self.perplexity.relative.abs() * 100.0,
+            if self.significant { "significant" } else { "not significant" }
+        )
+    }
+}
+
+pub fn check_distribution<T>(likelihoods: &std::collections::HashMap<T, f32>) -> Result<(), ClmError> {
+    match distribution_problem(likelihoods) {
+        Some(DistributionProblem::InvalidLikelihood(likelihood)) => Err(ClmError::Evaluation(format!(
+            "likelihood {} is infinite, NaN, or negative",
+            likelihood
+        ))),
+        Some(DistributionProblem::NotNormalized(_)) => {
+            log::warn!("Likelihoods do not sum to 1.0");
+            Ok(())
+        }
+        None => Ok(()),
+    }
 }
 
-pub fn check_distribution<T>(likelihoods: &std::collections::HashMap<T, f32>) {
+/// Why a predicted distribution is malformed
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DistributionProblem {
+    InvalidLikelihood(f32),
+    NotNormalized(f64),
+}
+
+fn distribution_problem<T>(likelihoods: &std::collections::HashMap<T, f32>) -> Option<DistributionProblem> {
     let mut total = 0.0;
     for (_, likelihood) in likelihoods.iter() {
         if likelihood.is_infinite() || likelihood.is_nan() || likelihood.is_negative() {
-            panic!("Likelihood is infinite, NaN, or negative");
+            return Some(DistributionProblem::InvalidLikelihood(*likelihood));
         }
 
         total += *likelihood as f64;
     }
     if (total - 1.0).abs() > 0.001 {
-        println!("Warning: Likelihoods do not sum to 1.0");
+        return Some(DistributionProblem::NotNormalized(total));
     }
+    None
 }
 
 pub fn print_top_k_tokens(
@@ -38,69 +226,489 @@ pub fn print_top_k_tokens(
     likelihoods: &std::collections::HashMap<Token, f32>,
     k: usize,
 ) {
+    write_top_k_tokens(&mut std::io::stdout(), tokenizer, likelihoods, k).expect("Failed to write to stdout");
+}
+
+/// Writes the k most likely tokens, one per row after a header. Writes nothing for k = 0.
+pub fn write_top_k_tokens(
+    out: &mut impl std::io::Write,
+    tokenizer: &Tokenizer,
+    likelihoods: &std::collections::HashMap<Token, f32>,
+    k: usize,
+) -> std::io::Result<()> {
+    if k == 0 {
+        return Ok(());
+    }
     let mut sorted_likelihoods: Vec<_> = likelihoods.iter().collect();
     sorted_likelihoods.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap());
 
-    let reverse_tokens = &tokenizer.build_reverse_map();
+    let reverse_tokens = tokenizer.reverse_map();
 
-    println!("Top {} tokens:", k);
+    writeln!(out, "Top {} tokens:", k)?;
     for (token, likelihood) in sorted_likelihoods.iter().take(k) {
         let token = *token;
         let token_str = reverse_tokens.get(token).unwrap();
-        println!("Token: {:?}, Likelihood: {}", token_str, likelihood);
+        writeln!(out, "Token: {:?}, Likelihood: {}", token_str, likelihood)?;
+    }
+    Ok(())
+}
+
+/// Options controlling which positions `evaluate_with_options` scores
+#[derive(Debug, Default, Clone)]
+pub struct EvalOptions {
+    /// Score only this many randomly chosen positions instead of all of them.
+    /// The stderr fields cover both the sampling error, with a finite population correction,
+    /// and the error of the full evaluation, so they widen with fewer positions.
+    pub sample: Option<usize>,
+    /// Seed for choosing the sampled positions, random if none
+    pub seed: Option<u64>,
+    /// Only consider this many consecutive positions after the warmup, applied before sampling
+    pub max_positions: Option<usize>,
+    /// What to do when the model predicts a malformed distribution
+    pub invalid_distributions: InvalidDistributionHandling,
+    /// The text holds token ids for `Tokenizer::encode_token_ids` instead of raw text
+    pub pretokenized: bool,
+    /// Record the rank of each ground truth token in `ModelStats::rank_histogram`
+    pub record_ranks: bool,
+    /// Text separating independent documents in the evaluated text. Each document is
+    /// tokenized and scored on its own, so no context crosses a boundary.
+    pub document_delimiter: Option<String>,
+    /// Positions skipped at the start of each document, `Model::recommended_warmup` if none,
+    /// so every model is scored once it sees its full context. At least `MIN_EVAL_WARMUP`.
+    pub document_warmup: Option<usize>,
+}
+
+/// How `evaluate_with_options` treats malformed distributions.
+/// They are counted in `ModelStats::invalid_distribution_count` in every mode.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidDistributionHandling {
+    /// Fail on NaN, infinite or negative likelihoods, only warn if they don't sum to 1
+    #[default]
+    Fail,
+    /// Log the position and score it anyway
+    Count,
+    /// Log the position and leave it out of the statistics
+    Skip,
+}
+
+impl std::str::FromStr for InvalidDistributionHandling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(InvalidDistributionHandling::Fail),
+            "count" => Ok(InvalidDistributionHandling::Count),
+            "skip" => Ok(InvalidDistributionHandling::Skip),
+            _ => Err(format!("unknown invalid distribution handling: {}", s)),
+        }
     }
 }
+
+impl EvalOptions {
+    /// Uses the evaluation settings stored in the training options
+    pub fn from_training_options(options: &TrainingOptions) -> Self {
+        EvalOptions {
+            max_positions: options.eval_max_positions,
+            pretokenized: options.pretokenized,
+            ..EvalOptions::default()
+        }
+    }
+
+    /// Sets `document_warmup` to the longest of the given recommended warmups unless one is set,
+    /// so models evaluated side by side score the same positions and `ModelStats::diff` compares like with like
+    pub fn with_shared_warmup(self, warmups: impl IntoIterator<Item = usize>) -> Self {
+        EvalOptions {
+            document_warmup: self.document_warmup.or_else(|| warmups.into_iter().max()),
+            ..self
+        }
+    }
+}
+
 /// Evaluates a model implementing the Model trait on the given text
-pub fn evaluate<M: Model>(model: &M, text: String, tokenizer: &Tokenizer) -> ModelStats {
-    let tokens = tokenizer.encode_fast(text);
-
-    let progress_bar = ProgressBar::new((tokens.len() as u64).saturating_sub(1));
-    progress_bar.set_style(
-        ProgressStyle::default_bar()
-            .template(
-                "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {eta} rem. ({msg})",
-            )
-            .expect("Failed to set progress bar style")
-            .progress_chars("#>-"),
-    );
-    progress_bar.inc(1);
+pub fn evaluate<M: Model>(model: &M, text: String, tokenizer: &Tokenizer) -> Result<ModelStats, ClmError> {
+    evaluate_with_options(model, text, tokenizer, &EvalOptions::default())
+}
+
+/// Evaluates a model on the given text, scoring the positions selected by `options`
+pub fn evaluate_with_options<M: Model>(model: &M, text: String, tokenizer: &Tokenizer, options: &EvalOptions) -> Result<ModelStats, ClmError> {
+    run_evaluation(model, text, tokenizer, options, 0, |_| {})
+}
+
+/// Evaluates a model like `evaluate`, calling `callback` with the running stats after every
+/// `every` scored positions, e.g. to feed a live dashboard. An `every` of 0 never calls it.
+pub fn evaluate_with_callback<M: Model>(
+    model: &M,
+    text: String,
+    tokenizer: &Tokenizer,
+    every: usize,
+    callback: impl FnMut(&ModelStats),
+) -> Result<ModelStats, ClmError> {
+    run_evaluation(model, text, tokenizer, &EvalOptions::default(), every, callback)
+}
+
+fn run_evaluation<M: Model>(
+    model: &M,
+    text: String,
+    tokenizer: &Tokenizer,
+    options: &EvalOptions,
+    every: usize,
+    mut callback: impl FnMut(&ModelStats),
+) -> Result<ModelStats, ClmError> {
+    let texts: Vec<String> = match &options.document_delimiter {
+        Some(delimiter) if !delimiter.is_empty() => text.split(delimiter.as_str()).map(str::to_string).collect(),
+        _ => vec![text],
+    };
+    let documents = texts
+        .into_iter()
+        .map(|text| {
+            if options.pretokenized {
+                tokenizer.encode_token_ids(&text)
+            } else {
+                Ok(tokenizer.encode_fast(text))
+            }
+        })
+        .collect::<Result<Vec<Vec<Token>>, ClmError>>()?;
+
+    // (document, position) pairs in text order
+    let warmup = options
+        .document_warmup
+        .unwrap_or_else(|| model.recommended_warmup())
+        .max(MIN_EVAL_WARMUP);
+    let mut positions: Vec<(usize, usize)> = documents
+        .iter()
+        .enumerate()
+        .flat_map(|(document, tokens)| (warmup..tokens.len()).map(move |pos| (document, pos)))
+        .collect();
+    if let Some(max_positions) = options.max_positions {
+        positions.truncate(max_positions);
+    }
+    // Positions the sample is drawn from, none if every position is scored
+    let population = options.sample.map(|_| positions.len());
+    if let Some(sample) = options.sample {
+        let mut rng = match options.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        let mut sampled: Vec<(usize, usize)> = rand::seq::index::sample(&mut rng, positions.len(), sample.min(positions.len()))
+            .into_iter()
+            .map(|index| positions[index])
+            .collect();
+        // Keep the positions in text order so consecutive contexts can share work
+        sampled.sort_unstable();
+        positions = sampled;
+    }
+
+    let progress_bar = ProgressBarBuilder::new(positions.len() as u64).unit("positions").build();
 
     let all_tokens = tokenizer.get_tokens();
+    let reverse_tokens = tokenizer.reverse_map();
+    let mut scored_bytes = 0;
+    let mut invalid_distribution_count = 0;
+    let mut rank_histogram: Vec<usize> = Vec::new();
     let time = std::time::Instant::now();
 
-    let positions: Vec<usize> = (32..tokens.len()).collect();
-    let mut likelihoods: Vec<f64> = Vec::with_capacity(positions.len());
-    for &pos in positions.iter() {
-        let current_text = tokens[0..(pos - 1)].to_vec();
-        let ground_truth = tokens[pos].clone();
+    let mut log_likelihoods: Vec<f64> = Vec::with_capacity(positions.len());
+    for batch in positions.chunks(EVAL_BATCH_SIZE) {
+        let contexts: Vec<Vec<Token>> = batch
+            .iter()
+            .map(|&(document, pos)| documents[document][..pos].to_vec())
+            .collect();
+        let batch_log_likelihoods = model.compute_log_likelihoods_batch(&contexts, all_tokens)?;
 
-        let token_likelihoods = model.compute_likelihoods(current_text, &all_tokens);
-        check_distribution(&token_likelihoods);
+        for (&(document, pos), token_log_likelihoods) in batch.iter().zip(batch_log_likelihoods) {
+            let ground_truth = documents[document][pos].clone();
+            // Only used for checking the distribution, scores stay in log space
+            let token_likelihoods: std::collections::HashMap<&Token, f32> = token_log_likelihoods
+                .iter()
+                .map(|(token, log_likelihood)| (token, log_likelihood.exp() as f32))
+                .collect();
+            if let Some(problem) = distribution_problem(&token_likelihoods) {
+                invalid_distribution_count += 1;
+                match options.invalid_distributions {
+                    InvalidDistributionHandling::Fail => check_distribution(&token_likelihoods)?,
+                    InvalidDistributionHandling::Count => {
+                        log::warn!("Malformed distribution at position {} of document {}: {:?}", pos, document, problem);
+                    }
+                    InvalidDistributionHandling::Skip => {
+                        log::warn!("Skipping malformed distribution at position {} of document {}: {:?}", pos, document, problem);
+                        progress_bar.inc(1);
+                        continue;
+                    }
+                }
+            }
 
-        let ground_truth_likelihood = token_likelihoods.get(&ground_truth)
-            .unwrap_or_else(|| panic!("Ground truth token not found in likelihoods!"));
-        progress_bar.inc(1);
-        let stats = calculate_model_stats(&likelihoods, Duration::from_micros(1), &all_tokens);
-        progress_bar.set_message(format!("ppt: {:.2}", stats.ppt));
-        likelihoods.push(*ground_truth_likelihood as f64);
+            let ground_truth_log_likelihood = token_log_likelihoods.get(&ground_truth).ok_or_else(|| {
+                ClmError::Evaluation("ground truth token not found in likelihoods".to_string())
+            })?;
+            progress_bar.inc(1);
+            let stats = calculate_model_stats(&log_likelihoods, Duration::from_micros(1), all_tokens, scored_bytes, population);
+            progress_bar.set_message(format!("ppt: {:.2}", stats.ppt));
+            log_likelihoods.push(*ground_truth_log_likelihood);
+            if options.record_ranks {
+                // Ties count in the ground truth's favor
+                let rank = token_log_likelihoods
+                    .values()
+                    .filter(|&&log_likelihood| log_likelihood > *ground_truth_log_likelihood)
+                    .count();
+                if rank_histogram.len() <= rank {
+                    rank_histogram.resize(rank + 1, 0);
+                }
+                rank_histogram[rank] += 1;
+            }
+            scored_bytes += reverse_tokens.get(&ground_truth).map_or(0, |content| content.len());
+            if every > 0 && log_likelihoods.len().is_multiple_of(every) {
+                callback(&ModelStats {
+                    invalid_distribution_count,
+                    rank_histogram: rank_histogram.clone(),
+                    ..calculate_model_stats(&log_likelihoods, time.elapsed(), all_tokens, scored_bytes, population)
+                });
+            }
+        }
     }
 
     let elapsed_time = time.elapsed();
     progress_bar.finish_and_clear();
 
-    let model_stats = calculate_model_stats(&likelihoods, elapsed_time, &all_tokens);
-    model_stats
+    Ok(ModelStats {
+        invalid_distribution_count,
+        rank_histogram,
+        ..calculate_model_stats(&log_likelihoods, elapsed_time, all_tokens, scored_bytes, population)
+    })
+}
+
+/// How well plain zstd compresses a text, the baseline a CLM should beat
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BaselineStats {
+    /// Raw size divided by compressed size
+    pub ratio: f64,
+    /// Compressed bits per raw byte
+    pub bits_per_byte: f64,
+}
+
+/// Compresses the text with zstd at the given level, without any dictionary
+pub fn compression_baseline(text: &str, level: i32) -> Result<BaselineStats, ClmError> {
+    let raw = text.as_bytes();
+    let mut dst = vec![0u8; unsafe { zstd_sys::ZSTD_compressBound(raw.len()) }];
+    let compressed_size = unsafe {
+        zstd_sys::ZSTD_compress(
+            dst.as_mut_ptr() as *mut _,
+            dst.len(),
+            raw.as_ptr() as *const _,
+            raw.len(),
+            level,
+        )
+    };
+    if unsafe { zstd_sys::ZSTD_isError(compressed_size) } != 0 {
+        return Err(ClmError::Zstd(format!("failed to compress the baseline text at level {}", level)));
+    }
+    Ok(BaselineStats {
+        ratio: raw.len() as f64 / compressed_size as f64,
+        bits_per_byte: if raw.is_empty() { 0.0 } else { compressed_size as f64 * 8.0 / raw.len() as f64 },
+    })
+}
+
+/// The log-probability of a text under a model
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SequenceScore {
+    /// Total natural log-probability of all scored tokens
+    pub log_prob: f64,
+    /// Natural log-probability of each token after the first
+    pub per_token: Vec<f64>,
+}
+
+/// Scores a text under the model by summing the log-likelihoods of its tokens.
+/// The first token only serves as context, since most models can't predict without one.
+pub fn score_sequence<M: Model>(model: &M, text: &str, tokenizer: &Tokenizer) -> Result<SequenceScore, ClmError> {
+    let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+    let all_tokens = tokenizer.get_tokens();
+
+    let mut per_token = Vec::with_capacity(tokens.len().saturating_sub(1));
+    let positions: Vec<usize> = (1..tokens.len()).collect();
+    for batch in positions.chunks(EVAL_BATCH_SIZE) {
+        let contexts: Vec<Vec<Token>> = batch.iter().map(|&pos| tokens[..pos].to_vec()).collect();
+        let batch_log_likelihoods = model.compute_log_likelihoods_batch(&contexts, all_tokens)?;
+        for (&pos, token_log_likelihoods) in batch.iter().zip(batch_log_likelihoods) {
+            let log_likelihood = token_log_likelihoods.get(&tokens[pos]).ok_or_else(|| {
+                ClmError::Evaluation("token not found in likelihoods".to_string())
+            })?;
+            per_token.push(*log_likelihood);
+        }
+    }
+
+    Ok(SequenceScore {
+        log_prob: per_token.iter().sum(),
+        per_token,
+    })
+}
+
+/// Score of one token appended to an `IncrementalScorer`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct IncrementalScore {
+    /// Negative natural log-likelihood of the appended token
+    pub surprisal: f64,
+    /// Perplexity of all tokens appended so far
+    pub perplexity: f64,
+}
+
+/// Scores tokens one at a time as they are appended to a running context, e.g. a chat
+/// while it is typed. Consecutive predictions share compressed sizes while the context
+/// still fits the model's window, see `CompressionCache`.
+pub struct IncrementalScorer<'a, M: Model> {
+    model: &'a M,
+    all_tokens: &'a [Token],
+    context: Vec<Token>,
+    cache: CompressionCache,
+    total_surprisal: f64,
+    scored: usize,
+}
+
+impl<'a, M: Model> IncrementalScorer<'a, M> {
+    /// Starts scoring after `context`, whose own tokens are not scored
+    pub fn new(model: &'a M, all_tokens: &'a [Token], context: Vec<Token>) -> Self {
+        IncrementalScorer {
+            model,
+            all_tokens,
+            context,
+            cache: CompressionCache::default(),
+            total_surprisal: 0.0,
+            scored: 0,
+        }
+    }
+
+    /// Scores `token` given the running context, then appends it to the context
+    pub fn push(&mut self, token: Token) -> Result<IncrementalScore, ClmError> {
+        let log_likelihoods = self
+            .model
+            .compute_log_likelihoods_incremental(&self.context, self.all_tokens, &mut self.cache)?;
+        let log_likelihood = log_likelihoods
+            .get(&token)
+            .ok_or_else(|| ClmError::Evaluation("token not found in likelihoods".to_string()))?;
+
+        let surprisal = -log_likelihood;
+        self.total_surprisal += surprisal;
+        self.scored += 1;
+        self.context.push(token);
+        Ok(IncrementalScore {
+            surprisal,
+            perplexity: self.perplexity(),
+        })
+    }
+
+    /// Perplexity of all tokens appended so far, 1 if there are none
+    pub fn perplexity(&self) -> f64 {
+        if self.scored == 0 {
+            return 1.0;
+        }
+        (self.total_surprisal / self.scored as f64).exp()
+    }
+
+    /// The context so far, including the appended tokens
+    pub fn context(&self) -> &[Token] {
+        &self.context
+    }
+}
+
+/// Spreads each token's surprisal evenly over its characters, e.g. to draw a heatmap of the
+/// spots the model finds hard. Returns each scored token's character range in the normalized
+/// text together with its surprisal per character in nats. Like `score_sequence`, the first
+/// token only serves as context.
+pub fn evaluate_per_char<M: Model>(
+    model: &M,
+    text: &str,
+    tokenizer: &Tokenizer,
+) -> Result<Vec<(std::ops::Range<usize>, f64)>, ClmError> {
+    let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+    let score = score_sequence(model, text, tokenizer)?;
+    let reverse_tokens = tokenizer.reverse_map();
+
+    let mut start = 0;
+    let mut ranges = Vec::with_capacity(score.per_token.len());
+    for (i, token) in tokens.iter().enumerate() {
+        let len = reverse_tokens.get(token).map_or(0, |content| content.chars().count());
+        // Score i belongs to token i + 1
+        if i > 0 && len > 0 {
+            ranges.push((start..start + len, -score.per_token[i - 1] / len as f64));
+        }
+        start += len;
+    }
+    Ok(ranges)
+}
+
+/// How likely a candidate continuation is after a context
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CandidateScore {
+    pub candidate: String,
+    /// Natural log-probability of all the candidate's tokens following the context
+    pub log_prob: f64,
+    pub probability: f64,
 }
 
-/// Calculates statistics for model evaluation from the token likelihoods
-fn calculate_model_stats(likelihoods: &[f64], elapsed_time: std::time::Duration, all_tokens: &[Token]) -> ModelStats {
-    let average_likelihood = likelihoods.iter().sum::<f64>() / likelihoods.len() as f64;
+/// Scores each candidate as a continuation of the context, e.g. for re-ranking.
+/// Returns the candidates from most to least likely.
+pub fn score_candidates<M: Model>(
+    model: &M,
+    tokenizer: &Tokenizer,
+    context: &str,
+    candidates: &[String],
+) -> Result<Vec<CandidateScore>, ClmError> {
+    let context_tokens = tokenizer.encode_fast_opt(context.to_string(), true);
+    let all_tokens = tokenizer.get_tokens();
+
+    let mut candidate_tokens = Vec::new();
+    let mut scores = candidates
+        .iter()
+        .map(|candidate| {
+            tokenizer.encode_into(candidate, &mut candidate_tokens);
+            let contexts: Vec<Vec<Token>> = (0..candidate_tokens.len())
+                .map(|i| [context_tokens.as_slice(), &candidate_tokens[..i]].concat())
+                .collect();
+            let log_prob = model
+                .compute_log_likelihoods_batch(&contexts, all_tokens)?
+                .iter()
+                .zip(&candidate_tokens)
+                .map(|(log_likelihoods, token)| {
+                    log_likelihoods.get(token).copied().ok_or_else(|| {
+                        ClmError::Evaluation("token not found in likelihoods".to_string())
+                    })
+                })
+                .sum::<Result<f64, ClmError>>()?;
+            Ok(CandidateScore {
+                candidate: candidate.clone(),
+                log_prob,
+                probability: log_prob.exp(),
+            })
+        })
+        .collect::<Result<Vec<_>, ClmError>>()?;
+    scores.sort_by(|a, b| b.log_prob.total_cmp(&a.log_prob));
+    Ok(scores)
+}
 
-    let cross_entropies = likelihoods.iter().map(|&x| -x.ln()).collect::<Vec<_>>();
+/// Calculates statistics for model evaluation from the natural log-likelihoods of the scored tokens.
+/// `population` is the number of positions the scored ones were sampled from, none if all were scored.
+fn calculate_model_stats(
+    log_likelihoods: &[f64],
+    elapsed_time: std::time::Duration,
+    all_tokens: &[Token],
+    scored_bytes: usize,
+    population: Option<usize>,
+) -> ModelStats {
+    let average_likelihood = log_likelihoods.iter().map(|x| x.exp()).sum::<f64>() / log_likelihoods.len() as f64;
+
+    let cross_entropies = log_likelihoods.iter().map(|&x| -x).collect::<Vec<_>>();
     let cross_entropy_mean = cross_entropies.iter().sum::<f64>() / (cross_entropies.len()) as f64;
     
-    let cross_entropy_variance = cross_entropies.iter().map(|&x| (x - cross_entropy_mean).powi(2)).sum::<f64>() / (cross_entropies.len()-1) as f64;
-    let cross_entropy_stderr = cross_entropy_variance.sqrt() / (cross_entropies.len() as f64).sqrt();
+    let cross_entropy_variance = cross_entropies.iter().map(|&x| (x - cross_entropy_mean).powi(2)).sum::<f64>() / cross_entropies.len().saturating_sub(1) as f64;
+    // A sample of n out of N positions misses the full mean by the finite population corrected
+    // variance / n * (N - n) / (N - 1), on top of the full mean's own variance / N
+    let sample_size = cross_entropies.len() as f64;
+    let variance_factor = match population {
+        Some(population) if population > 1 => {
+            let population = population as f64;
+            (population - sample_size) / (population - 1.0) + sample_size / population
+        }
+        _ => 1.0,
+    };
+    let cross_entropy_stderr = (cross_entropy_variance / sample_size * variance_factor).sqrt();
 
     let perplexity = cross_entropy_mean.exp();
     let perplexity_stderr = perplexity * cross_entropy_stderr;
@@ -108,13 +716,519 @@ fn calculate_model_stats(likelihoods: &[f64], elapsed_time: std::time::Duration,
     let ppt = perplexity / all_tokens.len() as f64;
     let ppt_stderr = perplexity_stderr / all_tokens.len() as f64;
 
+    // Total information of the scored tokens spread over the bytes of their text
+    let bits_per_byte = if scored_bytes > 0 {
+        cross_entropies.iter().sum::<f64>() / 2f64.ln() / scored_bytes as f64
+    } else {
+        0.0
+    };
+
     ModelStats {
         average_likelihood,
         cross_entropy: cross_entropy_mean,
+        cross_entropy_bits: cross_entropy_mean / 2f64.ln(),
         perplexity,
         perplexity_stderr,
-        time_per_token: elapsed_time.as_secs_f64() / likelihoods.len() as f64,
+        time_per_token: elapsed_time.as_secs_f64() / log_likelihoods.len() as f64,
         ppt,
         ppt_stderr,
+        bits_per_byte,
+        positions: log_likelihoods.len(),
+        invalid_distribution_count: 0,
+        rank_histogram: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clm::ngram_model::{BigramModel, UnigramModel};
+
+    #[test]
+    fn test_score_sequence_sums_per_token() {
+        let text = "abracadabra";
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(text, 5);
+        let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+        let model = UnigramModel::train(tokens.clone(), TrainingOptions::default()).unwrap();
+
+        let score = score_sequence(&model, "abraca", &tokenizer).unwrap();
+        assert_eq!(score.per_token.len(), tokenizer.encode_fast_opt("abraca".to_string(), true).len() - 1);
+        assert!((score.log_prob - score.per_token.iter().sum::<f64>()).abs() < 1e-12);
+        assert!(score.per_token.iter().all(|log_prob| *log_prob < 0.0));
+    }
+
+    #[test]
+    fn test_per_char_surprisal_sums_to_total() {
+        let text = "abracadabra";
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(text, 5);
+        let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+        let model = UnigramModel::train(tokens.clone(), TrainingOptions::default()).unwrap();
+
+        let per_char = evaluate_per_char(&model, text, &tokenizer).unwrap();
+        assert_eq!(per_char.len(), tokens.len() - 1);
+        assert_eq!(per_char.last().unwrap().0.end, text.len());
+        let total: f64 = per_char.iter().map(|(range, surprisal)| range.len() as f64 * surprisal).sum();
+        let score = score_sequence(&model, text, &tokenizer).unwrap();
+        // The mean cross-entropy times the number of scored tokens
+        let cross_entropy = -score.log_prob / score.per_token.len() as f64;
+        assert!((total - cross_entropy * score.per_token.len() as f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_score_candidates_orders_by_probability() {
+        let text = "aaaaaaaaab";
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(text, 2);
+        let model = UnigramModel::train(tokenizer.encode_fast_opt(text.to_string(), true), TrainingOptions::default()).unwrap();
+
+        let candidates = ["b", "a", "aa"].map(String::from);
+        let scores = score_candidates(&model, &tokenizer, "ab", &candidates).unwrap();
+        let order: Vec<&str> = scores.iter().map(|score| score.candidate.as_str()).collect();
+        assert_eq!(order, ["a", "aa", "b"]);
+        assert!((scores[0].probability - 0.9).abs() < 1e-6);
+        assert!((scores[1].log_prob - 2.0 * 0.9f64.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_write_top_k_tokens_rows() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("abcdef", 6);
+        let model = UnigramModel::train(tokenizer.encode_fast_opt("abcdef".to_string(), true), TrainingOptions::default()).unwrap();
+        let likelihoods = model.compute_likelihoods(Vec::new(), tokenizer.get_tokens()).unwrap();
+
+        let rows = |k| {
+            let mut out = Vec::new();
+            write_top_k_tokens(&mut out, &tokenizer, &likelihoods, k).unwrap();
+            String::from_utf8(out).unwrap().lines().filter(|line| line.starts_with("Token:")).count()
+        };
+        assert_eq!(rows(3), 3);
+        assert_eq!(rows(10), 6);
+        assert_eq!(rows(0), 0);
+    }
+
+    #[test]
+    fn test_cross_entropy_bits() {
+        let all_tokens: Vec<Token> = (0..4).map(|i| vec![i]).collect();
+        let likelihoods: Vec<f64> = [0.25, 0.5, 0.125, 0.25].iter().map(|x: &f64| x.ln()).collect();
+
+        let stats = calculate_model_stats(&likelihoods, Duration::from_secs(1), &all_tokens, 16, None);
+        assert_eq!(stats.cross_entropy_bits, stats.cross_entropy / 2f64.ln());
+        // -log2 of the likelihoods is 2, 1, 3 and 2 bits
+        assert!((stats.cross_entropy_bits - 2.0).abs() < 1e-12);
+        assert!((stats.bits_per_byte - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_check_distribution_rejects_nan() {
+        let likelihoods = std::collections::HashMap::from([(0, 0.5), (1, f32::NAN)]);
+        assert!(matches!(check_distribution(&likelihoods), Err(ClmError::Evaluation(_))));
+
+        let likelihoods = std::collections::HashMap::from([(0, 0.5), (1, 0.5)]);
+        assert!(check_distribution(&likelihoods).is_ok());
+    }
+
+    /// Wraps a model and doubles every other predicted distribution
+    struct FlakyModel {
+        inner: UnigramModel,
+        calls: std::cell::Cell<usize>,
+    }
+
+    impl Model for FlakyModel {
+        fn train(tokens: Vec<Token>, options: TrainingOptions) -> Result<Self, ClmError> {
+            Ok(FlakyModel {
+                inner: UnigramModel::train(tokens, options)?,
+                calls: std::cell::Cell::new(0),
+            })
+        }
+
+        fn compute_likelihoods(&self, current_text: Vec<Token>, all_tokens: &[Token]) -> Result<std::collections::HashMap<Token, f32>, ClmError> {
+            self.calls.set(self.calls.get() + 1);
+            let mut likelihoods = self.inner.compute_likelihoods(current_text, all_tokens)?;
+            if self.calls.get().is_multiple_of(2) {
+                likelihoods.values_mut().for_each(|likelihood| *likelihood *= 2.0);
+            }
+            Ok(likelihoods)
+        }
+
+        fn memory_footprint(&self) -> usize {
+            self.inner.memory_footprint()
+        }
+
+        fn name(&self) -> String {
+            format!("flaky {}", self.inner.name())
+        }
+    }
+
+    /// Gives every token but the first a likelihood far below f32's smallest value
+    struct SkewedModel;
+
+    impl Model for SkewedModel {
+        fn train(_tokens: Vec<Token>, _options: TrainingOptions) -> Result<Self, ClmError> {
+            Ok(SkewedModel)
+        }
+
+        fn compute_likelihoods(&self, current_text: Vec<Token>, all_tokens: &[Token]) -> Result<std::collections::HashMap<Token, f32>, ClmError> {
+            Ok(self
+                .compute_log_likelihoods(current_text, all_tokens)?
+                .into_iter()
+                .map(|(token, log_likelihood)| (token, log_likelihood.exp() as f32))
+                .collect())
+        }
+
+        fn compute_log_likelihoods(&self, _current_text: Vec<Token>, all_tokens: &[Token]) -> Result<std::collections::HashMap<Token, f64>, ClmError> {
+            Ok(all_tokens
+                .iter()
+                .enumerate()
+                .map(|(i, token)| (token.clone(), if i == 0 { 0.0 } else { -200.0 }))
+                .collect())
+        }
+
+        fn memory_footprint(&self) -> usize {
+            0
+        }
+
+        fn name(&self) -> String {
+            String::from("skewed")
+        }
+    }
+
+    #[test]
+    fn test_tiny_likelihoods_give_finite_cross_entropy() {
+        let text = pseudo_random_text(6, 100);
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(&text, 1);
+        let model = SkewedModel::train(Vec::new(), TrainingOptions::default()).unwrap();
+        assert!(model.compute_likelihoods(Vec::new(), tokenizer.get_tokens()).unwrap().values().any(|&l| l == 0.0));
+
+        let options = EvalOptions {
+            invalid_distributions: InvalidDistributionHandling::Count,
+            ..EvalOptions::default()
+        };
+        let stats = evaluate_with_options(&model, text, &tokenizer, &options).unwrap();
+        assert!(stats.cross_entropy.is_finite(), "{:?}", stats);
+        assert!(stats.cross_entropy > 100.0);
+    }
+
+    #[test]
+    fn test_invalid_distributions_are_counted() {
+        let text = pseudo_random_text(4, 200);
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(&text, 1);
+        let tokens = tokenizer.encode_fast_opt(text.clone(), true);
+        let options = |invalid_distributions| EvalOptions {
+            max_positions: Some(100),
+            invalid_distributions,
+            ..EvalOptions::default()
+        };
+
+        let model = FlakyModel::train(tokens.clone(), TrainingOptions::default()).unwrap();
+        let stats = evaluate_with_options(&model, text.clone(), &tokenizer, &options(InvalidDistributionHandling::Count)).unwrap();
+        assert_eq!(stats.invalid_distribution_count, 50);
+        assert_eq!(stats.positions, 100);
+
+        let model = FlakyModel::train(tokens, TrainingOptions::default()).unwrap();
+        let stats = evaluate_with_options(&model, text, &tokenizer, &options(InvalidDistributionHandling::Skip)).unwrap();
+        assert_eq!(stats.invalid_distribution_count, 50);
+        assert_eq!(stats.positions, 50);
+    }
+
+    fn pseudo_random_text(seed: usize, words: usize) -> String {
+        let vocabulary = ["the", "cat", "sat", "on", "a", "mat", "dog", "ran", "far", "away", "quickly"];
+        (0..words)
+            .map(|i| vocabulary[(i * 7919 + seed * 104729 + i * i * 31) % vocabulary.len()])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn test_sampled_evaluation_within_stderr() {
+        let train_text = "the cat sat on the mat and the dog ran far away from the cat. ".repeat(20);
+        let test_text = "a dog and a cat sat far from the mat, then the cat ran away. ".repeat(10);
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(&train_text, 30);
+        let model = BigramModel::train(tokenizer.encode_fast_opt(train_text, true), TrainingOptions::default()).unwrap();
+
+        let full = evaluate(&model, test_text.clone(), &tokenizer).unwrap();
+        let options = EvalOptions {
+            sample: Some(60),
+            seed: Some(42),
+            ..EvalOptions::default()
+        };
+        let sampled = evaluate_with_options(&model, test_text, &tokenizer, &options).unwrap();
+
+        assert!(sampled.perplexity_stderr > full.perplexity_stderr);
+        assert!((sampled.perplexity - full.perplexity).abs() < 3.0 * sampled.perplexity_stderr);
+    }
+
+    #[test]
+    fn test_sampling_every_position_matches_full_stderr() {
+        let log_likelihoods: Vec<f64> = [0.5, 0.25, 0.125, 0.5, 0.25].iter().map(|x: &f64| x.ln()).collect();
+        let all_tokens: Vec<Token> = (0..4).map(|i| vec![i]).collect();
+
+        let full = calculate_model_stats(&log_likelihoods, Duration::from_secs(1), &all_tokens, 5, None);
+        let census = calculate_model_stats(&log_likelihoods, Duration::from_secs(1), &all_tokens, 5, Some(5));
+        assert!((census.perplexity_stderr - full.perplexity_stderr).abs() < 1e-12);
+        // The same positions drawn from a larger text also carry the sampling error
+        let sampled = calculate_model_stats(&log_likelihoods, Duration::from_secs(1), &all_tokens, 5, Some(50));
+        assert!(sampled.perplexity_stderr > full.perplexity_stderr);
+    }
+
+    #[test]
+    fn test_incremental_scorer_matches_evaluate() {
+        let text = pseudo_random_text(5, 200);
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(&text, 1);
+        // A bigram model depends on the token right before each position
+        let model = BigramModel::train(tokenizer.encode_fast_opt(pseudo_random_text(6, 400), true), TrainingOptions::default()).unwrap();
+        let tokens = tokenizer.encode_fast(text.clone());
+
+        // `evaluate` starts scoring at the model's warmup
+        let warmup = model.recommended_warmup();
+        let mut scorer = IncrementalScorer::new(&model, tokenizer.get_tokens(), tokens[..warmup].to_vec());
+        let mut last = None;
+        for token in &tokens[warmup..] {
+            last = Some(scorer.push(token.clone()).unwrap());
+        }
+
+        let stats = evaluate(&model, text, &tokenizer).unwrap();
+        let last = last.unwrap();
+        assert!((last.perplexity - stats.perplexity).abs() < 1e-9 * stats.perplexity);
+        assert_eq!(scorer.context().len(), tokens.len());
+    }
+
+    #[test]
+    fn test_evaluation_context_ends_before_scored_token() {
+        let text = pseudo_random_text(7, 50);
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(&text, 1);
+        let tokens = tokenizer.encode_fast(text.clone());
+        let model = RecordingModel::train(Vec::new(), TrainingOptions::default()).unwrap();
+
+        let stats = evaluate(&model, text, &tokenizer).unwrap();
+        let contexts = model.contexts.borrow();
+        assert_eq!(contexts.len(), stats.positions);
+        for (pos, context) in (model.recommended_warmup()..tokens.len()).zip(contexts.iter()) {
+            assert_eq!(context.as_slice(), &tokens[..pos]);
+        }
+    }
+
+    /// Predicts uniformly and records every context it was asked about
+    struct RecordingModel {
+        contexts: std::cell::RefCell<Vec<Vec<Token>>>,
+    }
+
+    impl Model for RecordingModel {
+        fn train(_tokens: Vec<Token>, _options: TrainingOptions) -> Result<Self, ClmError> {
+            Ok(RecordingModel { contexts: Default::default() })
+        }
+
+        fn compute_likelihoods(&self, current_text: Vec<Token>, all_tokens: &[Token]) -> Result<std::collections::HashMap<Token, f32>, ClmError> {
+            self.contexts.borrow_mut().push(current_text);
+            Ok(all_tokens.iter().map(|token| (token.clone(), 1.0 / all_tokens.len() as f32)).collect())
+        }
+
+        fn memory_footprint(&self) -> usize {
+            0
+        }
+
+        fn name(&self) -> String {
+            String::from("recording")
+        }
+    }
+
+    #[test]
+    fn test_document_delimiter_resets_context() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("ab", 2);
+        let a = tokenizer.encode_fast("a".to_string())[0].clone();
+        let b = tokenizer.encode_fast("b".to_string())[0].clone();
+        let text = format!("{}\n\n{}", "a".repeat(40), "b".repeat(30));
+        let model = RecordingModel::train(Vec::new(), TrainingOptions::default()).unwrap();
+
+        let options = EvalOptions {
+            document_delimiter: Some("\n\n".to_string()),
+            document_warmup: Some(5),
+            ..EvalOptions::default()
+        };
+        let stats = evaluate_with_options(&model, text, &tokenizer, &options).unwrap();
+        assert_eq!(stats.positions, (40 - 5) + (30 - 5));
+
+        let contexts = model.contexts.borrow();
+        assert_eq!(contexts.len(), stats.positions);
+        // Every context comes from a single document
+        assert!(contexts.iter().all(|context| context.iter().all(|token| *token == a)
+            || context.iter().all(|token| *token == b)));
+        assert!(contexts.iter().any(|context| context.first() == Some(&b)));
+    }
+
+    #[test]
+    fn test_models_skip_their_recommended_warmup() {
+        let text = pseudo_random_text(5, 300);
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(&text, 10);
+        let tokens = tokenizer.encode_fast_opt(text.clone(), true);
+        let options = TrainingOptions {
+            context_window: 12,
+            k: 64,
+            ensemble_size: 1,
+            training_chunk_size: 64,
+            nb_threads: 4,
+            ..TrainingOptions::default()
+        };
+
+        let unigram = UnigramModel::train(tokens.clone(), options.clone()).unwrap();
+        let stats = evaluate(&unigram, text.clone(), &tokenizer).unwrap();
+        assert_eq!(stats.positions, tokens.len() - MIN_EVAL_WARMUP);
+
+        // The first scored context fills the whole window
+        let clm = crate::clm::clm_model::ClmModel::train(tokens.clone(), options).unwrap();
+        assert_eq!(clm.recommended_warmup(), 12);
+        let stats = evaluate(&clm, text.clone(), &tokenizer).unwrap();
+        assert_eq!(stats.positions, tokens.len() - 12);
+
+        // Models compared side by side score the same positions
+        let shared = EvalOptions::default().with_shared_warmup([unigram.recommended_warmup(), clm.recommended_warmup()]);
+        assert_eq!(shared.document_warmup, Some(12));
+        let unigram_stats = evaluate_with_options(&unigram, text.clone(), &tokenizer, &shared).unwrap();
+        let clm_stats = evaluate_with_options(&clm, text.clone(), &tokenizer, &shared).unwrap();
+        assert_eq!(unigram_stats.positions, clm_stats.positions);
+        assert_eq!(unigram_stats.positions, tokens.len() - 12);
+
+        // An explicit warmup applies to every model
+        let options = EvalOptions { document_warmup: Some(20), ..EvalOptions::default() };
+        assert_eq!(options.clone().with_shared_warmup([clm.recommended_warmup()]).document_warmup, Some(20));
+        let stats = evaluate_with_options(&unigram, text, &tokenizer, &options).unwrap();
+        assert_eq!(stats.positions, tokens.len() - 20);
+    }
+
+    #[test]
+    fn test_max_positions_caps_scored_positions() {
+        let text = pseudo_random_text(3, 200);
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(&text, 1);
+        let model = UnigramModel::train(tokenizer.encode_fast_opt(text.clone(), true), TrainingOptions::default()).unwrap();
+
+        let options = EvalOptions::from_training_options(&TrainingOptions {
+            eval_max_positions: Some(50),
+            ..TrainingOptions::default()
+        });
+        let stats = evaluate_with_options(&model, text.clone(), &tokenizer, &options).unwrap();
+        assert_eq!(stats.positions, 50);
+
+        let options = EvalOptions {
+            sample: Some(20),
+            seed: Some(1),
+            max_positions: Some(50),
+            ..EvalOptions::default()
+        };
+        let stats = evaluate_with_options(&model, text, &tokenizer, &options).unwrap();
+        assert_eq!(stats.positions, 20);
+    }
+
+    #[test]
+    fn test_callback_receives_running_stats() {
+        let text = pseudo_random_text(4, 200);
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(&text, 1);
+        let model = UnigramModel::train(tokenizer.encode_fast_opt(text.clone(), true), TrainingOptions::default()).unwrap();
+
+        let mut snapshots = Vec::new();
+        let stats = evaluate_with_callback(&model, text, &tokenizer, 25, |stats| snapshots.push(stats.positions)).unwrap();
+        assert_eq!(snapshots.len(), stats.positions / 25);
+        assert_eq!(snapshots, (1..=snapshots.len()).map(|i| i * 25).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_rank_histogram_of_certain_model() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("ab", 2);
+        let text = "a".repeat(100);
+        let model = UnigramModel::train(
+            tokenizer.encode_fast_opt(format!("{}b", text), true),
+            TrainingOptions::default(),
+        ).unwrap();
+
+        let options = EvalOptions {
+            record_ranks: true,
+            ..EvalOptions::default()
+        };
+        let stats = evaluate_with_options(&model, text.clone(), &tokenizer, &options).unwrap();
+        assert_eq!(stats.rank_histogram, vec![stats.positions]);
+        assert!(evaluate(&model, text, &tokenizer).unwrap().rank_histogram.is_empty());
+    }
+
+    #[test]
+    fn test_compression_baseline() {
+        let text = pseudo_random_text(5, 2000);
+        let stats = compression_baseline(&text, 3).unwrap();
+        assert!(stats.bits_per_byte > 0.0);
+        assert!(stats.ratio > 1.0);
+        assert!((stats.bits_per_byte - 8.0 / stats.ratio).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_csv_row_matches_header() {
+        let stats = ModelStats {
+            perplexity: 190.5,
+            bits_per_byte: 2.25,
+            positions: 1000,
+            invalid_distribution_count: 3,
+            ..ModelStats::default()
+        };
+        let options = TrainingOptions::default();
+        let header = ModelStats::csv_header();
+        assert!(header.starts_with("model,model_id,ensemble_size,"));
+
+        let row = stats.to_csv_row("clm[ens=15,tbs=5]", &options);
+        // The model name contains commas, so it is quoted
+        let row = row.strip_prefix("\"clm[ens=15,tbs=5]\",").unwrap();
+        let columns: Vec<&str> = header.split(',').skip(1).collect();
+        let values: Vec<&str> = row.split(',').collect();
+        assert_eq!(columns.len(), values.len());
+        let value = |column: &str| values[columns.iter().position(|c| *c == column).unwrap()];
+        assert_eq!(value("ensemble_size"), "15");
+        assert_eq!(value("perplexity"), "190.5");
+        assert_eq!(value("bits_per_byte"), "2.25");
+        assert_eq!(value("positions"), "1000");
+        assert_eq!(value("invalid_distribution_count"), "3");
+    }
+
+    #[test]
+    fn test_stats_diff() {
+        let baseline = ModelStats {
+            perplexity: 200.0,
+            perplexity_stderr: 2.0,
+            cross_entropy: 200f64.ln(),
+            bits_per_byte: 2.0,
+            time_per_token: 0.01,
+            ppt: 1.0,
+            ..ModelStats::default()
+        };
+        let better = ModelStats {
+            perplexity: 190.0,
+            perplexity_stderr: 2.0,
+            cross_entropy: 190f64.ln(),
+            bits_per_byte: 1.9,
+            time_per_token: 0.02,
+            ppt: 0.95,
+            ..ModelStats::default()
+        };
+
+        let diff = better.diff(&baseline);
+        assert!(diff.perplexity.absolute < 0.0);
+        assert!((diff.perplexity.relative + 0.05).abs() < 1e-12);
+        assert!(diff.cross_entropy.absolute < 0.0);
+        assert!(diff.bits_per_byte.absolute < 0.0);
+        assert!(diff.time_per_token.relative > 0.0);
+        assert!(diff.significant);
+        assert_eq!(diff.to_string(), "perplexity improved 5.0% (significant)");
+
+        let noisy = ModelStats {
+            perplexity_stderr: 20.0,
+            ..better
+        };
+        assert!(!noisy.diff(&baseline).significant);
     }
 }
@@ -0,0 +1,33 @@
+//! A small corpus and training options that train a model in well under a second, shared by
+//! the tests and the benchmarks (which include this file by path).
+
+use super::TrainingOptions;
+
+const WORDS: [&str; 11] = [
+    "the", "cat", "sat", "on", "a", "mat", "and", "dog", "ran", "far", "away",
+];
+
+/// `length` words of a fixed, repetitive sequence over a small vocabulary
+pub fn corpus(length: usize) -> String {
+    corpus_of(&WORDS, length)
+}
+
+/// Like `corpus`, drawing the words from `vocabulary`
+pub fn corpus_of(vocabulary: &[&str], length: usize) -> String {
+    (0..length)
+        .map(|i| vocabulary[(i * 7 + i / 5) % vocabulary.len()])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Options for a tiny two-dictionary ensemble on a `corpus`
+pub fn small_options() -> TrainingOptions {
+    TrainingOptions {
+        k: 64,
+        ensemble_size: 2,
+        training_chunk_size: 64,
+        train_compression_level: 3,
+        token_count: 30,
+        ..TrainingOptions::default()
+    }
+}
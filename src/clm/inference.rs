@@ -1,19 +1,161 @@
 use std::collections::HashMap;
+use std::str::FromStr;
 
 use rand::distr::{weighted::WeightedIndex, Distribution};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
 use super::tokenizer::{Token, Tokenizer};
+use super::training_options::TrainingOptions;
 
+/// A decoding strategy together with its parameter, parsed from strings like `top-k:5`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Sampler {
+    /// Always pick the most likely token
+    #[default]
+    Argmax,
+    /// Sample from the top k tokens weighted by their likelihood
+    TopK(usize),
+    /// Sample uniformly from the top k tokens
+    TopKUnweighted(usize),
+    /// Sample from the smallest set of tokens whose likelihoods sum to p
+    TopP(f32),
+    /// Sample from the whole distribution sharpened or flattened by a temperature
+    Temperature(f32),
+    /// Sample from the tokens at least p times as likely as the most likely one
+    MinP(f32),
+}
+
+impl Sampler {
+    /// Picks the next token from the distribution
+    pub fn sample<R: Rng + ?Sized>(&self, distribution: &HashMap<Token, f32>, rng: &mut R) -> Token {
+        match *self {
+            Sampler::Argmax => decode_top_k_unweighted_with_rng(distribution, 1, rng),
+            Sampler::TopK(k) => decode_top_k_with_rng(distribution, k, rng),
+            Sampler::TopKUnweighted(k) => decode_top_k_unweighted_with_rng(distribution, k, rng),
+            Sampler::TopP(p) => decode_top_p_with_rng(distribution, p, rng),
+            Sampler::Temperature(temperature) => decode_temperature_with_rng(distribution, temperature, rng),
+            Sampler::MinP(p) => decode_min_p_with_rng(distribution, p, rng),
+        }
+    }
+
+    /// Rejects parameters that select no token or make the weights meaningless
+    fn validate(self) -> Result<Self, String> {
+        let valid = match self {
+            Sampler::Argmax => true,
+            Sampler::TopK(k) | Sampler::TopKUnweighted(k) => k >= 1,
+            Sampler::TopP(p) | Sampler::MinP(p) => p > 0.0 && p <= 1.0,
+            Sampler::Temperature(temperature) => temperature >= 0.0,
+        };
+        if valid {
+            Ok(self)
+        } else {
+            Err(format!("invalid sampler {}: k must be at least 1, p in (0, 1] and the temperature at least 0", self))
+        }
+    }
+}
+
+impl FromStr for Sampler {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, parameter) = match s.split_once(':') {
+            Some((name, parameter)) => (name, Some(parameter)),
+            None => (s, None),
+        };
+        let sampler = match name.to_lowercase().replace(['-', '_'], "").as_str() {
+            "argmax" | "greedy" => Sampler::Argmax,
+            "topk" => Sampler::TopK(parse_parameter(s, parameter)?),
+            "topkunweighted" => Sampler::TopKUnweighted(parse_parameter(s, parameter)?),
+            "topp" => Sampler::TopP(parse_parameter(s, parameter)?),
+            "temperature" => Sampler::Temperature(parse_parameter(s, parameter)?),
+            "minp" => Sampler::MinP(parse_parameter(s, parameter)?),
+            _ => return Err(format!("unknown sampler: {}", s)),
+        };
+        sampler.validate()
+    }
+}
+
+fn parse_parameter<T: FromStr>(sampler: &str, parameter: Option<&str>) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    parameter
+        .ok_or_else(|| format!("sampler {} needs a parameter, e.g. {}:5", sampler, sampler))?
+        .parse()
+        .map_err(|err| format!("invalid parameter in {}: {}", sampler, err))
+}
+
+impl std::fmt::Display for Sampler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sampler::Argmax => write!(f, "argmax"),
+            Sampler::TopK(k) => write!(f, "top-k:{}", k),
+            Sampler::TopKUnweighted(k) => write!(f, "top-k-unweighted:{}", k),
+            Sampler::TopP(p) => write!(f, "top-p:{}", p),
+            Sampler::Temperature(temperature) => write!(f, "temperature:{}", temperature),
+            Sampler::MinP(p) => write!(f, "min-p:{}", p),
+        }
+    }
+}
+
+/// Stored as its string form, so saved runs read like the CLI's `--sampler`
+impl Serialize for Sampler {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Sampler {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Sampling parameters that override a model's stored defaults
+#[derive(Debug, Clone, Default)]
+pub struct SamplingOverrides {
+    pub sampler: Option<Sampler>,
+    pub seed: Option<u64>,
+}
+
+/// The sampling parameters used during generation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SamplingConfig {
+    pub sampler: Sampler,
+    pub seed: Option<u64>,
+}
+
+impl SamplingConfig {
+    /// Uses the defaults stored with the model unless they are overridden
+    pub fn resolve(options: &TrainingOptions, overrides: &SamplingOverrides) -> Self {
+        SamplingConfig {
+            sampler: overrides.sampler.unwrap_or(options.default_sampler),
+            seed: overrides.seed.or(options.default_seed),
+        }
+    }
+
+    /// Picks the next token from the distribution using the configured sampler
+    pub fn sample<R: Rng + ?Sized>(&self, distribution: &HashMap<Token, f32>, rng: &mut R) -> Token {
+        self.sampler.sample(distribution, rng)
+    }
+}
+
+/// Sorts the distribution by descending likelihood, breaking ties by token so the order is stable
+fn sorted_distribution(distribution: &HashMap<Token, f32>) -> Vec<(&Token, &f32)> {
+    let mut sorted_distribution: Vec<_> = distribution.iter().collect();
+    sorted_distribution.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap().then_with(|| a.0.cmp(b.0)));
+    sorted_distribution
+}
 
 pub fn print_distribution(
     tokenizer: &Tokenizer,
     distribution: &HashMap<Token, f32>,
     k: usize,
 ) {
-    let mut sorted_distribution: Vec<_> = distribution.iter().collect();
-    sorted_distribution.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap());
+    let sorted_distribution = sorted_distribution(distribution);
 
-    let reverse_tokens = &tokenizer.build_reverse_map();
+    let reverse_tokens = tokenizer.reverse_map();
 
     for (token, likelihood) in sorted_distribution.iter().take(k) {
         let token_str = reverse_tokens.get(*token).unwrap();
@@ -21,34 +163,108 @@ pub fn print_distribution(
     }
 }
 
+/// A token and its likelihood in a serialized inference step
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenProb {
+    pub token: String,
+    pub prob: f32,
+}
+
+/// One generation step as emitted by `--json-steps`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StepRecord {
+    pub step: usize,
+    pub top_tokens: Vec<TokenProb>,
+    pub chosen: String,
+}
+
+impl StepRecord {
+    /// Records the k most likely tokens of the distribution, in the order `print_distribution` prints them
+    pub fn new(
+        step: usize,
+        reverse_tokens: &HashMap<Token, String>,
+        distribution: &HashMap<Token, f32>,
+        k: usize,
+        chosen: &Token,
+    ) -> Self {
+        let token_str = |token: &Token| {
+            reverse_tokens
+                .get(token)
+                .cloned()
+                .unwrap_or_else(|| String::from_utf8_lossy(token).into_owned())
+        };
+        StepRecord {
+            step,
+            top_tokens: sorted_distribution(distribution)
+                .into_iter()
+                .take(k)
+                .map(|(token, prob)| TokenProb {
+                    token: token_str(token),
+                    prob: *prob,
+                })
+                .collect(),
+            chosen: token_str(chosen),
+        }
+    }
+}
+
 pub fn decode_top_k(distribution: &HashMap<Token, f32>, k: usize) -> Token {
-    let mut sorted_distribution: Vec<_> = distribution.iter().collect();
-    sorted_distribution.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap());
+    decode_top_k_with_rng(distribution, k, &mut rand::rng())
+}
+
+pub fn decode_top_k_with_rng<R: Rng + ?Sized>(distribution: &HashMap<Token, f32>, k: usize, rng: &mut R) -> Token {
+    let sorted_distribution = sorted_distribution(distribution);
     let top_k = sorted_distribution.iter().take(k).map(|(k, _)| *k).collect::<Vec<_>>();
 
     // Sample from the top k tokens
-    let mut rng = rand::rng();
     let sampler = WeightedIndex::new(top_k.iter().map(|token| distribution[*token])).unwrap();
-    let sampled_index = sampler.sample(&mut rng);
+    let sampled_index = sampler.sample(rng);
 
     top_k[sampled_index].clone()
 }
+
 pub fn decode_top_k_unweighted(distribution: &HashMap<Token, f32>, k: usize) -> Token {
-    let mut sorted_distribution: Vec<_> = distribution.iter().collect();
-    sorted_distribution.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap());
+    decode_top_k_unweighted_with_rng(distribution, k, &mut rand::rng())
+}
+
+pub fn decode_top_k_unweighted_with_rng<R: Rng + ?Sized>(distribution: &HashMap<Token, f32>, k: usize, rng: &mut R) -> Token {
+    let sorted_distribution = sorted_distribution(distribution);
     let top_k = sorted_distribution.iter().take(k).map(|(k, _)| *k).collect::<Vec<_>>();
 
     // Sample from the top k tokens
-    let mut rng = rand::rng();
     let sampler = WeightedIndex::new(top_k.iter().map(|_| 1.0)).unwrap();
-    let sampled_index = sampler.sample(&mut rng);
+    let sampled_index = sampler.sample(rng);
 
     top_k[sampled_index].clone()
 }
 
+pub fn decode_top_k_tempered(distribution: &HashMap<Token, f32>, k: usize, temperature: f32) -> Token {
+    decode_top_k_tempered_with_rng(distribution, k, temperature, &mut rand::rng())
+}
+
+/// Samples from the top k tokens after re-softmaxing their log-likelihoods with a temperature.
+/// A temperature near 0 approaches argmax, a large one approaches `decode_top_k_unweighted`.
+pub fn decode_top_k_tempered_with_rng<R: Rng + ?Sized>(distribution: &HashMap<Token, f32>, k: usize, temperature: f32, rng: &mut R) -> Token {
+    let sorted_distribution = sorted_distribution(distribution);
+    let top_k = sorted_distribution.iter().take(k).collect::<Vec<_>>();
+
+    // Relative to the most likely token, so a small temperature can't overflow
+    let max_log_likelihood = (*top_k[0].1 as f64).ln();
+    let weights = top_k
+        .iter()
+        .map(|(_, probability)| (((**probability as f64).ln() - max_log_likelihood) / temperature as f64).exp());
+    let sampler = WeightedIndex::new(weights).unwrap();
+    let sampled_index = sampler.sample(rng);
+
+    top_k[sampled_index].0.clone()
+}
+
 pub fn decode_top_p(distribution: &HashMap<Token, f32>, p: f32) -> Token {
-    let mut sorted_distribution: Vec<_> = distribution.iter().collect();
-    sorted_distribution.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap());
+    decode_top_p_with_rng(distribution, p, &mut rand::rng())
+}
+
+pub fn decode_top_p_with_rng<R: Rng + ?Sized>(distribution: &HashMap<Token, f32>, p: f32, rng: &mut R) -> Token {
+    let sorted_distribution = sorted_distribution(distribution);
 
     let mut cumulative_probability = 0.0;
     let mut selected_tokens = Vec::new();
@@ -62,9 +278,191 @@ pub fn decode_top_p(distribution: &HashMap<Token, f32>, p: f32) -> Token {
     }
 
     // Sample from the selected tokens
-    let mut rng = rand::rng();
     let sampler = WeightedIndex::new(selected_tokens.iter().map(|token| distribution[*token])).unwrap();
-    let sampled_index = sampler.sample(&mut rng);
+    let sampled_index = sampler.sample(rng);
+
+    selected_tokens[sampled_index].clone()
+}
+
+pub fn decode_min_p_with_rng<R: Rng + ?Sized>(distribution: &HashMap<Token, f32>, p: f32, rng: &mut R) -> Token {
+    let sorted_distribution = sorted_distribution(distribution);
+    let threshold = sorted_distribution.first().map_or(0.0, |(_, max)| **max) * p;
+
+    // Keep the tokens that are at least p times as likely as the best one
+    let selected_tokens = sorted_distribution
+        .iter()
+        .take_while(|(_, probability)| **probability >= threshold)
+        .map(|(token, _)| *token)
+        .collect::<Vec<_>>();
+    let sampler = WeightedIndex::new(selected_tokens.iter().map(|token| distribution[*token])).unwrap();
+    let sampled_index = sampler.sample(rng);
 
     selected_tokens[sampled_index].clone()
-}
\ No newline at end of file
+}
+
+/// Samples from the whole distribution re-softmaxed with a temperature. A temperature of
+/// at most 0 picks the most likely token, like the limit of ever smaller temperatures.
+pub fn decode_temperature_with_rng<R: Rng + ?Sized>(distribution: &HashMap<Token, f32>, temperature: f32, rng: &mut R) -> Token {
+    let sorted_distribution = sorted_distribution(distribution);
+    if temperature <= 0.0 {
+        return sorted_distribution[0].0.clone();
+    }
+
+    // Raising the likelihoods to 1/T is a softmax over the log-likelihoods with temperature T,
+    // relative to the most likely token so the weights can neither overflow nor all underflow
+    let max_log_likelihood = (*sorted_distribution[0].1 as f64).ln();
+    let weights = sorted_distribution
+        .iter()
+        .map(|(_, probability)| (((**probability as f64).ln() - max_log_likelihood) / temperature as f64).exp());
+    let sampler = WeightedIndex::new(weights).unwrap();
+    let sampled_index = sampler.sample(rng);
+
+    sorted_distribution[sampled_index].0.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clm::SavedRun;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_loaded_model_default_sampler_is_applied() {
+        let options = TrainingOptions {
+            default_sampler: Sampler::TopK(3),
+            default_seed: Some(7),
+            ..TrainingOptions::default()
+        };
+        let saved_run = SavedRun {
+            dicts: String::from("[]"),
+            tokenizer: Tokenizer::default(),
+            training_options: options,
+            unigram_prior: Vec::new(),
+        };
+        let loaded: SavedRun = serde_json::from_str(&serde_json::to_string(&saved_run).unwrap()).unwrap();
+
+        let config = SamplingConfig::resolve(&loaded.training_options, &SamplingOverrides::default());
+        assert_eq!(config.sampler, Sampler::TopK(3));
+        assert_eq!(config.seed, Some(7));
+
+        let overrides = SamplingOverrides {
+            sampler: Some("min-p:0.1".parse().unwrap()),
+            ..SamplingOverrides::default()
+        };
+        let config = SamplingConfig::resolve(&loaded.training_options, &overrides);
+        assert_eq!(config.sampler, Sampler::MinP(0.1));
+        assert_eq!(config.seed, Some(7));
+    }
+
+    #[test]
+    fn test_seeded_sampling_is_reproducible() {
+        let distribution: HashMap<Token, f32> =
+            (0..10u8).map(|i| (vec![i], (i + 1) as f32 / 55.0)).collect();
+        let config = SamplingConfig {
+            sampler: Sampler::Temperature(1.5),
+            seed: Some(1),
+        };
+        let sample = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20).map(|_| config.sample(&distribution, &mut rng)).collect::<Vec<_>>()
+        };
+        assert_eq!(sample(1), sample(1));
+    }
+
+    #[test]
+    fn test_step_records_serialize_as_json_lines() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("ab ab ab ba", 10);
+        let reverse_tokens = tokenizer.reverse_map();
+        let tokens = tokenizer.get_tokens();
+        let distribution: HashMap<Token, f32> = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, token)| (token.clone(), (i + 1) as f32))
+            .collect();
+
+        let lines = (0..2)
+            .map(|step| {
+                let chosen = &tokens[step];
+                serde_json::to_string(&StepRecord::new(step, reverse_tokens, &distribution, 2, chosen)).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        for (step, line) in lines.iter().enumerate() {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["step"], step);
+            assert_eq!(value["chosen"], reverse_tokens[&tokens[step]].as_str());
+            let top_tokens = value["top_tokens"].as_array().unwrap();
+            assert_eq!(top_tokens.len(), 2);
+            assert_eq!(top_tokens[0]["token"], reverse_tokens[tokens.last().unwrap()].as_str());
+            assert!(top_tokens[0]["prob"].as_f64().unwrap() >= top_tokens[1]["prob"].as_f64().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_sampler_round_trips_and_samples() {
+        let distribution: HashMap<Token, f32> =
+            HashMap::from([(vec![0], 0.6), (vec![1], 0.3), (vec![2], 0.1)]);
+        let samplers = [
+            Sampler::Argmax,
+            Sampler::TopK(2),
+            Sampler::TopKUnweighted(2),
+            Sampler::TopP(0.5),
+            Sampler::Temperature(0.7),
+            Sampler::MinP(0.4),
+        ];
+        let mut rng = StdRng::seed_from_u64(3);
+        for sampler in samplers {
+            assert_eq!(sampler.to_string().parse::<Sampler>(), Ok(sampler));
+            for _ in 0..20 {
+                let token = sampler.sample(&distribution, &mut rng);
+                assert!(distribution.contains_key(&token));
+                match sampler {
+                    Sampler::Argmax | Sampler::TopP(_) => assert_eq!(token, vec![0]),
+                    Sampler::TopK(_) | Sampler::TopKUnweighted(_) | Sampler::MinP(_) => assert_ne!(token, vec![2]),
+                    Sampler::Temperature(_) => {}
+                }
+            }
+        }
+        assert!("top-k".parse::<Sampler>().is_err());
+        assert!("top-p:abc".parse::<Sampler>().is_err());
+        // Parameters that would select no token or give NaN weights
+        for invalid in ["top-k:0", "top-k-unweighted:0", "top-p:0", "min-p:1.5", "min-p:-0.1", "temperature:-1", "temperature:NaN"] {
+            assert!(invalid.parse::<Sampler>().is_err(), "{}", invalid);
+        }
+        assert_eq!("temperature:0".parse::<Sampler>(), Ok(Sampler::Temperature(0.0)));
+        assert_eq!("min-p:1".parse::<Sampler>(), Ok(Sampler::MinP(1.0)));
+    }
+
+    #[test]
+    fn test_tiny_temperature_picks_most_likely_token() {
+        let distribution: HashMap<Token, f32> =
+            HashMap::from([(vec![0], 0.5), (vec![1], 0.3), (vec![2], 0.15), (vec![3], 0.05)]);
+        let mut rng = StdRng::seed_from_u64(9);
+        for temperature in [0.0, 1e-6] {
+            for _ in 0..100 {
+                assert_eq!(decode_temperature_with_rng(&distribution, temperature, &mut rng), vec![0]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_top_k_tempered_interpolates() {
+        let distribution: HashMap<Token, f32> =
+            HashMap::from([(vec![0], 0.5), (vec![1], 0.3), (vec![2], 0.15), (vec![3], 0.05)]);
+        let mut rng = StdRng::seed_from_u64(5);
+        let counts = |temperature, rng: &mut StdRng| {
+            let mut counts = [0i32; 4];
+            for _ in 0..3000 {
+                counts[decode_top_k_tempered_with_rng(&distribution, 3, temperature, rng)[0] as usize] += 1;
+            }
+            counts
+        };
+
+        assert_eq!(counts(0.01, &mut rng), [3000, 0, 0, 0]);
+        let hot = counts(1000.0, &mut rng);
+        assert_eq!(hot[3], 0);
+        assert!(hot[..3].iter().all(|&count| (count - 1000).abs() < 150), "{:?}", hot);
+    }
+}
@@ -1,12 +1,25 @@
-use crate::clm::clm_model::ClmModel;
-use crate::clm::tokenizer::Tokenizer;
-use crate::clm::training_options::TrainingOptions;
+use crate::clm::clm_model::{ClmModel, Model};
+use crate::clm::error::ClmError;
+use crate::clm::tokenizer::{CodeLayout, Token, Tokenizer};
+use crate::clm::training_options::{TrainingOptions, Utf8Handling};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::min;
+use thiserror::Error;
 
+pub mod bench;
+pub mod caching_model;
 pub mod clm_model;
+pub mod conversation;
+pub mod error;
 pub mod evaluate;
+#[cfg(test)]
+mod fixtures;
 pub mod inference;
 pub mod ngram_model;
+pub mod progress;
+pub mod self_test;
+pub mod token_cache;
 pub mod tokenizer;
 pub mod trainer;
 pub mod training_options;
@@ -17,34 +30,596 @@ pub struct SavedRun {
     pub dicts: String,
     pub tokenizer: Tokenizer,
     pub training_options: TrainingOptions,
+    /// Token frequencies of the training data, see `RegularizationMode::UnigramPrior`
+    #[serde(default)]
+    pub unigram_prior: Vec<(Token, f64)>,
 }
 
-pub fn save_run(base_path: &str, model: &ClmModel, tokenizer: Tokenizer) {
+/// Describes why a saved run is internally inconsistent
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ValidationError {
+    /// The dictionaries could not be parsed
+    #[error("invalid dictionaries: {0}")]
+    InvalidDictionaries(String),
+    /// The number of stored dictionaries doesn't match `ensemble_size`
+    #[error("expected {expected} dictionaries (ensemble_size) but found {found}")]
+    DictionaryCount { expected: usize, found: usize },
+    /// The dictionary at the given index is empty
+    #[error("dictionary {0} is empty")]
+    EmptyDictionary(usize),
+    /// The tokenizer's token size doesn't match `token_byte_size`
+    #[error("expected tokens of {expected} bytes (token_byte_size) but found {found}")]
+    TokenByteSize { expected: usize, found: usize },
+    /// `inference_basis` is at most 1, which flattens or inverts every prediction
+    #[error("inference_basis must be greater than 1 but is {0}")]
+    InferenceBasis(f64),
+    /// `prior_weight` is outside `0..=1`, which makes the blended likelihoods negative or NaN
+    #[error("prior_weight must be between 0 and 1 but is {0}")]
+    PriorWeight(f64),
+}
+
+impl SavedRun {
+    /// Checks that the stored dictionaries and tokenizer agree with the training options
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        self.training_options.validate_inference()?;
+        let dictionaries: Vec<Vec<u8>> = serde_json::from_str(&self.dicts)
+            .map_err(|err| ValidationError::InvalidDictionaries(err.to_string()))?;
+        if dictionaries.len() != self.training_options.ensemble_size {
+            return Err(ValidationError::DictionaryCount {
+                expected: self.training_options.ensemble_size,
+                found: dictionaries.len(),
+            });
+        }
+        if let Some(index) = dictionaries.iter().position(|dict| dict.is_empty()) {
+            return Err(ValidationError::EmptyDictionary(index));
+        }
+
+        let expected = self.training_options.token_byte_size;
+        // Variable-width codes only share the base size, not the length of each code
+        let token_sizes: Vec<usize> = match self.tokenizer.code_layout {
+            CodeLayout::Fixed | CodeLayout::Structured => {
+                self.tokenizer.tokens().values().map(|code| code.len()).collect()
+            }
+            CodeLayout::VariableWidth => Vec::new(),
+        };
+        for found in std::iter::once(self.tokenizer.token_byte_size).chain(token_sizes) {
+            if found != expected {
+                return Err(ValidationError::TokenByteSize { expected, found });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Trains a tokenizer and a model on the given text, like the `train` command does for
+/// the training files. Useful to build small models for tests and benchmarks.
+pub fn train_from_text(text: &str, options: TrainingOptions) -> Result<(ClmModel, Tokenizer), ClmError> {
+    let mut tokenizer = Tokenizer::for_training(&options);
+    tokenizer.train(&tokenizer_training_text(text, &options), options.token_count);
+    let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+    let mut model = ClmModel::train(tokens, options)?;
+    model.set_token_lengths(&tokenizer);
+    Ok((model, tokenizer))
+}
+
+/// Basic statistics of a corpus, to help choose `token_count` and `token_byte_size`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorpusStats {
+    /// Characters after normalization
+    pub chars: usize,
+    pub unique_chars: usize,
+    /// The most frequent normalized characters with their counts
+    pub top_chars: Vec<(char, usize)>,
+    /// Vocabulary size of the tokenizer used for the estimate
+    pub vocab_size: usize,
+    /// Tokens the corpus would be encoded into, extrapolated from the tokenizer's training sample
+    pub estimated_tokens: usize,
+}
+
+/// Number of most frequent characters reported in `CorpusStats::top_chars`
+const CORPUS_STATS_TOP_CHARS: usize = 10;
+
+/// Normalizes the text and trains a tokenizer with `options.token_count` tokens on the sample
+/// `tokenizer_training_text` selects, to estimate how many tokens the text would need
+pub fn corpus_stats(text: &str, options: &TrainingOptions) -> CorpusStats {
+    let mut tokenizer = Tokenizer::for_training(options);
+
+    let mut counts: std::collections::HashMap<char, usize> = std::collections::HashMap::new();
+    let normalized = tokenizer.normalize(text);
+    for c in &normalized {
+        *counts.entry(*c).or_insert(0) += 1;
+    }
+    let mut top_chars: Vec<(char, usize)> = counts.into_iter().collect();
+    top_chars.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let unique_chars = top_chars.len();
+    top_chars.truncate(CORPUS_STATS_TOP_CHARS);
+
+    let sample = tokenizer_training_text(text, options);
+    tokenizer.train(&sample, options.token_count);
+    let chars_per_token = tokenizer.chars_per_token(&sample);
+    let estimated_tokens = if chars_per_token > 0.0 {
+        (normalized.len() as f64 / chars_per_token).round() as usize
+    } else {
+        0
+    };
+
+    CorpusStats {
+        chars: normalized.len(),
+        unique_chars,
+        top_chars,
+        vocab_size: tokenizer.tokens().len(),
+        estimated_tokens,
+    }
+}
+
+/// Number of consecutive bytes in each window sampled by `tokenizer_training_text`
+const TOKENIZER_SAMPLE_WINDOW: usize = 4096;
+
+/// Selects the text the tokenizer is trained on. With `tokenizer_train_bytes` set, that many
+/// bytes are taken in evenly spaced windows over the whole text, so the tokenizer doesn't only
+/// see its beginning.
+pub fn tokenizer_training_text<'a>(text: &'a str, options: &TrainingOptions) -> Cow<'a, str> {
+    let budget = match options.tokenizer_train_bytes {
+        Some(budget) if budget < text.len() => budget,
+        _ => return Cow::Borrowed(text),
+    };
+    let windows = budget.div_ceil(TOKENIZER_SAMPLE_WINDOW);
+    if windows * TOKENIZER_SAMPLE_WINDOW >= text.len() {
+        // Spread out, the windows would overlap and sample some bytes twice
+        return Cow::Borrowed(&text[..floor_char_boundary(text, budget)]);
+    }
+    let stride = text.len() / windows;
+    let mut sample = String::with_capacity(budget);
+    for window in 0..windows {
+        let len = min(TOKENIZER_SAMPLE_WINDOW, budget - sample.len());
+        let start = floor_char_boundary(text, window * stride);
+        let end = floor_char_boundary(text, start + len);
+        sample.push_str(&text[start..end]);
+    }
+    Cow::Owned(sample)
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    index = min(index, text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Reads and concatenates all training files in order
+pub fn read_training_text(options: &TrainingOptions) -> Result<String, ClmError> {
+    options
+        .training_sources()
+        .into_iter()
+        .map(|path| read_text_file(path, options.utf8_handling))
+        .collect()
+}
+
+/// Reads a text file, handling invalid UTF-8 as configured
+pub fn read_text_file(path: &str, utf8_handling: Utf8Handling) -> Result<String, ClmError> {
+    let bytes = std::fs::read(path)?;
+    match String::from_utf8(bytes) {
+        Ok(text) => Ok(text),
+        Err(err) => match utf8_handling {
+            Utf8Handling::Strict => Err(ClmError::Config(format!(
+                "{} is not valid UTF-8 ({}), set utf8_handling to Lossy to replace invalid bytes",
+                path,
+                err.utf8_error()
+            ))),
+            Utf8Handling::Lossy => {
+                let text = String::from_utf8_lossy(err.as_bytes()).into_owned();
+                let replaced = text.matches(char::REPLACEMENT_CHARACTER).count();
+                log::warn!("Replaced {} invalid UTF-8 sequences in {}", replaced, path);
+                Ok(text)
+            }
+        },
+    }
+}
+
+/// Format of the timestamp prefixing every saved run's file name
+const RUN_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H-%M-%S";
+
+/// Parses the timestamp prefix of a saved run's file name
+fn run_timestamp(file_name: &str) -> Option<chrono::NaiveDateTime> {
+    let prefix = file_name.get(..19)?;
+    chrono::NaiveDateTime::parse_from_str(prefix, RUN_TIMESTAMP_FORMAT).ok()
+}
+
+/// Lists a model directory, explaining what to do if it doesn't exist yet
+fn read_model_dir(base_path: &str) -> Result<std::fs::ReadDir, ClmError> {
+    std::fs::read_dir(base_path).map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => ClmError::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!(
+                "model directory {} doesn't exist, create it or train a model first",
+                base_path
+            ),
+        )),
+        _ => ClmError::Io(err),
+    })
+}
+
+/// Returns the names of all files in `base_path`, sorted
+pub fn model_files(base_path: &str) -> Result<Vec<String>, ClmError> {
+    let mut model_files = Vec::new();
+    for entry in read_model_dir(base_path)? {
+        let path = entry?.path();
+        if path.is_file() {
+            model_files.push(path.file_name().unwrap().to_string_lossy().into_owned());
+        }
+    }
+    model_files.sort();
+    Ok(model_files)
+}
+
+/// Whether `file_name` contains `model_name`, ignoring case
+fn matches_model_name(file_name: &str, model_name: &str) -> bool {
+    file_name.to_lowercase().contains(&model_name.to_lowercase())
+}
+
+/// Returns all files in `base_path` and the one matching `model_name`, ignoring case.
+/// A file named exactly `model_name`, with or without extension, wins over files that only
+/// contain it. Several files containing it without an exact match are an error.
+pub fn find_model(base_path: &str, model_name: &str) -> Result<(Vec<String>, Option<String>), ClmError> {
+    let model_files = model_files(base_path)?;
+    let exact = model_files.iter().find(|file_name| {
+        let stem = std::path::Path::new(file_name.as_str()).file_stem().unwrap_or_default().to_string_lossy();
+        file_name.eq_ignore_ascii_case(model_name) || stem.eq_ignore_ascii_case(model_name)
+    });
+    if let Some(exact) = exact {
+        let chosen_model = Some(exact.clone());
+        return Ok((model_files, chosen_model));
+    }
+
+    let candidates: Vec<String> = model_files
+        .iter()
+        .filter(|file_name| matches_model_name(file_name, model_name))
+        .cloned()
+        .collect();
+    match candidates.len() {
+        0 => Ok((model_files, None)),
+        1 => Ok((model_files, candidates.into_iter().next())),
+        _ => Err(ClmError::AmbiguousModel {
+            name: model_name.to_string(),
+            candidates,
+        }),
+    }
+}
+
+/// Finds the newest saved run in `base_path` whose file name contains `model_name`, ignoring
+/// case and ordering by the timestamp prefix rather than by directory order
+pub fn latest_run(base_path: &str, model_name: &str) -> Result<Option<String>, ClmError> {
+    let mut latest: Option<(chrono::NaiveDateTime, String)> = None;
+    for entry in read_model_dir(base_path)? {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if !path.is_file() || !matches_model_name(file_name, model_name) {
+            continue;
+        }
+        if let Some(timestamp) = run_timestamp(file_name) {
+            let candidate = (timestamp, file_name.to_string());
+            if latest.as_ref().is_none_or(|latest| candidate > *latest) {
+                latest = Some(candidate);
+            }
+        }
+    }
+    Ok(latest.map(|(_, file_name)| file_name))
+}
+
+/// Loads the newest saved run in `base_path` whose file name contains `model_name`
+pub fn load_latest(base_path: &str, model_name: &str) -> Result<(ClmModel, Tokenizer), ClmError> {
+    let file_name = latest_run(base_path, model_name)?
+        .ok_or_else(|| ClmError::Config(format!("no saved run matching {} in {}", model_name, base_path)))?;
+    load(&std::path::Path::new(base_path).join(file_name).to_string_lossy())
+}
+
+/// Saves the model with its tokenizer and options to a timestamped file in `base_path`
+/// and returns the path of that file
+pub fn save_run(base_path: &str, model: &ClmModel, mut tokenizer: Tokenizer) -> Result<String, ClmError> {
     // Save the model, tokenizer, and training options to the specified path
     let model_id = model
         .options
         .clone()
         .model_id
         .unwrap_or("without-id".to_string());
-    println!("Saving model {} to {}", model_id, base_path);
+    log::info!("Saving model {} to {}", model_id, base_path);
+    if model.options.serialize_trie {
+        tokenizer.precompute_trie();
+    }
     let saved_run = SavedRun {
         dicts: model.to_save_string(),
         tokenizer,
         training_options: model.options.clone(),
+        unigram_prior: model.unigram_prior().clone().into_iter().collect(),
     };
-    let serialized = serde_json::to_string(&saved_run).unwrap();
+    let serialized = serde_json::to_string(&saved_run)?;
     // write to file
-    let timestamp = chrono::Utc::now().format("%Y-%m-%dT%H-%M-%S").to_string();
+    let timestamp = chrono::Utc::now().format(RUN_TIMESTAMP_FORMAT).to_string();
     let file_path = format!("{}/{}-{}.json", base_path, timestamp, model_id);
-    std::fs::write(file_path, serialized).expect("Unable to write the file");
+    std::fs::write(&file_path, serialized)?;
+    Ok(file_path)
 }
 
-pub fn load(path: &str) -> (ClmModel, Tokenizer) {
+pub fn load(path: &str) -> Result<(ClmModel, Tokenizer), ClmError> {
     // Load the model, tokenizer, and training options from the specified path
-    let contents = std::fs::read_to_string(path).expect("Unable to read file");
-    let saved_run: SavedRun = serde_json::from_str(&contents).unwrap();
-    (
-        ClmModel::load_from_string(saved_run.dicts, saved_run.training_options.clone()),
-        saved_run.tokenizer,
-    )
+    let contents = std::fs::read_to_string(path)?;
+    let saved_run: SavedRun = serde_json::from_str(&contents)?;
+    saved_run.validate()?;
+    let mut model = ClmModel::load_from_string(saved_run.dicts, saved_run.training_options.clone())?;
+    model.set_unigram_prior(saved_run.unigram_prior.into_iter().collect());
+    model.set_token_lengths(&saved_run.tokenizer);
+    log::info!(
+        "Loaded model using {}",
+        human_bytes::human_bytes(model.memory_footprint() as f64)
+    );
+    // Build the reverse map now, so decoding right after loading doesn't pay for it
+    saved_run.tokenizer.reverse_map();
+    Ok((model, saved_run.tokenizer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved_run(dicts: &str, ensemble_size: usize) -> SavedRun {
+        let mut tokenizer = Tokenizer::new(5);
+        tokenizer.train("abc", 3);
+        SavedRun {
+            dicts: dicts.to_string(),
+            tokenizer,
+            training_options: TrainingOptions {
+                ensemble_size,
+                ..TrainingOptions::default()
+            },
+            unigram_prior: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_training_over_multiple_files() {
+        let dir = std::env::temp_dir().join(format!("chatclm-shards-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let shards = ["the cat sat on the mat. ", "the dog ran far away, the end!"];
+        let paths: Vec<String> = shards
+            .iter()
+            .enumerate()
+            .map(|(i, shard)| {
+                let path = dir.join(format!("shard{}.txt", i));
+                std::fs::write(&path, shard).unwrap();
+                path.to_str().unwrap().to_string()
+            })
+            .collect();
+
+        let options = TrainingOptions {
+            training_files: paths,
+            ..TrainingOptions::default()
+        };
+        let text = read_training_text(&options).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(text, shards.concat());
+
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(&text, 20);
+        assert_eq!(
+            tokenizer.encode_fast_opt(text, true),
+            tokenizer.encode_fast_opt(shards.concat(), true)
+        );
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let missing = load("/nonexistent/chatclm-model.json");
+        assert!(matches!(missing, Err(ClmError::Io(_))));
+
+        let path = std::env::temp_dir().join(format!("chatclm-invalid-{}.json", std::process::id()));
+        std::fs::write(&path, "{ not json").unwrap();
+        let invalid = load(path.to_str().unwrap());
+        assert!(matches!(invalid, Err(ClmError::Serde(_))));
+
+        let inconsistent = serde_json::to_string(&saved_run("[[1, 2]]", 3)).unwrap();
+        std::fs::write(&path, inconsistent).unwrap();
+        let inconsistent = load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            inconsistent,
+            Err(ClmError::Validation(ValidationError::DictionaryCount { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_tokenizer_training_text_respects_budget() {
+        let text = "abcdefghij".repeat(10_000);
+        let options = TrainingOptions {
+            tokenizer_train_bytes: Some(20_000),
+            ..TrainingOptions::default()
+        };
+        let sample = tokenizer_training_text(&text, &options);
+        assert_eq!(sample.len(), 20_000);
+
+        let default = tokenizer_training_text(&text, &TrainingOptions::default());
+        assert_eq!(default.len(), 50_000);
+
+        // A budget close to the text's length takes its start instead of overlapping windows
+        let short_text: String = (0..5000).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+        let nearly_whole = tokenizer_training_text(&short_text, &TrainingOptions {
+            tokenizer_train_bytes: Some(4999),
+            ..TrainingOptions::default()
+        });
+        assert_eq!(nearly_whole, &short_text[..4999]);
+
+        let whole = tokenizer_training_text(&text, &TrainingOptions {
+            tokenizer_train_bytes: None,
+            ..TrainingOptions::default()
+        });
+        assert_eq!(whole.len(), text.len());
+
+        // Old configs without the field keep the previous cap, an explicit null uses everything
+        let mut json = serde_json::to_value(TrainingOptions::default()).unwrap();
+        json.as_object_mut().unwrap().remove("tokenizer_train_bytes");
+        let old: TrainingOptions = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(old.tokenizer_train_bytes, Some(50_000));
+        json["tokenizer_train_bytes"] = serde_json::Value::Null;
+        let all: TrainingOptions = serde_json::from_value(json).unwrap();
+        assert_eq!(all.tokenizer_train_bytes, None);
+    }
+
+    #[test]
+    fn test_latest_run_is_newest() {
+        let dir = std::env::temp_dir().join(format!("chatclm-latest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for file_name in [
+            "2025-06-09T11-44-46-enwik9.json",
+            "2025-07-01T08-00-00-enwik9.json",
+            "2025-06-30T23-59-59-enwik9.json",
+            "2025-08-01T00-00-00-other.json",
+            "notes-enwik9.txt",
+        ] {
+            std::fs::write(dir.join(file_name), "").unwrap();
+        }
+
+        let base_path = dir.to_str().unwrap();
+        let latest = latest_run(base_path, "enwik9").unwrap();
+        assert_eq!(latest.as_deref(), Some("2025-07-01T08-00-00-enwik9.json"));
+        assert_eq!(latest_run(base_path, "missing").unwrap(), None);
+        assert!(matches!(load_latest(base_path, "missing"), Err(ClmError::Config(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_run_returns_loadable_path() {
+        let dir = std::env::temp_dir().join(format!("chatclm-save-run-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = fixtures::corpus(3000);
+        let (model, tokenizer) = train_from_text(&text, fixtures::small_options()).unwrap();
+
+        let path = save_run(dir.to_str().unwrap(), &model, tokenizer.clone()).unwrap();
+        assert!(std::path::Path::new(&path).is_file());
+        let (loaded, loaded_tokenizer) = load(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.options.ensemble_size, model.options.ensemble_size);
+        assert_eq!(loaded_tokenizer.get_tokens(), tokenizer.get_tokens());
+        // The reverse map is ready without building it first
+        assert!(loaded_tokenizer.has_reverse_map());
+        assert_eq!(loaded_tokenizer.reverse_map(), tokenizer.reverse_map());
+    }
+
+    #[test]
+    fn test_structured_codes_give_valid_distributions() {
+        let words = ["sing", "song", "sang", "king", "ring", "rang", "the", "a"];
+        let text = fixtures::corpus_of(&words, 3000);
+        let options = TrainingOptions {
+            code_layout: CodeLayout::Structured,
+            ..fixtures::small_options()
+        };
+        let (model, tokenizer) = train_from_text(&text, options).unwrap();
+        assert_eq!(tokenizer.code_layout, CodeLayout::Structured);
+
+        let context = tokenizer.encode_fast_opt(text[..200].to_string(), true);
+        let likelihoods = model.compute_likelihoods(context, tokenizer.get_tokens()).unwrap();
+        crate::clm::evaluate::check_distribution(&likelihoods).unwrap();
+    }
+
+    #[test]
+    fn test_find_model_disambiguation() {
+        let dir = std::env::temp_dir().join(format!("chatclm-find-model-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for file_name in ["enwik9.json", "2025-06-09T11-44-46-enwik9.json", "2025-07-01T08-00-00-Enwik9-small.json"] {
+            std::fs::write(dir.join(file_name), "").unwrap();
+        }
+        let base_path = dir.to_str().unwrap();
+
+        let (files, chosen) = find_model(base_path, "ENWIK9").unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(chosen.as_deref(), Some("enwik9.json"));
+        let (_, chosen) = find_model(base_path, "enwik9-SMALL").unwrap();
+        assert_eq!(chosen.as_deref(), Some("2025-07-01T08-00-00-Enwik9-small.json"));
+        let (_, chosen) = find_model(base_path, "wiki").unwrap();
+        assert_eq!(chosen, None);
+
+        std::fs::remove_file(dir.join("enwik9.json")).unwrap();
+        let err = find_model(base_path, "enwik9").unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        match err {
+            ClmError::AmbiguousModel { candidates, .. } => assert_eq!(candidates.len(), 2),
+            other => panic!("expected an ambiguous model error, got {}", other),
+        }
+    }
+
+    #[test]
+    fn test_missing_model_directory() {
+        let dir = std::env::temp_dir().join(format!("chatclm-no-models-{}", std::process::id()));
+        let err = find_model(dir.to_str().unwrap(), "enwik9").unwrap_err();
+        assert!(matches!(&err, ClmError::Io(io) if io.kind() == std::io::ErrorKind::NotFound));
+        assert!(err.to_string().contains("train a model first"));
+        assert!(latest_run(dir.to_str().unwrap(), "enwik9").is_err());
+    }
+
+    #[test]
+    fn test_corpus_stats() {
+        let path = std::env::temp_dir().join(format!("chatclm-corpus-stats-{}.txt", std::process::id()));
+        std::fs::write(&path, "Hello World, hello world").unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let stats = corpus_stats(&text, &TrainingOptions {
+            token_count: 12,
+            ..TrainingOptions::default()
+        });
+        // h, e, l, o, space, w, r, d and the comma
+        assert_eq!(stats.unique_chars, 9);
+        assert_eq!(stats.chars, 24);
+        assert_eq!(stats.top_chars[0], ('l', 6));
+        assert!(stats.estimated_tokens > 0 && stats.estimated_tokens < stats.chars);
+    }
+
+    #[test]
+    fn test_read_text_file_with_invalid_utf8() {
+        let path = std::env::temp_dir().join(format!("chatclm-invalid-utf8-{}.txt", std::process::id()));
+        std::fs::write(&path, b"valid \xff\xfe text").unwrap();
+        let path_str = path.to_str().unwrap();
+
+        let lossy = read_text_file(path_str, Utf8Handling::Lossy);
+        let strict = read_text_file(path_str, Utf8Handling::Strict);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(lossy.unwrap(), "valid \u{fffd}\u{fffd} text");
+        assert!(matches!(strict, Err(ClmError::Config(message)) if message.contains("not valid UTF-8")));
+    }
+
+    #[test]
+    fn test_validate_saved_run() {
+        assert_eq!(saved_run("[[1, 2], [3]]", 2).validate(), Ok(()));
+        assert_eq!(
+            saved_run("[[1, 2], [3]]", 15).validate(),
+            Err(ValidationError::DictionaryCount { expected: 15, found: 2 })
+        );
+        assert_eq!(
+            saved_run("[[1, 2], []]", 2).validate(),
+            Err(ValidationError::EmptyDictionary(1))
+        );
+
+        let mut run = saved_run("[[1]]", 1);
+        run.training_options.token_byte_size = 4;
+        assert_eq!(
+            run.validate(),
+            Err(ValidationError::TokenByteSize { expected: 4, found: 5 })
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_inference_basis_below_one() {
+        let mut run = saved_run("[[1]]", 1);
+        run.training_options.inference_basis = 0.9;
+        let path = std::env::temp_dir().join(format!("chatclm-basis-{}.json", std::process::id()));
+        std::fs::write(&path, serde_json::to_string(&run).unwrap()).unwrap();
+        let loaded = load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(loaded, Err(ClmError::Validation(ValidationError::InferenceBasis(basis))) if basis == 0.9));
+        run.training_options.inference_basis = 1.0;
+        assert_eq!(run.validate(), Err(ValidationError::InferenceBasis(1.0)));
+    }
 }
@@ -1,20 +1,37 @@
-use crate::clm::clm_model::Model;
+use crate::clm::clm_model::{blend_prior, clamp_rounding_errors, Model, MIN_EVAL_WARMUP};
+use crate::clm::error::ClmError;
 use crate::clm::tokenizer::Token;
-use crate::clm::training_options::TrainingOptions;
+use crate::clm::training_options::{FallbackKind, TrainingOptions};
 use std::collections::HashMap;
 
+/// Pseudo-count added to every token's count by `BigramModel`, so no token gets probability 0
+const BIGRAM_SMOOTHING: f32 = 60.0;
+
 /// A model that implements an n-gram approach (specifically a bigram model)
 /// to predict the next token based on the previous token
 pub struct BigramModel {
     /// A HashMap where the key is a token, and the value is another HashMap
     /// containing the count of each token that follows it
     transition_counts: HashMap<Token, HashMap<Token, usize>>,
+    /// Total occurrences of each token, used by `FallbackKind::Unigram`
+    token_counts: HashMap<Token, usize>,
+    fallback: FallbackKind,
+    /// Prior injected with `Model::with_prior`, empty if none
+    prior: HashMap<Token, f32>,
+    prior_weight: f32,
+    /// `TrainingOptions::likelihood_clamp_epsilon`
+    clamp_epsilon: f32,
 }
 
 impl Model for BigramModel {
     /// Trains a bigram model by counting token pair occurrences
-    fn train(tokens: Vec<Token>, _options: TrainingOptions) -> Self {
+    fn train(tokens: Vec<Token>, options: TrainingOptions) -> Result<Self, ClmError> {
+        options.validate_inference()?;
         let mut transition_counts: HashMap<Token, HashMap<Token, usize>> = HashMap::new();
+        let mut token_counts: HashMap<Token, usize> = HashMap::new();
+        for token in &tokens {
+            *token_counts.entry(token.clone()).or_insert(0) += 1;
+        }
 
         // Count bigram transitions
         for i in 0..tokens.len() - 1 {
@@ -24,11 +41,18 @@ impl Model for BigramModel {
             // Update the transition count for the current token to the next token
             let next_token_counts = transition_counts
                 .entry(current_token.clone())
-                .or_insert_with(HashMap::new);
+                .or_default();
             *next_token_counts.entry(next_token.clone()).or_insert(0) += 1;
         }
 
-        BigramModel { transition_counts }
+        Ok(BigramModel {
+            transition_counts,
+            token_counts,
+            fallback: options.bigram_fallback,
+            prior: HashMap::new(),
+            prior_weight: options.prior_weight as f32,
+            clamp_epsilon: options.likelihood_clamp_epsilon,
+        })
     }
 
     /// Computes the likelihood of each possible next token based on bigram probabilities
@@ -36,13 +60,14 @@ impl Model for BigramModel {
         &self,
         current_text: Vec<Token>,
         all_tokens: &[Token],
-    ) -> HashMap<Token, f32> {
+    ) -> Result<HashMap<Token, f32>, ClmError> {
         let mut likelihoods = HashMap::new();
+        if all_tokens.is_empty() {
+            return Ok(likelihoods);
+        }
 
-        // Get the last token in the current text to determine the context
-        let last_token = current_text.last().unwrap();
-        // Get the transition counts for the last token
-        let next_token_counts = self.transition_counts.get(last_token);
+        // Get the transition counts for the last token, an empty text falls back like an unseen token
+        let next_token_counts = current_text.last().and_then(|last_token| self.transition_counts.get(last_token));
 
         if let Some(next_token_counts) = next_token_counts {
             // Calculate the total count of all possible next tokens
@@ -53,7 +78,14 @@ impl Model for BigramModel {
                 let default_count = (total_count as f64 / all_tokens.len() as f64) as usize + 1;
                 let count = next_token_counts.get(token).unwrap_or(&default_count);
                 // Add smoothing
-                likelihoods.insert(token.clone(), *count as f32 + 60.0);
+                likelihoods.insert(token.clone(), *count as f32 + BIGRAM_SMOOTHING);
+            }
+        } else if self.fallback == FallbackKind::Unigram && !self.token_counts.is_empty() {
+            // If no transitions exist for the last token, back off to the token frequencies,
+            // smoothed like the transitions so tokens never seen in training keep a share
+            for token in all_tokens {
+                let count = self.token_counts.get(token).copied().unwrap_or(0);
+                likelihoods.insert(token.clone(), count as f32 + BIGRAM_SMOOTHING);
             }
         } else {
             // If no transitions exist for the last token, fall back to uniform distribution
@@ -71,7 +103,33 @@ impl Model for BigramModel {
             }
         }
 
-        likelihoods
+        blend_prior(&mut likelihoods, &self.prior, self.prior_weight, self.clamp_epsilon);
+        Ok(likelihoods)
+    }
+
+    fn memory_footprint(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + token_map_footprint(&self.transition_counts)
+            + token_map_footprint(&self.token_counts)
+            + token_map_footprint(&self.prior)
+            + self
+                .transition_counts
+                .values()
+                .map(token_map_footprint)
+                .sum::<usize>()
+    }
+
+    fn name(&self) -> String {
+        String::from("bigram")
+    }
+
+    /// Only the last token is looked at
+    fn recommended_warmup(&self) -> usize {
+        MIN_EVAL_WARMUP
+    }
+
+    fn with_prior(&mut self, prior: HashMap<Token, f32>) {
+        self.prior = prior;
     }
 }
 
@@ -82,11 +140,17 @@ pub struct UnigramModel {
     token_counts: HashMap<Token, usize>,
     /// Total number of tokens in the training set
     total_tokens: usize,
+    /// Prior injected with `Model::with_prior`, empty if none
+    prior: HashMap<Token, f32>,
+    prior_weight: f32,
+    /// `TrainingOptions::likelihood_clamp_epsilon`
+    clamp_epsilon: f32,
 }
 
 impl Model for UnigramModel {
     /// Trains a unigram model by counting token occurrences
-    fn train(tokens: Vec<Token>, _options: TrainingOptions) -> Self {
+    fn train(tokens: Vec<Token>, options: TrainingOptions) -> Result<Self, ClmError> {
+        options.validate_inference()?;
         let mut token_counts: HashMap<Token, usize> = HashMap::new();
         let total_tokens = tokens.len();
 
@@ -95,10 +159,13 @@ impl Model for UnigramModel {
             *token_counts.entry(token.clone()).or_insert(0) += 1;
         }
 
-        UnigramModel {
+        Ok(UnigramModel {
             token_counts,
             total_tokens,
-        }
+            prior: HashMap::new(),
+            prior_weight: options.prior_weight as f32,
+            clamp_epsilon: options.likelihood_clamp_epsilon,
+        })
     }
 
     /// Computes the likelihood of each possible next token based on unigram probabilities
@@ -108,14 +175,17 @@ impl Model for UnigramModel {
         &self,
         _current_text: Vec<Token>,
         all_tokens: &[Token],
-    ) -> HashMap<Token, f32> {
+    ) -> Result<HashMap<Token, f32>, ClmError> {
         let mut likelihoods = HashMap::new();
+        if all_tokens.is_empty() {
+            return Ok(likelihoods);
+        }
 
         if self.total_tokens > 0 {
             for token in all_tokens {
-                let count = self.token_counts.get(token).unwrap();
-                // Add smoothing
-                likelihoods.insert(token.clone(), *count as f32 as f32);
+                // Tokens never seen in training, e.g. from the full tokenizer vocabulary, get nothing
+                let count = self.token_counts.get(token).copied().unwrap_or(0);
+                likelihoods.insert(token.clone(), count as f32);
             }
         } else {
             // Fall back to uniform distribution
@@ -133,8 +203,167 @@ impl Model for UnigramModel {
             }
         }
 
-        likelihoods
+        blend_prior(&mut likelihoods, &self.prior, self.prior_weight, self.clamp_epsilon);
+        Ok(likelihoods)
+    }
+
+    fn memory_footprint(&self) -> usize {
+        std::mem::size_of::<Self>() + token_map_footprint(&self.token_counts) + token_map_footprint(&self.prior)
+    }
+
+    fn name(&self) -> String {
+        String::from("unigram")
+    }
+
+    /// The context isn't looked at
+    fn recommended_warmup(&self) -> usize {
+        MIN_EVAL_WARMUP
+    }
+
+    fn with_prior(&mut self, prior: HashMap<Token, f32>) {
+        self.prior = prior;
+    }
+}
+
+/// Most held-out positions scored when fitting `InterpolatedModel`'s lambda
+const FIT_VALIDATION_POSITIONS: usize = 10_000;
+
+/// A model that linearly interpolates bigram and unigram probabilities,
+/// `lambda * bigram + (1 - lambda) * unigram`
+pub struct InterpolatedModel {
+    bigram: BigramModel,
+    unigram: UnigramModel,
+    /// Weight of the bigram model, between 0 and 1
+    lambda: f64,
+    /// Prior injected with `Model::with_prior`, empty if none
+    prior: HashMap<Token, f32>,
+    prior_weight: f32,
+    /// `TrainingOptions::likelihood_clamp_epsilon`
+    clamp_epsilon: f32,
+}
+
+impl InterpolatedModel {
+    /// Weight of the bigram model
+    pub fn lambda(&self) -> f64 {
+        self.lambda
+    }
+
+    /// Picks the lambda from a grid of 0, 0.05, ..., 1 that maximizes the likelihood of `validation`.
+    /// Positions whose token isn't in `all_tokens` are skipped.
+    pub fn fit_lambda(&mut self, validation: &[Token], all_tokens: &[Token]) -> Result<f64, ClmError> {
+        // The component probabilities don't depend on lambda, so compute them once
+        let pairs: Vec<(f64, f64)> = (1..validation.len())
+            .filter(|&pos| all_tokens.contains(&validation[pos]))
+            .take(FIT_VALIDATION_POSITIONS)
+            .map(|pos| {
+                let context = vec![validation[pos - 1].clone()];
+                let bigram = self.bigram.compute_likelihoods(context.clone(), all_tokens)?;
+                let unigram = self.unigram.compute_likelihoods(context, all_tokens)?;
+                Ok((bigram[&validation[pos]] as f64, unigram[&validation[pos]] as f64))
+            })
+            .collect::<Result<_, ClmError>>()?;
+        if pairs.is_empty() {
+            return Ok(self.lambda);
+        }
+
+        let log_likelihood = |lambda: f64| -> f64 {
+            pairs
+                .iter()
+                .map(|(bigram, unigram)| (lambda * bigram + (1.0 - lambda) * unigram).ln())
+                .sum()
+        };
+        self.lambda = (0..=20)
+            .map(|step| step as f64 / 20.0)
+            .max_by(|&a, &b| log_likelihood(a).total_cmp(&log_likelihood(b)))
+            .unwrap_or(self.lambda);
+        Ok(self.lambda)
+    }
+}
+
+impl Model for InterpolatedModel {
+    /// Trains the bigram and unigram components. With `fit_interpolation_lambda` the last
+    /// tenth of the tokens is first held out to fit lambda, then both are retrained on all tokens.
+    fn train(tokens: Vec<Token>, options: TrainingOptions) -> Result<Self, ClmError> {
+        let mut lambda = options.interpolation_lambda.clamp(0.0, 1.0);
+        if options.fit_interpolation_lambda && tokens.len() >= 20 {
+            let split = tokens.len() - tokens.len() / 10;
+            let mut held_out_model = Self::train(
+                tokens[..split].to_vec(),
+                TrainingOptions {
+                    fit_interpolation_lambda: false,
+                    ..options.clone()
+                },
+            )?;
+            let mut vocabulary = tokens[..split].to_vec();
+            vocabulary.sort_unstable();
+            vocabulary.dedup();
+            lambda = held_out_model.fit_lambda(&tokens[split..], &vocabulary)?;
+            log::info!("Fitted interpolation lambda {:.2}", lambda);
+        }
+
+        Ok(InterpolatedModel {
+            bigram: BigramModel::train(tokens.clone(), options.clone())?,
+            unigram: UnigramModel::train(tokens, options.clone())?,
+            lambda,
+            prior: HashMap::new(),
+            prior_weight: options.prior_weight as f32,
+            clamp_epsilon: options.likelihood_clamp_epsilon,
+        })
+    }
+
+    fn compute_likelihoods(
+        &self,
+        current_text: Vec<Token>,
+        all_tokens: &[Token],
+    ) -> Result<HashMap<Token, f32>, ClmError> {
+        let bigram = self.bigram.compute_likelihoods(current_text.clone(), all_tokens)?;
+        let unigram = self.unigram.compute_likelihoods(current_text, all_tokens)?;
+        let lambda = self.lambda as f32;
+
+        let mut likelihoods: HashMap<Token, f32> = bigram
+            .into_iter()
+            .map(|(token, likelihood)| {
+                let unigram_likelihood = unigram.get(&token).copied().unwrap_or(0.0);
+                (token, lambda * likelihood + (1.0 - lambda) * unigram_likelihood)
+            })
+            .collect();
+
+        // Both components are normalized, this only corrects rounding
+        clamp_rounding_errors(&mut likelihoods, self.clamp_epsilon);
+        let sum: f32 = likelihoods.values().sum();
+        if sum > 0.0 {
+            likelihoods.values_mut().for_each(|likelihood| *likelihood /= sum);
+        }
+
+        blend_prior(&mut likelihoods, &self.prior, self.prior_weight, self.clamp_epsilon);
+        Ok(likelihoods)
+    }
+
+    fn memory_footprint(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.bigram.memory_footprint()
+            + self.unigram.memory_footprint()
+            + token_map_footprint(&self.prior)
+    }
+
+    fn name(&self) -> String {
+        format!("interpolated(lambda={:.2})", self.lambda)
+    }
+
+    /// Only the last token is looked at
+    fn recommended_warmup(&self) -> usize {
+        MIN_EVAL_WARMUP
     }
+
+    fn with_prior(&mut self, prior: HashMap<Token, f32>) {
+        self.prior = prior;
+    }
+}
+
+/// Estimates the heap memory of a map keyed by tokens, not counting what the values own
+fn token_map_footprint<V>(map: &HashMap<Token, V>) -> usize {
+    map.capacity() * std::mem::size_of::<(Token, V)>()
+        + map.keys().map(|token| token.capacity()).sum::<usize>()
 }
 
 #[cfg(test)]
@@ -162,7 +391,7 @@ mod tests {
         // 2 -> 4 (once)
         // 4 -> 1 (once)
 
-        let model = BigramModel::train(tokens.clone(), TrainingOptions::default());
+        let model = BigramModel::train(tokens.clone(), TrainingOptions::default()).unwrap();
 
         // Check that the model contains the correct transition counts
         assert_eq!(
@@ -194,6 +423,83 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_bigram_unseen_context_fallback() {
+        // Token 4 only ends the text, so no transitions from it are known
+        let tokens: Vec<Token> = vec![vec![1], vec![2], vec![1], vec![3], vec![1], vec![2], vec![4]];
+        // Token 5 never occurs in training
+        let all_tokens: Vec<Token> = vec![vec![1], vec![2], vec![3], vec![4], vec![5]];
+
+        let uniform = BigramModel::train(tokens.clone(), TrainingOptions::default()).unwrap();
+        let likelihoods = uniform.compute_likelihoods(vec![vec![4]], &all_tokens).unwrap();
+        assert!(likelihoods.values().all(|&likelihood| likelihood == 0.2));
+
+        let options = TrainingOptions {
+            bigram_fallback: FallbackKind::Unigram,
+            ..TrainingOptions::default()
+        };
+        let bigram = BigramModel::train(tokens, options).unwrap();
+        let likelihoods = bigram.compute_likelihoods(vec![vec![4]], &all_tokens).unwrap();
+        // The 7 training tokens plus the pseudo-count of each of the 5 tokens
+        let total = 7.0 + 5.0 * BIGRAM_SMOOTHING;
+        assert!((likelihoods[&vec![1]] - (3.0 + BIGRAM_SMOOTHING) / total).abs() < 1e-6);
+        assert!((likelihoods[&vec![5]] - BIGRAM_SMOOTHING / total).abs() < 1e-6);
+        assert!(likelihoods[&vec![1]] > likelihoods[&vec![2]] && likelihoods[&vec![2]] > likelihoods[&vec![5]]);
+        // An empty context falls back the same way
+        assert_eq!(bigram.compute_likelihoods(Vec::new(), &all_tokens).unwrap(), likelihoods);
+    }
+
+    #[test]
+    fn test_interpolated_lies_between_components() {
+        let tokens: Vec<Token> = vec![vec![1], vec![2], vec![3], vec![1], vec![2], vec![1], vec![3], vec![3]];
+        let all_tokens: Vec<Token> = vec![vec![1], vec![2], vec![3]];
+        let options = TrainingOptions {
+            interpolation_lambda: 0.3,
+            ..TrainingOptions::default()
+        };
+        let bigram = BigramModel::train(tokens.clone(), options.clone()).unwrap();
+        let unigram = UnigramModel::train(tokens.clone(), options.clone()).unwrap();
+        let interpolated = InterpolatedModel::train(tokens, options).unwrap();
+
+        let context = vec![vec![2]];
+        let bigram = bigram.compute_likelihoods(context.clone(), &all_tokens).unwrap();
+        let unigram = unigram.compute_likelihoods(context.clone(), &all_tokens).unwrap();
+        let likelihoods = interpolated.compute_likelihoods(context, &all_tokens).unwrap();
+        for token in &all_tokens {
+            let (low, high) = (bigram[token].min(unigram[token]), bigram[token].max(unigram[token]));
+            assert!(likelihoods[token] >= low - 1e-6 && likelihoods[token] <= high + 1e-6);
+            assert!((likelihoods[token] - (0.3 * bigram[token] + 0.7 * unigram[token])).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_unseen_vocabulary_token() {
+        let tokens: Vec<Token> = vec![vec![1], vec![2], vec![3], vec![1], vec![2], vec![1]];
+        let all_tokens: Vec<Token> = vec![vec![1], vec![2], vec![3], vec![4]];
+        let unigram = UnigramModel::train(tokens.clone(), TrainingOptions::default()).unwrap();
+        let likelihoods = unigram.compute_likelihoods(vec![vec![1]], &all_tokens).unwrap();
+        assert_eq!(likelihoods[&vec![4]], 0.0);
+        assert!((likelihoods[&vec![1]] - 0.5).abs() < 1e-6);
+
+        let interpolated = InterpolatedModel::train(tokens, TrainingOptions::default()).unwrap();
+        let likelihoods = interpolated.compute_likelihoods(vec![vec![1]], &all_tokens).unwrap();
+        assert_eq!(likelihoods.len(), all_tokens.len());
+        assert!((likelihoods.values().sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_fit_lambda_prefers_bigram_for_predictable_text() {
+        // The next token is fully determined by the last one, but all are equally frequent
+        let tokens: Vec<Token> = (0..300).map(|i| vec![(i % 3) as u8]).collect();
+        let options = TrainingOptions {
+            fit_interpolation_lambda: true,
+            ..TrainingOptions::default()
+        };
+        let model = InterpolatedModel::train(tokens, options).unwrap();
+        assert_eq!(model.lambda(), 1.0);
+        assert_eq!(model.name(), "interpolated(lambda=1.00)");
+    }
+
     #[test]
     fn test_unigram_model_training() {
         // Create a sequence of tokens for training
@@ -204,7 +510,7 @@ mod tests {
         // 2: 2 times
         // 3: 1 time
 
-        let model = UnigramModel::train(tokens.clone(), TrainingOptions::default());
+        let model = UnigramModel::train(tokens.clone(), TrainingOptions::default()).unwrap();
 
         // Check that the model contains the correct counts
         assert_eq!(model.token_counts.get(&vec![1]).unwrap(), &3);
@@ -218,18 +524,95 @@ mod tests {
         // Create a sequence of tokens for training
         let tokens: Vec<Token> = vec![vec![1], vec![2], vec![3], vec![1], vec![2], vec![1]];
 
-        let model = UnigramModel::train(tokens.clone(), TrainingOptions::default());
+        let model = UnigramModel::train(tokens.clone(), TrainingOptions::default()).unwrap();
 
         // Get predictions - context should be ignored
         let all_possible_tokens = vec![vec![1], vec![2], vec![3]];
-        let likelihoods = model.compute_likelihoods(vec![vec![100]], &all_possible_tokens);
+        let likelihoods = model.compute_likelihoods(vec![vec![100]], &all_possible_tokens).unwrap();
 
         // 1 should be most likely, followed by 2, then 3
         assert!(likelihoods.get(&vec![1]).unwrap() > likelihoods.get(&vec![2]).unwrap());
         assert!(likelihoods.get(&vec![2]).unwrap() > likelihoods.get(&vec![3]).unwrap());
 
         // Test that the context is ignored by using a different context
-        let likelihoods2 = model.compute_likelihoods(vec![vec![1]], &all_possible_tokens);
+        let likelihoods2 = model.compute_likelihoods(vec![vec![1]], &all_possible_tokens).unwrap();
         assert_eq!(likelihoods, likelihoods2);
     }
+
+    #[test]
+    fn test_masked_likelihoods_sum_to_one() {
+        let tokens: Vec<Token> = vec![vec![1], vec![2], vec![3], vec![1], vec![2], vec![1]];
+        let model = BigramModel::train(tokens, TrainingOptions::default()).unwrap();
+        let allowed = vec![vec![2], vec![3]];
+
+        let likelihoods = model.compute_likelihoods_masked(vec![vec![1]], &allowed).unwrap();
+        assert_eq!(likelihoods.len(), 2);
+        assert!(allowed.iter().all(|token| likelihoods.contains_key(token)));
+        let sum: f32 = likelihoods.values().sum();
+        assert!((sum - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_likelihoods_vec_matches_map() {
+        let tokens: Vec<Token> = vec![vec![1], vec![2], vec![1], vec![3], vec![1], vec![2]];
+        let all_tokens: Vec<Token> = vec![vec![3], vec![1], vec![2]];
+        let model = BigramModel::train(tokens, TrainingOptions::default()).unwrap();
+
+        let map = model.compute_likelihoods(vec![vec![1]], &all_tokens).unwrap();
+        let vec = model.compute_likelihoods_vec(vec![vec![1]], &all_tokens).unwrap();
+        assert_eq!(vec.len(), all_tokens.len());
+        assert!((vec.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        for (token, likelihood) in all_tokens.iter().zip(&vec) {
+            assert_eq!(map[token], *likelihood);
+        }
+    }
+
+    #[test]
+    fn test_likelihoods_for_text_matches_tokens() {
+        let text = "abcabcabd";
+        let mut tokenizer = crate::clm::tokenizer::Tokenizer::new(4);
+        tokenizer.train(text, 5);
+        let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+        let model = BigramModel::train(tokens, TrainingOptions::default()).unwrap();
+        let all_tokens = tokenizer.get_tokens();
+
+        let context = tokenizer.encode_fast_opt("ab".to_string(), true);
+        let expected = model.compute_likelihoods(context, all_tokens).unwrap();
+        assert_eq!(model.likelihoods_for_text(&tokenizer, "ab", all_tokens).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_names() {
+        let tokens: Vec<Token> = vec![vec![1], vec![2], vec![1]];
+        assert_eq!(BigramModel::train(tokens.clone(), TrainingOptions::default()).unwrap().name(), "bigram");
+        assert_eq!(UnigramModel::train(tokens, TrainingOptions::default()).unwrap().name(), "unigram");
+    }
+
+    #[test]
+    fn test_empty_vocabulary_gives_empty_distribution() {
+        let tokens: Vec<Token> = vec![vec![1], vec![2], vec![1]];
+        let bigram = BigramModel::train(tokens.clone(), TrainingOptions::default()).unwrap();
+        assert!(bigram.compute_likelihoods(vec![vec![1]], &[]).unwrap().is_empty());
+        let unigram = UnigramModel::train(tokens, TrainingOptions::default()).unwrap();
+        assert!(unigram.compute_likelihoods(vec![vec![1]], &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_batch_likelihoods_match_single() {
+        let tokens: Vec<Token> = vec![vec![1], vec![2], vec![3], vec![1], vec![2], vec![1]];
+        let all_possible_tokens = vec![vec![1], vec![2], vec![3]];
+        let contexts = vec![vec![vec![1]], vec![vec![2]], vec![vec![3], vec![1]]];
+
+        let bigram = BigramModel::train(tokens.clone(), TrainingOptions::default()).unwrap();
+        let batch = bigram.compute_likelihoods_batch(&contexts, &all_possible_tokens).unwrap();
+        for (context, likelihoods) in contexts.iter().zip(batch) {
+            assert_eq!(likelihoods, bigram.compute_likelihoods(context.clone(), &all_possible_tokens).unwrap());
+        }
+
+        let unigram = UnigramModel::train(tokens, TrainingOptions::default()).unwrap();
+        let batch = unigram.compute_likelihoods_batch(&contexts, &all_possible_tokens).unwrap();
+        for (context, likelihoods) in contexts.iter().zip(batch) {
+            assert_eq!(likelihoods, unigram.compute_likelihoods(context.clone(), &all_possible_tokens).unwrap());
+        }
+    }
 }
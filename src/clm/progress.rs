@@ -0,0 +1,139 @@
+use indicatif::{HumanDuration, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Most redraws per second of a progress bar, however often it's updated
+pub const DEFAULT_DRAW_RATE: u8 = 4;
+
+/// How often a bar redraws by itself, so the spinner and elapsed time move during slow steps
+pub const DEFAULT_STEADY_TICK: Duration = Duration::from_millis(250);
+
+/// Time before the ETA is shown, the rate estimate is mostly noise before that
+const ETA_WARMUP: Duration = Duration::from_secs(3);
+
+/// Builds the progress bars of all long-running loops, so they share a template,
+/// a draw-rate cap and a steady tick
+#[derive(Debug, Clone)]
+pub struct ProgressBarBuilder {
+    len: u64,
+    unit: &'static str,
+    draw_rate: u8,
+    steady_tick: Option<Duration>,
+    hidden: bool,
+}
+
+impl ProgressBarBuilder {
+    /// A bar counting up to `len` with the default refresh settings
+    pub fn new(len: u64) -> Self {
+        ProgressBarBuilder {
+            len,
+            unit: "",
+            draw_rate: DEFAULT_DRAW_RATE,
+            steady_tick: Some(DEFAULT_STEADY_TICK),
+            hidden: false,
+        }
+    }
+
+    /// What is counted, shown after the position, e.g. "chunks"
+    pub fn unit(mut self, unit: &'static str) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// Caps the redraws per second, at least 1
+    pub fn draw_rate(mut self, draw_rate: u8) -> Self {
+        self.draw_rate = draw_rate.max(1);
+        self
+    }
+
+    /// Redraws at this interval even without updates, never if none
+    pub fn steady_tick(mut self, steady_tick: Option<Duration>) -> Self {
+        self.steady_tick = steady_tick;
+        self
+    }
+
+    /// Draws nothing, e.g. in tests or when the output is piped
+    pub fn hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Most redraws per second
+    pub fn get_draw_rate(&self) -> u8 {
+        self.draw_rate
+    }
+
+    /// Interval of the steady tick, if any
+    pub fn get_steady_tick(&self) -> Option<Duration> {
+        self.steady_tick
+    }
+
+    /// The template every bar is drawn with
+    pub fn template(&self) -> String {
+        let unit = match self.unit {
+            "" => String::new(),
+            unit => format!(" {}", unit),
+        };
+        format!(
+            "{{spinner:.green}} [{{elapsed_precise}}] [{{bar:40.cyan/blue}}] {{pos}}/{{len}}{} {{smoothed_eta}} rem. ({{msg}})",
+            unit
+        )
+    }
+
+    /// Creates the bar and starts its steady tick
+    pub fn build(&self) -> ProgressBar {
+        let target = if self.hidden {
+            ProgressDrawTarget::hidden()
+        } else {
+            ProgressDrawTarget::stderr_with_hz(self.draw_rate)
+        };
+        let style = ProgressStyle::with_template(&self.template())
+            .expect("Failed to set progress bar style")
+            .with_key("smoothed_eta", write_smoothed_eta)
+            .progress_chars("#>-");
+        let progress_bar = ProgressBar::with_draw_target(Some(self.len), target).with_style(style);
+        if let Some(steady_tick) = self.steady_tick {
+            progress_bar.enable_steady_tick(steady_tick);
+        }
+        progress_bar
+    }
+}
+
+/// Writes indicatif's moving-average ETA once it had `ETA_WARMUP` to settle
+fn write_smoothed_eta(state: &ProgressState, w: &mut dyn Write) {
+    if state.elapsed() < ETA_WARMUP || state.pos() == 0 {
+        let _ = write!(w, "--");
+    } else {
+        let _ = write!(w, "{:#}", HumanDuration(state.eta()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_applies_settings() {
+        let builder = ProgressBarBuilder::new(10)
+            .unit("chunks")
+            .draw_rate(0)
+            .steady_tick(Some(Duration::from_millis(50)))
+            .hidden(true);
+        assert_eq!(builder.get_draw_rate(), 1);
+        assert_eq!(builder.get_steady_tick(), Some(Duration::from_millis(50)));
+        assert!(builder.template().contains("{pos}/{len} chunks {smoothed_eta}"));
+
+        let progress_bar = builder.build();
+        assert!(progress_bar.is_hidden());
+        assert_eq!(progress_bar.length(), Some(10));
+        progress_bar.inc(3);
+        assert_eq!(progress_bar.position(), 3);
+        progress_bar.finish_and_clear();
+
+        // Defaults
+        let builder = ProgressBarBuilder::new(5);
+        assert_eq!(builder.get_draw_rate(), DEFAULT_DRAW_RATE);
+        assert_eq!(builder.get_steady_tick(), Some(DEFAULT_STEADY_TICK));
+        assert!(builder.template().contains("{pos}/{len} {smoothed_eta}"));
+    }
+}
@@ -0,0 +1,95 @@
+use crate::clm::clm_model::Model;
+use crate::clm::evaluate::check_distribution;
+use crate::clm::tokenizer::{Token, Tokenizer};
+use serde::{Deserialize, Serialize};
+
+/// Minimum fraction of the normalized sample that has to survive encoding and decoding
+const MIN_ROUND_TRIP_COVERAGE: f64 = 0.99;
+
+/// Result of `self_test`, a quick check that a loaded model is usable
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    /// Number of contexts whose predicted distribution was checked
+    pub distributions_checked: usize,
+    /// Why each failing distribution was rejected
+    pub distribution_errors: Vec<String>,
+    /// Fraction of the normalized sample's characters reproduced by decode(encode(sample))
+    pub round_trip_coverage: f64,
+    /// Whether every distribution was valid and the round trip covered the sample
+    pub passed: bool,
+}
+
+impl std::fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "distributions: {}/{} valid",
+            self.distributions_checked - self.distribution_errors.len(),
+            self.distributions_checked
+        )?;
+        for error in &self.distribution_errors {
+            writeln!(f, "  {}", error)?;
+        }
+        writeln!(f, "round trip coverage: {:.2}%", self.round_trip_coverage * 100.0)?;
+        write!(f, "{}", if self.passed { "healthy" } else { "unhealthy" })
+    }
+}
+
+/// Checks that the model predicts valid distributions for a few contexts taken from `sample`
+/// and that the tokenizer reproduces `sample` when encoding and decoding it
+pub fn self_test<M: Model>(model: &M, tokenizer: &Tokenizer, sample: &str) -> HealthReport {
+    let all_tokens = tokenizer.get_tokens();
+    let tokens = tokenizer.encode_fast_opt(sample.to_string(), true);
+
+    // Short, medium and long contexts, plus one built from the vocabulary alone
+    let mut contexts: Vec<Vec<Token>> = [1, 8, 64]
+        .iter()
+        .filter(|&&len| len <= tokens.len())
+        .map(|&len| tokens[..len].to_vec())
+        .collect();
+    contexts.push(all_tokens.iter().take(4).cloned().collect());
+
+    let distribution_errors: Vec<String> = contexts
+        .iter()
+        .filter_map(|context| {
+            model
+                .compute_likelihoods(context.clone(), all_tokens)
+                .and_then(|likelihoods| check_distribution(&likelihoods))
+                .err()
+                .map(|error| format!("context of {} tokens: {}", context.len(), error))
+        })
+        .collect();
+
+    let normalized = tokenizer.normalize(sample);
+    let decoded = tokenizer.decode(&tokens);
+    let round_trip_coverage = if normalized.is_empty() {
+        1.0
+    } else {
+        let matching = normalized.iter().zip(decoded.chars()).take_while(|(a, b)| *a == b).count();
+        matching as f64 / normalized.len() as f64
+    };
+
+    HealthReport {
+        distributions_checked: contexts.len(),
+        passed: distribution_errors.is_empty() && round_trip_coverage >= MIN_ROUND_TRIP_COVERAGE,
+        distribution_errors,
+        round_trip_coverage,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clm::{fixtures, train_from_text};
+
+    #[test]
+    fn test_tiny_model_is_healthy() {
+        let text = fixtures::corpus(3000);
+        let (model, tokenizer) = train_from_text(&text, fixtures::small_options()).unwrap();
+
+        let report = self_test(&model, &tokenizer, &text[..500]);
+        assert!(report.passed, "{}", report);
+        assert_eq!(report.distributions_checked, 4);
+        assert_eq!(report.round_trip_coverage, 1.0);
+    }
+}
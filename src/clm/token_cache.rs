@@ -0,0 +1,137 @@
+use crate::clm::error::ClmError;
+use crate::clm::tokenizer::{Token, Tokenizer};
+use crate::clm::training_options::TrainingOptions;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+
+/// Stores encoded training tokens on disk, so the training files are only tokenized once
+/// for a given tokenizer and dataset percentage
+pub struct TokenCache {
+    dir: PathBuf,
+}
+
+impl TokenCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        TokenCache { dir: dir.into() }
+    }
+
+    /// Hashes everything the encoded tokens depend on. The training files are identified
+    /// by their path, size and modification time, so editing them invalidates the cache.
+    pub fn key(options: &TrainingOptions, tokenizer: &Tokenizer) -> Result<u64, ClmError> {
+        let mut hasher = DefaultHasher::new();
+        for path in options.training_sources() {
+            let metadata = std::fs::metadata(path)?;
+            path.hash(&mut hasher);
+            metadata.len().hash(&mut hasher);
+            metadata.modified()?.hash(&mut hasher);
+        }
+        options.dataset_percentage.to_bits().hash(&mut hasher);
+        // Both change how the same files are read into tokens
+        options.pretokenized.hash(&mut hasher);
+        options.utf8_handling.hash(&mut hasher);
+        tokenizer.fingerprint().hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.tokens", key))
+    }
+
+    /// Loads the cached tokens for these inputs, or encodes and stores them if there are none
+    pub fn get_or_encode(
+        &self,
+        options: &TrainingOptions,
+        tokenizer: &Tokenizer,
+        encode: impl FnOnce() -> Result<Vec<Token>, ClmError>,
+    ) -> Result<Vec<Token>, ClmError> {
+        let path = self.path(Self::key(options, tokenizer)?);
+        if let Ok(bytes) = std::fs::read(&path) {
+            if let Some(tokens) = Self::decode(&bytes) {
+                log::info!("Loaded {} cached tokens from {}", tokens.len(), path.display());
+                return Ok(tokens);
+            }
+            log::warn!("Ignoring corrupt token cache {}", path.display());
+        }
+
+        let tokens = encode()?;
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(&path, Self::encode(&tokens))?;
+        Ok(tokens)
+    }
+
+    /// Writes each token as a little-endian u16 length followed by its bytes
+    fn encode(tokens: &[Token]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(tokens.iter().map(|token| token.len() + 2).sum());
+        for token in tokens {
+            bytes.extend_from_slice(&(token.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(token);
+        }
+        bytes
+    }
+
+    fn decode(mut bytes: &[u8]) -> Option<Vec<Token>> {
+        let mut tokens = Vec::new();
+        while !bytes.is_empty() {
+            let len = u16::from_le_bytes([*bytes.first()?, *bytes.get(1)?]) as usize;
+            tokens.push(bytes.get(2..2 + len)?.to_vec());
+            bytes = &bytes[2 + len..];
+        }
+        Some(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clm::training_options::Utf8Handling;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_second_call_loads_from_cache() {
+        let dir = std::env::temp_dir().join(format!("chatclm-token-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let training_file = dir.join("train.txt");
+        std::fs::write(&training_file, "the cat sat on the mat").unwrap();
+        let options = TrainingOptions {
+            training_file: training_file.to_str().unwrap().to_string(),
+            ..TrainingOptions::default()
+        };
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("the cat sat on the mat", 10);
+
+        let cache = TokenCache::new(dir.join("cache"));
+        let encodings = Cell::new(0);
+        let encode = || {
+            encodings.set(encodings.get() + 1);
+            let text = std::fs::read_to_string(&training_file)?;
+            Ok(tokenizer.encode_fast_opt(text, true))
+        };
+
+        let first = cache.get_or_encode(&options, &tokenizer, encode).unwrap();
+        let second = cache.get_or_encode(&options, &tokenizer, encode).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(encodings.get(), 1);
+
+        let other_options = TrainingOptions {
+            dataset_percentage: 0.5,
+            ..options.clone()
+        };
+        cache.get_or_encode(&other_options, &tokenizer, encode).unwrap();
+        assert_eq!(encodings.get(), 2);
+
+        let key = TokenCache::key(&options, &tokenizer).unwrap();
+        let pretokenized = TrainingOptions {
+            pretokenized: true,
+            ..options.clone()
+        };
+        assert_ne!(TokenCache::key(&pretokenized, &tokenizer).unwrap(), key);
+        let lossy = TrainingOptions {
+            utf8_handling: Utf8Handling::Lossy,
+            ..options.clone()
+        };
+        assert_ne!(TokenCache::key(&lossy, &tokenizer).unwrap(), key);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,11 @@
+use crate::clm::error::ClmError;
+use crate::clm::progress::ProgressBarBuilder;
+use crate::clm::training_options::TrainingOptions;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::sync::OnceLock;
 use unidecode::unidecode;
 
 pub type Token = Vec<u8>;
@@ -9,22 +13,279 @@ pub type Token = Vec<u8>;
 /// A BPE (Byte Pair Encoding) tokenizer
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Tokenizer {
-    /// Tokens
-    pub tokens: HashMap<String, Vec<u8>>,
+    /// Tokens, only changed through methods that keep the caches below up to date
+    tokens: HashMap<String, Vec<u8>>,
     /// List of merge operations in order they were learned
     pub merges: Vec<(String, String)>,
     /// The size of the vocabulary
     pub vocab_size: usize,
     /// The size of the token in bytes
     pub token_byte_size: usize,
+    /// Upper bound in characters for learned tokens, 0 for no limit. Only applies to
+    /// training, see `with_max_token_chars`.
+    #[serde(default)]
+    max_token_chars: usize,
+    /// Text emitted when decoding an unknown token code
+    #[serde(default = "default_unk_placeholder")]
+    pub unk_placeholder: String,
+    /// How token codes are laid out in bytes
+    #[serde(default)]
+    pub code_layout: CodeLayout,
+    /// Byte no token code contains, so it can delimit codes in the compressed text
+    #[serde(default)]
+    pub reserved_byte: Option<u8>,
+    /// The hash token codes are derived from, tokenizers saved without it used `DefaultHasher`
+    #[serde(default = "legacy_hash_scheme")]
+    pub hash_scheme: HashScheme,
+    /// How text is normalized and which merges are allowed
+    #[serde(default)]
+    pub normalization: NormalizationConfig,
+    /// Precomputed encoding trie, see `precompute_trie`
+    #[serde(default, deserialize_with = "deserialize_stored_trie")]
+    pub trie: Option<SerializedTrie>,
+    /// Fingerprint of the tokens, built on first use
+    #[serde(skip)]
+    vocab_fingerprint: OnceLock<u64>,
+    /// Trie built from the tokens when there is no matching precomputed one
+    #[serde(skip)]
+    trie_cache: OnceLock<TrieNode>,
+    /// Tokens in a stable order, built on first use
+    #[serde(skip)]
+    token_cache: OnceLock<(Vec<String>, Vec<Token>)>,
+    /// Length in characters of the longest token, bounds the encoding traversal.
+    /// Built on first use, so it always matches the current tokens.
+    #[serde(skip)]
+    max_token_len: OnceLock<usize>,
+    /// Token contents by code, built on first use or by `load`
+    #[serde(skip)]
+    reverse_cache: OnceLock<HashMap<Token, String>>,
 }
 
-// Trie node for token prefixes
+/// How whitespace is handled during normalization
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WhitespaceMode {
+    /// Keep spaces as they are and drop all other whitespace
+    #[default]
+    Spaces,
+    /// Replace every run of whitespace with a single space
+    Collapse,
+    /// Keep spaces, tabs and newlines as they are, e.g. for indented code
+    Preserve,
+}
+
+/// The byte layout of token codes.
+///
+/// Fixed-width codes make every token look alike to the compressor and let the
+/// byte stream be split every `token_byte_size` bytes. Variable-width codes grow
+/// by one byte per additional character, so the compressed size reflects how much
+/// text a token covers, at the cost of breaking the fixed-width contract:
+/// codes have to be split by their length prefix instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CodeLayout {
+    /// Every code is exactly `token_byte_size` hash bytes
+    #[default]
+    Fixed,
+    /// The first byte holds the code width, which is `token_byte_size` plus one byte
+    /// for every character beyond the first (at most 255), followed by hash bytes
+    VariableWidth,
+    /// Every code is exactly `token_byte_size` bytes. The first half are the hash of the
+    /// token's first character, so a merged token shares its code prefix with its first
+    /// merge parent and all other tokens starting alike; the rest hash the whole token.
+    Structured,
+}
+
+/// How `Tokenizer::decode_partial` marks tokens that aren't committed yet
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PendingStyle {
+    /// No marking, same as `decode`
+    #[default]
+    Plain,
+    /// Wrapped in ANSI faint/reset escapes for terminals
+    Dim,
+    /// Wrapped in brackets, for output without escape support
+    Bracketed,
+}
+
+impl PendingStyle {
+    fn markers(self) -> (&'static str, &'static str) {
+        match self {
+            PendingStyle::Plain => ("", ""),
+            PendingStyle::Dim => ("\x1b[2m", "\x1b[0m"),
+            PendingStyle::Bracketed => ("⟦", "⟧"),
+        }
+    }
+}
+
+/// The hash function token codes are computed with. Codes end up in the trained
+/// dictionaries, so the hash must give the same output on every platform and Rust version.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashScheme {
+    /// std's `DefaultHasher`, whose output may change between Rust versions
+    DefaultHasher,
+    /// 64-bit FNV-1a, stable by definition
+    #[default]
+    Fnv1a64,
+}
+
+impl HashScheme {
+    /// Hashes the content, together with a counter if given, so callers can draw
+    /// as many bytes as they need
+    fn hash(self, content: &str, counter: Option<u64>) -> u64 {
+        match self {
+            HashScheme::DefaultHasher => {
+                let mut hasher = DefaultHasher::new();
+                match counter {
+                    Some(counter) => (content, counter).hash(&mut hasher),
+                    None => content.hash(&mut hasher),
+                }
+                hasher.finish()
+            }
+            HashScheme::Fnv1a64 => {
+                let counter_bytes = counter.map(|counter| counter.to_le_bytes().to_vec()).unwrap_or_default();
+                content
+                    .bytes()
+                    .chain(counter_bytes)
+                    .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+                    })
+            }
+        }
+    }
+}
+
+fn legacy_hash_scheme() -> HashScheme {
+    HashScheme::DefaultHasher
+}
+
+/// Options controlling text normalization and tokenizer training
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NormalizationConfig {
+    /// How whitespace is handled
+    #[serde(default)]
+    pub whitespace: WhitespaceMode,
+    /// Allow merges where the first token ends with whitespace, so runs of
+    /// whitespace (e.g. indentation) can become a single token
+    #[serde(default)]
+    pub merge_across_spaces: bool,
+    /// Attach whitespace to the word after it, like GPT's "Ġword" tokens: merges may start
+    /// with whitespace but never put it after other characters, so a frequent word and
+    /// its leading space become a single token
+    #[serde(default)]
+    pub space_prefix: bool,
+    /// Map every run of newlines to a single boundary token instead of dropping it,
+    /// so paragraph structure survives. The token decodes back to a newline.
+    #[serde(default)]
+    pub newline_boundary: bool,
+    /// Number of characters per chunk when counting pairs during training. Pairs straddling
+    /// two chunks are intentionally not counted, so smaller chunks train faster but can
+    /// learn slightly different merges.
+    #[serde(default = "default_train_chunk_size")]
+    pub train_chunk_size: usize,
+}
+
+impl Default for NormalizationConfig {
+    fn default() -> Self {
+        NormalizationConfig {
+            whitespace: WhitespaceMode::default(),
+            merge_across_spaces: false,
+            space_prefix: false,
+            newline_boundary: false,
+            train_chunk_size: default_train_chunk_size(),
+        }
+    }
+}
+
+fn default_train_chunk_size() -> usize {
+    1024
+}
+
+/// The token newline runs are mapped to when `NormalizationConfig::newline_boundary` is set
+pub const BOUNDARY_TOKEN: &str = "\n";
+
+/// Most salts tried to find an unused code for a new token
+const MAX_CODE_SALT: u64 = 1024;
+
+fn default_unk_placeholder() -> String {
+    String::from("[UNK]")
+}
+
+/// Summary of a `Tokenizer::prune` call
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PruneReport {
+    /// Number of tokens removed from the vocabulary
+    pub removed: usize,
+    /// Number of tokens left in the vocabulary
+    pub remaining: usize,
+    /// The removed tokens
+    pub removed_tokens: Vec<String>,
+}
+
+/// An encoding trie stored together with a fingerprint of the vocabulary it was built from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SerializedTrie {
+    fingerprint: u64,
+    root: TrieNode,
+}
+
+/// Reads a stored trie, dropping tries in a format this version doesn't read, e.g. the
+/// nested one of older versions. A dropped trie is rebuilt from the tokens on first use.
+fn deserialize_stored_trie<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<SerializedTrie>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredTrie {
+        Current(SerializedTrie),
+        Other(serde::de::IgnoredAny),
+    }
+    Ok(match Option::<StoredTrie>::deserialize(deserializer)? {
+        Some(StoredTrie::Current(trie)) => Some(trie),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Characters `TrieNode::longest_match` looked at, so tests can check encoding is linear
+    static MATCH_STEPS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// Trie node for token prefixes. Serialized as a flat list of (token, code), nesting one
+// level per character would run into serde_json's recursion limit for long tokens.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "Vec<(String, Token)>", into = "Vec<(String, Token)>")]
 struct TrieNode {
     children: HashMap<char, TrieNode>,
     token_code: Option<Vec<u8>>,
 }
 
+impl From<Vec<(String, Token)>> for TrieNode {
+    fn from(entries: Vec<(String, Token)>) -> Self {
+        let mut root = TrieNode::new();
+        for (token, code) in entries {
+            root.insert(&token, code);
+        }
+        root
+    }
+}
+
+impl From<TrieNode> for Vec<(String, Token)> {
+    fn from(root: TrieNode) -> Self {
+        let mut entries = Vec::new();
+        let mut stack = vec![(String::new(), root)];
+        while let Some((path, node)) = stack.pop() {
+            if let Some(code) = node.token_code {
+                entries.push((path.clone(), code));
+            }
+            for (ch, child) in node.children {
+                let mut child_path = path.clone();
+                child_path.push(ch);
+                stack.push((child_path, child));
+            }
+        }
+        entries.sort();
+        entries
+    }
+}
+
 impl TrieNode {
     fn new() -> Self {
         TrieNode {
@@ -41,6 +302,24 @@ impl TrieNode {
         }
         node.token_code = Some(code);
     }
+
+    // Find the longest token at the start of the text, but never beyond max_len characters
+    fn longest_match<'a>(root: &'a TrieNode, text: &[char], max_len: usize) -> Option<(&'a Vec<u8>, usize)> {
+        let mut node = root;
+        let mut last_match = None;
+        for (j, ch) in text.iter().enumerate().take(max_len) {
+            #[cfg(test)]
+            MATCH_STEPS.with(|steps| steps.set(steps.get() + 1));
+            match node.children.get(ch) {
+                Some(child) => node = child,
+                None => break,
+            }
+            if let Some(code) = &node.token_code {
+                last_match = Some((code, j + 1));
+            }
+        }
+        last_match
+    }
 }
 
 impl Tokenizer {
@@ -51,25 +330,188 @@ impl Tokenizer {
             merges: Vec::new(),
             vocab_size: 0,
             token_byte_size,
+            max_token_chars: 0,
+            code_layout: CodeLayout::default(),
+            reserved_byte: None,
+            hash_scheme: HashScheme::default(),
+            unk_placeholder: default_unk_placeholder(),
+            normalization: NormalizationConfig::default(),
+            trie: None,
+            vocab_fingerprint: OnceLock::new(),
+            trie_cache: OnceLock::new(),
+            token_cache: OnceLock::new(),
+            max_token_len: OnceLock::new(),
+            reverse_cache: OnceLock::new(),
         }
     }
 
-    pub fn get_str_tokens(&self) -> Vec<String> {
-        self.tokens.keys().cloned().collect()
+    /// Creates an empty tokenizer set up to be trained with the given options
+    pub fn for_training(options: &TrainingOptions) -> Self {
+        let mut tokenizer = Tokenizer::new(options.token_byte_size).with_max_token_chars(options.max_token_chars);
+        tokenizer.code_layout = options.code_layout;
+        tokenizer.reserved_byte = options.token_delimiter;
+        tokenizer
+    }
+
+    /// Caps the length in characters of the tokens `train` learns, 0 for no limit.
+    /// Tokens already learned are kept and still matched in full.
+    pub fn with_max_token_chars(mut self, max_token_chars: usize) -> Self {
+        self.max_token_chars = max_token_chars;
+        self
+    }
+
+    /// Creates a tokenizer for text that was tokenized elsewhere, with the ids `0..vocab_size`
+    /// as its tokens. Each id's code is its little-endian bytes, see `encode_token_ids`.
+    pub fn for_token_ids(vocab_size: usize, token_byte_size: usize) -> Result<Self, ClmError> {
+        if token_byte_size < 8 && vocab_size as u64 > 1 << (8 * token_byte_size) {
+            return Err(ClmError::Config(format!(
+                "{} token ids don't fit into {} bytes",
+                vocab_size, token_byte_size
+            )));
+        }
+        let mut tokenizer = Tokenizer::new(token_byte_size);
+        tokenizer.tokens = (0..vocab_size as u64)
+            .map(|id| {
+                let mut code = id.to_le_bytes().to_vec();
+                code.resize(token_byte_size, 0);
+                (id.to_string(), code)
+            })
+            .collect();
+        tokenizer.invalidate_caches();
+        tokenizer.vocab_size = vocab_size;
+        Ok(tokenizer)
+    }
+
+    /// Encodes whitespace-separated token ids, e.g. the output of an external tokenizer,
+    /// using a tokenizer created by `for_token_ids`
+    pub fn encode_token_ids(&self, text: &str) -> Result<Vec<Token>, ClmError> {
+        text.split_whitespace()
+            .map(|id| {
+                self.tokens
+                    .get(id)
+                    .cloned()
+                    .ok_or_else(|| ClmError::Config(format!("unknown token id: {}", id)))
+            })
+            .collect()
+    }
+
+    /// Writes the tokens as whitespace-separated ids, each token's index in `get_tokens`,
+    /// e.g. to inspect an encoded corpus or feed it to other tools
+    pub fn write_token_ids(&self, tokens: &[Token], out: &mut impl std::io::Write) -> Result<(), ClmError> {
+        let ids: HashMap<&Token, usize> = self.get_tokens().iter().enumerate().map(|(id, code)| (code, id)).collect();
+        for (i, token) in tokens.iter().enumerate() {
+            let id = ids
+                .get(token)
+                .ok_or_else(|| ClmError::Config(format!("token code {:?} is not in the vocabulary", token)))?;
+            if i > 0 {
+                write!(out, " ")?;
+            }
+            write!(out, "{}", id)?;
+        }
+        writeln!(out)?;
+        Ok(())
+    }
+
+    /// Reads ids written by `write_token_ids` back into token codes
+    pub fn read_token_ids(&self, text: &str) -> Result<Vec<Token>, ClmError> {
+        let tokens = self.get_tokens();
+        text.split_whitespace()
+            .map(|id| {
+                id.parse::<usize>()
+                    .ok()
+                    .and_then(|id| tokens.get(id))
+                    .cloned()
+                    .ok_or_else(|| ClmError::Config(format!("unknown token id: {}", id)))
+            })
+            .collect()
+    }
+
+    /// Returns the token strings sorted alphabetically
+    pub fn get_str_tokens(&self) -> &[String] {
+        &self.sorted_tokens().0
+    }
+
+    /// Returns the token codes in the same order as `get_str_tokens`
+    pub fn get_tokens(&self) -> &[Token] {
+        &self.sorted_tokens().1
+    }
+
+    /// The vocabulary, each token's content with its code
+    pub fn tokens(&self) -> &HashMap<String, Token> {
+        &self.tokens
+    }
+
+    /// Adds a token or replaces its code, returning the old code
+    pub fn insert_token(&mut self, content: String, code: Token) -> Option<Token> {
+        let old = self.tokens.insert(content, code);
+        self.invalidate_caches();
+        old
+    }
+
+    /// Removes a token, returning its code. Merges producing it are kept.
+    pub fn remove_token(&mut self, content: &str) -> Option<Token> {
+        let old = self.tokens.remove(content);
+        self.invalidate_caches();
+        old
     }
 
-    pub fn get_tokens(&self) -> Vec<Token> {
-        self.tokens.values().cloned().collect()
+    /// Clears cached data derived from `tokens`, called whenever they change
+    fn invalidate_caches(&mut self) {
+        self.vocab_fingerprint = OnceLock::new();
+        self.trie_cache = OnceLock::new();
+        self.token_cache = OnceLock::new();
+        self.max_token_len = OnceLock::new();
+        self.reverse_cache = OnceLock::new();
+    }
+
+    fn sorted_tokens(&self) -> &(Vec<String>, Vec<Token>) {
+        self.token_cache.get_or_init(|| {
+            let mut entries: Vec<_> = self.tokens.iter().collect();
+            entries.sort();
+            entries
+                .into_iter()
+                .map(|(content, code)| (content.clone(), code.clone()))
+                .unzip()
+        })
     }
 
     pub fn normalize(&self, text: &str) -> Vec<char> {
-        let lowercased = unidecode(&text.to_lowercase());
+        let lowercased = unidecode(&text.to_lowercase()).to_lowercase();
+        let whitespace = self.normalization.whitespace;
+        let newline_boundary = self.normalization.newline_boundary;
+        let is_newline = |c: char| c == '\n' || c == '\r';
 
-        lowercased
-            .to_lowercase()
+        let filtered = lowercased
             .chars()
-            .filter(|c| c.is_ascii_lowercase() || *c == ' ' || *c == '.' || *c == ',' || *c == '!')
-            .collect()
+            .filter(|c| {
+                c.is_ascii_lowercase()
+                    || *c == ' '
+                    || *c == '.'
+                    || *c == ','
+                    || *c == '!'
+                    || (whitespace != WhitespaceMode::Spaces && c.is_whitespace())
+                    || (newline_boundary && is_newline(*c))
+            });
+
+        if whitespace == WhitespaceMode::Collapse || newline_boundary {
+            let mut collapsed = Vec::new();
+            for c in filtered {
+                if newline_boundary && is_newline(c) {
+                    if collapsed.last() != Some(&'\n') {
+                        collapsed.push('\n');
+                    }
+                } else if whitespace == WhitespaceMode::Collapse && c.is_whitespace() {
+                    if collapsed.last() != Some(&' ') {
+                        collapsed.push(' ');
+                    }
+                } else {
+                    collapsed.push(c);
+                }
+            }
+            collapsed
+        } else {
+            filtered.collect()
+        }
     }
 
     /// Trains the tokenizer on the given text
@@ -78,15 +520,21 @@ impl Tokenizer {
         let normalized_text: Vec<char> = self.normalize(text);
         // Initialize with character-level tokens
         let mut vocab: HashMap<String, Token> = HashMap::new();
+        let mut used_codes: HashSet<Token> = HashSet::new();
         for c in normalized_text.iter() {
             let char_str = c.to_string();
-            vocab
-                .entry(char_str.clone())
-                .or_insert_with(|| self.compute_token_code(&char_str, self.token_byte_size));
+            if let std::collections::hash_map::Entry::Vacant(entry) = vocab.entry(char_str) {
+                let code = self.unique_token_code(entry.key(), &mut used_codes);
+                entry.insert(code);
+            }
+        }
+        if self.normalization.newline_boundary && !vocab.contains_key(BOUNDARY_TOKEN) {
+            let code = self.unique_token_code(BOUNDARY_TOKEN, &mut used_codes);
+            vocab.insert(BOUNDARY_TOKEN.to_string(), code);
         }
 
-        // chunk the tokenized text in to sqrt(len) chunks
-        let chunk_size = 1024;
+        // chunk the tokenized text, pairs across chunk boundaries are never counted
+        let chunk_size = self.normalization.train_chunk_size.max(1);
         let mut chunks: Vec<Vec<String>> = normalized_text
             .into_iter()
             .map(|c: char| c.to_string())
@@ -101,11 +549,31 @@ impl Tokenizer {
             let mut pair_counts: HashMap<(String, String), usize> = HashMap::new();
             for chunk in &chunks {
                 for i in 0..chunk.len().saturating_sub(1) {
-                    if chunk[i].ends_with(' ') {
+                    // Skip if first ends with a space
+                    let space_prefix = self.normalization.space_prefix;
+                    if !self.normalization.merge_across_spaces
+                        && !space_prefix
+                        && chunk[i].ends_with(char::is_whitespace)
+                    {
+                        continue;
+                    }
+                    // With leading spaces, whitespace only ever starts a token
+                    if space_prefix && chunk[i + 1].starts_with(char::is_whitespace) {
+                        continue;
+                    }
+                    // The boundary token always stays on its own
+                    if self.normalization.newline_boundary
+                        && (chunk[i] == BOUNDARY_TOKEN || chunk[i + 1] == BOUNDARY_TOKEN)
+                    {
+                        continue;
+                    }
+                    // Merges that would exceed the length cap are never learned
+                    if self.max_token_chars > 0
+                        && chunk[i].chars().count() + chunk[i + 1].chars().count() > self.max_token_chars
+                    {
                         continue;
                     }
                     let pair = (chunk[i].clone(), chunk[i + 1].clone());
-                    // Skip if first ends with a space
                     *pair_counts.entry(pair).or_insert(0) += 1;
                 }
             }
@@ -117,13 +585,14 @@ impl Tokenizer {
                 // Create new merged token
                 let new_token_str = format!("{}{}", first, second);
 
-                let token = self.compute_token_code(&new_token_str, self.token_byte_size);
-
                 // Add the merge to our list of merges
                 self.merges.push((first.clone(), second.clone()));
 
-                // Add the new token to our vocabulary
-                vocab.insert(new_token_str.clone(), token);
+                // Add the new token to our vocabulary, unless another merge already built it
+                if !vocab.contains_key(&new_token_str) {
+                    let token = self.unique_token_code(&new_token_str, &mut used_codes);
+                    vocab.insert(new_token_str.clone(), token);
+                }
 
                 // Apply the merge to the tokenized text, chunk by chunk
                 for chunk in &mut chunks {
@@ -144,6 +613,7 @@ impl Tokenizer {
         }
         // Store the final vocabulary
         self.tokens = vocab;
+        self.invalidate_caches();
 
         /*// Create reverse mapping for decoding
         for (content, code) in &self.tokens {
@@ -151,19 +621,166 @@ impl Tokenizer {
         }*/
     }
 
+    /// Removes multi-character tokens used fewer than `min_count` times when encoding `corpus`.
+    /// Single-character tokens are always kept so every normalized text stays encodable.
+    pub fn prune(&mut self, min_count: usize, corpus: &str) -> PruneReport {
+        let mut counts: HashMap<Token, usize> = HashMap::new();
+        for token in self.encode_fast_opt(corpus.to_string(), true) {
+            *counts.entry(token).or_insert(0) += 1;
+        }
+
+        let mut removed_tokens: Vec<String> = self
+            .tokens
+            .iter()
+            .filter(|(content, code)| {
+                content.chars().count() > 1 && counts.get(*code).copied().unwrap_or(0) < min_count
+            })
+            .map(|(content, _)| content.clone())
+            .collect();
+        removed_tokens.sort();
+
+        for content in &removed_tokens {
+            self.tokens.remove(content);
+        }
+        self.merges
+            .retain(|(first, second)| self.tokens.contains_key(&format!("{}{}", first, second)));
+        self.vocab_size = self.tokens.len();
+        self.invalidate_caches();
+
+        PruneReport {
+            removed: removed_tokens.len(),
+            remaining: self.tokens.len(),
+            removed_tokens,
+        }
+    }
+
+    /// The token codes emitted when encoding `text`. Tokens missing from it are dead
+    /// vocabulary on that text, see `prune`.
+    pub fn active_vocab(&self, text: &str) -> HashSet<Token> {
+        let mut tokens = Vec::new();
+        self.encode_into(text, &mut tokens);
+        tokens.into_iter().collect()
+    }
+
+    fn longest_token_len(&self) -> usize {
+        self.tokens.keys().map(|token| token.chars().count()).max().unwrap_or(0)
+    }
+
+    /// Returns an owned copy of `reverse_map`
     pub fn build_reverse_map(&self) -> HashMap<Token, String> {
-        let mut reverse_tokens: HashMap<Token, String> = HashMap::new();
-        for (content, code) in &self.tokens {
-            reverse_tokens.insert(code.clone(), content.clone());
+        self.reverse_map().clone()
+    }
+
+    /// Maps each token code to its content, built once and reused until the tokens change
+    pub fn reverse_map(&self) -> &HashMap<Token, String> {
+        self.reverse_cache.get_or_init(|| {
+            self.tokens
+                .iter()
+                .map(|(content, code)| (code.clone(), content.clone()))
+                .collect()
+        })
+    }
+
+    /// Whether `reverse_map` is already built
+    #[cfg(test)]
+    pub(crate) fn has_reverse_map(&self) -> bool {
+        self.reverse_cache.get().is_some()
+    }
+
+    /// Precomputes the encoding trie so it is serialized with the tokenizer
+    /// and does not need to be rebuilt on every load
+    pub fn precompute_trie(&mut self) {
+        self.trie = Some(SerializedTrie {
+            fingerprint: self.vocab_fingerprint(),
+            root: self.build_trie(),
+        });
+    }
+
+    /// Returns the precomputed trie if it matches the current vocabulary, otherwise one built
+    /// once from the tokens
+    fn trie(&self) -> &TrieNode {
+        match &self.trie {
+            Some(trie) if trie.fingerprint == self.vocab_fingerprint() => &trie.root,
+            _ => self.trie_cache.get_or_init(|| self.build_trie()),
         }
-        reverse_tokens
     }
 
-    /// Computes a fixed-size byte code for a token based on its hash
-    fn compute_token_code(&self, content: &str, token_byte_size: usize) -> Vec<u8> {
+    fn build_trie(&self) -> TrieNode {
+        let mut root = TrieNode::new();
+        for (token, code) in &self.tokens {
+            root.insert(token, code.clone());
+        }
+        root
+    }
+
+    /// Hashes everything that affects encoding, to detect stale encoded text
+    pub fn fingerprint(&self) -> u64 {
         let mut hasher = DefaultHasher::new();
-        content.hash(&mut hasher);
-        let hash = hasher.finish();
+        self.vocab_fingerprint().hash(&mut hasher);
+        self.max_token_len().hash(&mut hasher);
+        format!("{:?}{:?}{:?}", self.code_layout, self.reserved_byte, self.normalization).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Hashes the vocabulary in a stable order to detect stale tries, once until the tokens change
+    fn vocab_fingerprint(&self) -> u64 {
+        *self.vocab_fingerprint.get_or_init(|| {
+            let mut entries: Vec<_> = self.tokens.iter().collect();
+            entries.sort();
+            let mut hasher = DefaultHasher::new();
+            entries.hash(&mut hasher);
+            hasher.finish()
+        })
+    }
+
+    /// Computes the byte code for a token based on its hash and the code layout
+    fn compute_token_code(&self, content: &str, token_byte_size: usize) -> Vec<u8> {
+        self.compute_salted_token_code(content, token_byte_size, 0)
+    }
+
+    /// Computes a code for a new token that no token in `used` has, so decoding is unambiguous.
+    /// On a hash collision the content is rehashed with a salt, giving up once the code space
+    /// seems exhausted, e.g. for 1-byte codes.
+    fn unique_token_code(&self, content: &str, used: &mut HashSet<Token>) -> Token {
+        let mut code = self.compute_token_code(content, self.token_byte_size);
+        let mut salt = 1;
+        while used.contains(&code) {
+            if salt > MAX_CODE_SALT {
+                log::warn!("No unique code for token {:?}, decoding it is ambiguous", content);
+                break;
+            }
+            code = self.compute_salted_token_code(content, self.token_byte_size, salt);
+            salt += 1;
+        }
+        used.insert(code.clone());
+        code
+    }
+
+    /// Like `compute_token_code`, drawing a different code for every salt. A salt of 0 gives
+    /// the token's regular code.
+    fn compute_salted_token_code(&self, content: &str, token_byte_size: usize, salt: u64) -> Vec<u8> {
+        let mut code = match self.code_layout {
+            CodeLayout::Fixed => self.compute_fixed_code(content, token_byte_size, salt),
+            CodeLayout::Structured => self.compute_structured_code(content, token_byte_size, salt),
+            CodeLayout::VariableWidth => self.compute_variable_width_code(content, token_byte_size, salt),
+        };
+        if let Some(reserved) = self.reserved_byte {
+            // The width byte of variable width codes already avoids it
+            let start = usize::from(self.code_layout == CodeLayout::VariableWidth);
+            for byte in &mut code[start..] {
+                if *byte == reserved {
+                    *byte ^= 0x80;
+                }
+            }
+        }
+        code
+    }
+
+    fn compute_fixed_code(&self, content: &str, token_byte_size: usize, salt: u64) -> Vec<u8> {
+        let hash = match salt {
+            0 => self.hash_scheme.hash(content, None),
+            salt => self.hash_scheme.hash(content, Some(salt)),
+        };
 
         // Convert the hash to a fixed-size byte array
         let mut code = Vec::with_capacity(token_byte_size);
@@ -175,67 +792,113 @@ impl Tokenizer {
         code
     }
 
-    pub fn encode_fast_opt(&self, text: String, silent: bool) -> Vec<Vec<u8>> {
-        // Build trie once (could be cached on self)
-        if !silent {
-            println!("Building trie...");
+    fn compute_structured_code(&self, content: &str, token_byte_size: usize, salt: u64) -> Vec<u8> {
+        let prefix_len = token_byte_size / 2;
+        let first_char = content.chars().next().map(String::from).unwrap_or_default();
+        let prefix_hash = self.hash_scheme.hash(&first_char, None).to_le_bytes();
+        let mut code: Vec<u8> = prefix_hash.iter().cycle().take(prefix_len).copied().collect();
+        // Salt the content hash, so a single character's code isn't its prefix repeated
+        let content_hash = self.hash_scheme.hash(content, Some(salt)).to_le_bytes();
+        code.extend(content_hash.iter().cycle().take(token_byte_size - prefix_len));
+        code
+    }
+
+    fn compute_variable_width_code(&self, content: &str, token_byte_size: usize, salt: u64) -> Vec<u8> {
+        let mut width = (token_byte_size + content.chars().count().saturating_sub(1)).clamp(2, 255);
+        if self.reserved_byte == Some(width as u8) {
+            // Take one more hash byte, or one less at the upper bound
+            width = if width == 255 { 254 } else { width + 1 };
         }
-        let mut root = TrieNode::new();
-        for (token, code) in &self.tokens {
-            root.insert(token, code.clone());
-        }    
-        if !silent {
-            println!("Normalizing text...");
+        let mut code = Vec::with_capacity(width);
+        code.push(width as u8);
+        // Hash the content together with a counter to get as many bytes as needed
+        let mut counter = salt << 32;
+        while code.len() < width {
+            let hash = self.hash_scheme.hash(content, Some(counter)).to_le_bytes();
+            let missing = width - code.len();
+            code.extend_from_slice(&hash[..missing.min(hash.len())]);
+            counter += 1;
         }
+        code
+    }
+
+    /// Splits a byte stream of concatenated codes back into the individual codes
+    pub fn split_codes(&self, bytes: &[u8]) -> Vec<Token> {
+        match self.code_layout {
+            CodeLayout::Fixed | CodeLayout::Structured => {
+                bytes.chunks(self.token_byte_size).map(|code| code.to_vec()).collect()
+            }
+            CodeLayout::VariableWidth => {
+                let mut codes = Vec::new();
+                let mut i = 0;
+                while i < bytes.len() {
+                    let width = (bytes[i] as usize).max(1);
+                    codes.push(bytes[i..(i + width).min(bytes.len())].to_vec());
+                    i += width;
+                }
+                codes
+            }
+        }
+    }
+
+    /// Encodes like `encode_fast` into `out`, replacing its contents. Reuses the allocations
+    /// of `out` and of the codes already in it, and never reports progress, for hot loops.
+    pub fn encode_into(&self, text: &str, out: &mut Vec<Token>) {
+        let root = self.trie();
+        let max_token_len = self.max_token_len();
+        let normalized: Vec<char> = self.normalize(text);
+        let mut count = 0;
+        let mut i = 0;
+        while i < normalized.len() {
+            if let Some((code, match_len)) = TrieNode::longest_match(root, &normalized[i..], max_token_len) {
+                match out.get_mut(count) {
+                    Some(slot) => {
+                        slot.clear();
+                        slot.extend_from_slice(code);
+                    }
+                    None => out.push(code.clone()),
+                }
+                count += 1;
+                i += match_len;
+            } else {
+                // No token, skip this char
+                i += 1;
+            }
+        }
+        out.truncate(count);
+    }
+
+    pub fn encode_fast_opt(&self, text: String, silent: bool) -> Vec<Vec<u8>> {
+        if silent {
+            let mut output = Vec::new();
+            self.encode_into(&text, &mut output);
+            return output;
+        }
+        // Use the precomputed trie if available, otherwise build it once
+        log::info!("Building trie...");
+        let root = self.trie();
+        let max_token_len = self.max_token_len();
+        log::info!("Normalizing text...");
         let normalized: Vec<char> = self.normalize(&text);
         drop(text);
         let mut output = Vec::new();
         let n = normalized.len();
         let mut i = 0;
 
-        let progress_bar = indicatif::ProgressBar::new(n as u64);
-        if !silent {
-            progress_bar.set_style(
-            indicatif::ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
-                )
-                .expect("Failed to set progress bar style")
-                .progress_chars("#>-"),
-        );
-        }
+        let progress_bar = ProgressBarBuilder::new(n as u64).unit("chars").build();
         // Traverse input greedily
         while i < n {
-            let mut node = &root;
-            let mut last_match_code: Option<&Vec<u8>> = None;
-            let mut match_len = 0;
-            // Try to extend as far as possible
-            for j in i..n {
-                if let Some(child) = node.children.get(&normalized[j]) {
-                    node = child;
-                    if let Some(code) = &node.token_code {
-                        last_match_code = Some(code);
-                        match_len = j - i + 1;
-                    }
-                } else {
-                    break;
-                }
-            }
             // If we found a match, emit it and advance
-            if let Some(code) = last_match_code {
+            if let Some((code, match_len)) = TrieNode::longest_match(root, &normalized[i..], max_token_len) {
                 output.push(code.clone());
                 i += match_len;
             } else {
                 // No token, skip this char
                 i += 1;
             }
-            if !silent {
-                progress_bar.set_position(i as u64);
-            }
-        }
-        if !silent {
-            progress_bar.finish_with_message("Encoding complete");
+            progress_bar.set_position(i as u64);
         }
+        progress_bar.finish_with_message("Encoding complete");
 
         output
     }
@@ -244,44 +907,148 @@ impl Tokenizer {
         self.encode_fast_opt(text, false)
     }
 
+    /// Encodes the text by replaying the learned merges in order, like during training.
+    /// Slower than `encode_fast`, whose greedy longest match can split text differently.
+    pub fn encode_bpe(&self, text: &str) -> Vec<Token> {
+        let normalized = self.normalize(text);
+        self.bpe_spans(&normalized)
+            .into_iter()
+            .map(|(start, len)| self.tokens[&normalized[start..start + len].iter().collect::<String>()].clone())
+            .collect()
+    }
+
+    /// Fraction of tokens on which `encode_fast` and `encode_bpe` agree, i.e. start at the
+    /// same character and cover the same text, relative to the longer of the two encodings
+    pub fn tokenization_agreement(&self, text: &str) -> f64 {
+        let normalized = self.normalize(text);
+        let greedy = self.greedy_spans(&normalized);
+        let bpe: std::collections::HashSet<(usize, usize)> = self.bpe_spans(&normalized).into_iter().collect();
+        let total = greedy.len().max(bpe.len());
+        if total == 0 {
+            return 1.0;
+        }
+        let agreeing = greedy.iter().filter(|span| bpe.contains(span)).count();
+        agreeing as f64 / total as f64
+    }
+
+    /// Average number of normalized characters per token `encode_fast` emits, 0 for empty text.
+    /// Higher means a more efficient encoding.
+    pub fn chars_per_token(&self, text: &str) -> f64 {
+        let normalized = self.normalize(text);
+        let tokens = self.greedy_spans(&normalized).len();
+        if tokens == 0 {
+            return 0.0;
+        }
+        normalized.len() as f64 / tokens as f64
+    }
+
+    /// Length of the longest token, derived from the tokens so it never goes stale
+    fn max_token_len(&self) -> usize {
+        *self.max_token_len.get_or_init(|| self.longest_token_len())
+    }
+
+    /// The (start, length) of each token `encode_fast` emits, in characters of the normalized text
+    fn greedy_spans(&self, normalized: &[char]) -> Vec<(usize, usize)> {
+        let root = self.trie();
+        let max_token_len = self.max_token_len();
+        let mut spans = Vec::new();
+        let mut i = 0;
+        while i < normalized.len() {
+            match TrieNode::longest_match(root, &normalized[i..], max_token_len) {
+                Some((_, len)) => {
+                    spans.push((i, len));
+                    i += len;
+                }
+                None => i += 1,
+            }
+        }
+        spans
+    }
+
+    /// The (start, length) of each token after replaying the merges, skipping unknown pieces
+    fn bpe_spans(&self, normalized: &[char]) -> Vec<(usize, usize)> {
+        let mut pieces: Vec<String> = normalized.iter().map(|c| c.to_string()).collect();
+        for (first, second) in &self.merges {
+            let mut merged = Vec::with_capacity(pieces.len());
+            let mut i = 0;
+            while i < pieces.len() {
+                if i + 1 < pieces.len() && pieces[i] == *first && pieces[i + 1] == *second {
+                    merged.push(format!("{}{}", first, second));
+                    i += 2;
+                } else {
+                    merged.push(std::mem::take(&mut pieces[i]));
+                    i += 1;
+                }
+            }
+            pieces = merged;
+        }
+
+        let mut spans = Vec::with_capacity(pieces.len());
+        let mut start = 0;
+        for piece in pieces {
+            let len = piece.chars().count();
+            if self.tokens.contains_key(&piece) {
+                spans.push((start, len));
+            }
+            start += len;
+        }
+        spans
+    }
+
     /// Decodes a sequence of token codes back into text
     pub fn decode(&self, tokens: &[Vec<u8>]) -> String {
         let mut text = String::new();
 
-        let reverse_tokens = self.build_reverse_map();
+        let reverse_tokens = self.reverse_map();
 
         for token_code in tokens {
             if let Some(content) = reverse_tokens.get(token_code) {
                 text.push_str(content);
             } else {
                 // Handle unknown token with a placeholder
-                text.push_str("[UNK]");
+                text.push_str(&self.unk_placeholder);
             }
         }
 
         text
     }
 
+    /// Decodes a sequence of token codes of which only the first `committed` are final,
+    /// e.g. when generation stops mid-sequence or the context gets truncated. The trailing
+    /// tokens are marked with `style` so they can be told apart from the committed text.
+    pub fn decode_partial(&self, tokens: &[Vec<u8>], committed: usize, style: PendingStyle) -> String {
+        let (committed_tokens, pending_tokens) = tokens.split_at(committed.min(tokens.len()));
+        let mut text = self.decode(committed_tokens);
+        if !pending_tokens.is_empty() {
+            let (open, close) = style.markers();
+            text.push_str(open);
+            text.push_str(&self.decode(pending_tokens));
+            text.push_str(close);
+        }
+        text
+    }
+
     pub fn decode_with_delimiters(&self, tokens: &[Vec<u8>]) -> String {
         // decodes but adds the middle dot between two tokens
+        self.decode_with_separator(tokens, "·")
+    }
+
+    /// Decodes a sequence of token codes, inserting `sep` between two tokens
+    pub fn decode_with_separator(&self, tokens: &[Vec<u8>], sep: &str) -> String {
         let mut text = String::new();
-        let reverse_tokens = self.build_reverse_map();
+        let reverse_tokens = self.reverse_map();
         let mut first = true;
         for token_code in tokens {
+            if !first {
+                text.push_str(sep);
+            }
             if let Some(content) = reverse_tokens.get(token_code) {
-                if !first {
-                    text.push('·'); // middle dot as delimiter
-                }
                 text.push_str(content);
-                first = false;
             } else {
                 // Handle unknown token with a placeholder
-                if !first {
-                    text.push('·');
-                }
-                text.push_str("[UNK]");
-                first = false;
+                text.push_str(&self.unk_placeholder);
             }
+            first = false;
         }
         text
     }
@@ -294,7 +1061,7 @@ impl Tokenizer {
             *token_counts.entry(token.clone()).or_insert(0) += 1;
         }
 
-        let reverse_tokens = self.build_reverse_map();
+        let reverse_tokens = self.reverse_map();
 
         // print it nicely formatted
         println!("Token statistics:");
@@ -311,3 +1078,504 @@ impl Default for Tokenizer {
         Self::new(8)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Characters that survive `normalize` with the default config
+    const NORMALIZED_ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz .,!";
+
+    fn random_normalized_text(rng: &mut impl rand::Rng, max_len: usize) -> String {
+        let alphabet: Vec<char> = NORMALIZED_ALPHABET.chars().collect();
+        // Skew towards a few letters so merges actually apply
+        let len = rng.random_range(0..=max_len);
+        (0..len)
+            .map(|_| match rng.random_range(0..3) {
+                0 => alphabet[rng.random_range(0..alphabet.len())],
+                _ => alphabet[rng.random_range(0..4)],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fuzz_encode_decode_round_trip() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        for (token_byte_size, code_layout) in [(4, CodeLayout::Fixed), (2, CodeLayout::Fixed), (1, CodeLayout::Fixed), (3, CodeLayout::VariableWidth), (4, CodeLayout::Structured)] {
+            for _ in 0..20 {
+                let mut tokenizer = Tokenizer::new(token_byte_size);
+                tokenizer.code_layout = code_layout;
+                let corpus = format!("{}{}", NORMALIZED_ALPHABET, random_normalized_text(&mut rng, 400));
+                tokenizer.train(&corpus, rng.random_range(30..120));
+
+                for _ in 0..20 {
+                    let text = random_normalized_text(&mut rng, 60);
+                    assert_eq!(tokenizer.decode(&tokenizer.encode_bpe(&text)), text, "{:?}", code_layout);
+                    assert_eq!(tokenizer.decode(&tokenizer.encode_fast_opt(text.clone(), true)), text);
+                }
+                // Other text decodes to its normalized form
+                let text = "Ünïcödé, TABS\tand CAPS!";
+                let normalized: String = tokenizer.normalize(text).into_iter().collect();
+                assert_eq!(tokenizer.decode(&tokenizer.encode_bpe(text)), normalized);
+            }
+        }
+    }
+
+    #[test]
+    fn test_serialized_trie_encodes_identically() {
+        let text = "the quick brown fox jumps over the lazy dog, the end.";
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(text, 40);
+
+        let mut with_trie = tokenizer.clone();
+        with_trie.precompute_trie();
+        let serialized = serde_json::to_string(&with_trie).unwrap();
+        let loaded: Tokenizer = serde_json::from_str(&serialized).unwrap();
+        assert!(loaded.trie.is_some());
+
+        assert_eq!(
+            loaded.encode_fast_opt(text.to_string(), true),
+            tokenizer.encode_fast_opt(text.to_string(), true)
+        );
+    }
+
+    #[test]
+    fn test_serialized_trie_handles_long_tokens() {
+        let long_token = "ab".repeat(200);
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("ab", 2);
+        tokenizer.insert_token(long_token.clone(), vec![7, 7, 7, 7]);
+        tokenizer.precompute_trie();
+
+        // One nesting level per character would exceed serde_json's recursion limit
+        let loaded: Tokenizer = serde_json::from_str(&serde_json::to_string(&tokenizer).unwrap()).unwrap();
+        assert!(loaded.trie.is_some());
+        assert_eq!(loaded.encode_fast_opt(long_token, true), vec![vec![7, 7, 7, 7]]);
+
+        // A trie in the old nested format is dropped and rebuilt
+        let legacy = r#"{"tokens": {"a": [1, 0, 0, 0]}, "merges": [], "vocab_size": 1, "token_byte_size": 4,
+            "trie": {"fingerprint": 1, "root": {"children": {}, "token_code": null}}}"#;
+        let legacy: Tokenizer = serde_json::from_str(legacy).unwrap();
+        assert!(legacy.trie.is_none());
+        assert_eq!(legacy.encode_fast_opt("a".to_string(), true), vec![vec![1, 0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_decode_with_separator() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("ab ab", 3);
+        let tokens = tokenizer.encode_fast_opt("ab a".to_string(), true);
+
+        assert_eq!(tokenizer.decode_with_separator(&tokens, "|"), "a|b| |a");
+        assert_eq!(tokenizer.decode_with_delimiters(&tokens), "a·b· ·a");
+        assert_eq!(tokenizer.decode_with_separator(&tokens, ""), tokenizer.decode(&tokens));
+    }
+
+    #[test]
+    fn test_decode_partial_marks_pending_tokens() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("ab ab", 3);
+        let tokens = tokenizer.encode_fast_opt("ab a".to_string(), true);
+
+        assert_eq!(tokenizer.decode_partial(&tokens, 2, PendingStyle::Bracketed), "ab⟦ a⟧");
+        assert_eq!(tokenizer.decode_partial(&tokens, 2, PendingStyle::Dim), "ab\x1b[2m a\x1b[0m");
+        assert_eq!(tokenizer.decode_partial(&tokens, 2, PendingStyle::Plain), tokenizer.decode(&tokens));
+        // Nothing pending, nothing marked
+        assert_eq!(tokenizer.decode_partial(&tokens, 10, PendingStyle::Dim), "ab a");
+        assert_eq!(tokenizer.decode_partial(&tokens, 0, PendingStyle::Bracketed), "⟦ab a⟧");
+    }
+
+    #[test]
+    fn test_custom_unk_placeholder() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("ab", 2);
+        let mut tokens = tokenizer.encode_fast_opt("ab".to_string(), true);
+        tokens.insert(1, vec![0, 0, 0, 0]);
+
+        assert_eq!(tokenizer.decode(&tokens), "a[UNK]b");
+        tokenizer.unk_placeholder = String::from("?");
+        assert_eq!(tokenizer.decode(&tokens), "a?b");
+        assert_eq!(tokenizer.decode_with_separator(&tokens, "|"), "a|?|b");
+    }
+
+    #[test]
+    fn test_long_runs_encode_linearly() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("aaaaaaaa b", 6);
+        let max_len = tokenizer.max_token_len();
+        assert_eq!(max_len, tokenizer.longest_token_len());
+
+        let encode_counted = |n: usize| {
+            MATCH_STEPS.with(|steps| steps.set(0));
+            let tokens = tokenizer.encode_fast_opt("a".repeat(n), true);
+            (tokens, MATCH_STEPS.with(|steps| steps.get()))
+        };
+        let (short_tokens, short_steps) = encode_counted(20_000);
+        let (long_tokens, long_steps) = encode_counted(80_000);
+
+        assert_eq!(tokenizer.decode(&short_tokens), "a".repeat(20_000));
+        assert_eq!(tokenizer.decode(&long_tokens), "a".repeat(80_000));
+        // Every character is looked at no more than once per token length
+        assert!(long_steps <= 80_000 * max_len);
+        assert!(long_steps <= 4 * short_steps + max_len);
+    }
+
+    #[test]
+    fn test_max_token_chars_caps_learned_tokens() {
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(20);
+        let mut tokenizer = Tokenizer::new(4).with_max_token_chars(3);
+        tokenizer.train(&text, 60);
+
+        assert!(tokenizer.tokens.len() > 30);
+        assert!(tokenizer.tokens.keys().all(|token| token.chars().count() <= 3));
+        assert!(tokenizer.max_token_len() <= 3);
+
+        let tokens = tokenizer.encode_fast_opt(text.clone(), true);
+        assert_eq!(tokenizer.decode(&tokens), text);
+
+        // A cap set after training doesn't cut matches of the longer tokens
+        let mut uncapped = Tokenizer::new(4);
+        uncapped.train(&text, 60);
+        let expected = uncapped.encode_fast_opt(text.clone(), true);
+        let capped = uncapped.with_max_token_chars(2);
+        assert!(capped.max_token_len() > 2);
+        assert_eq!(capped.encode_fast_opt(text, true), expected);
+    }
+
+    #[test]
+    fn test_pretokenized_file() {
+        let path = std::env::temp_dir().join(format!("chatclm-pretokenized-{}.txt", std::process::id()));
+        std::fs::write(&path, "3 1 4\n1 5\n\n9 2 6").unwrap();
+        let tokenizer = Tokenizer::for_token_ids(10, 2).unwrap();
+
+        let tokens = tokenizer.encode_token_ids(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected: Vec<Token> = [3u8, 1, 4, 1, 5, 9, 2, 6].iter().map(|&id| vec![id, 0]).collect();
+        assert_eq!(tokens, expected);
+        assert_eq!(tokenizer.decode_with_separator(&tokens, " "), "3 1 4 1 5 9 2 6");
+
+        assert!(matches!(tokenizer.encode_token_ids("3 10"), Err(ClmError::Config(_))));
+        assert!(Tokenizer::for_token_ids(257, 1).is_err());
+    }
+
+    #[test]
+    fn test_token_codes_are_stable() {
+        let tokenizer = Tokenizer::new(5);
+        // The first five little-endian bytes of FNV-1a 64 of "the", 0x56f5c9194461d57c
+        assert_eq!(tokenizer.compute_token_code("the", 5), vec![0x7c, 0xd5, 0x61, 0x44, 0x19]);
+
+        let legacy: Tokenizer = serde_json::from_str(
+            r#"{"tokens": {}, "merges": [], "vocab_size": 0, "token_byte_size": 5}"#,
+        )
+        .unwrap();
+        assert_eq!(legacy.hash_scheme, HashScheme::DefaultHasher);
+    }
+
+    #[test]
+    fn test_train_chunk_size() {
+        let merges = |train_chunk_size| {
+            let mut tokenizer = Tokenizer::new(4);
+            tokenizer.normalization.train_chunk_size = train_chunk_size;
+            tokenizer.train("xababab", 4);
+            tokenizer.merges
+        };
+        // Chunks "xa", "ba", "ba", "b" only see "b"+"a" twice, the whole text has "a"+"b" three times
+        assert_eq!(merges(2), vec![("b".to_string(), "a".to_string())]);
+        assert_eq!(merges(usize::MAX), vec![("a".to_string(), "b".to_string())]);
+        // Single characters have no pairs at all
+        assert!(merges(1).is_empty());
+    }
+
+    #[test]
+    fn test_active_vocab() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("the cat sat on the mat, the dog ran far away", 30);
+        let corpus = "the cat sat on the mat";
+        let active = tokenizer.active_vocab(corpus);
+
+        let all_tokens: HashSet<&Token> = tokenizer.get_tokens().iter().collect();
+        assert!(active.iter().all(|token| all_tokens.contains(token)));
+        assert!(active.len() < all_tokens.len());
+        assert!(!active.contains(&tokenizer.tokens["d"]));
+        assert_eq!(active, tokenizer.encode_fast_opt(corpus.to_string(), true).into_iter().collect());
+        assert!(tokenizer.active_vocab("").is_empty());
+    }
+
+    #[test]
+    fn test_space_prefix_merges_leading_spaces() {
+        let text = "a cat saw the dog and the bird saw the cat on the mat ".repeat(20);
+
+        let mut prefixed = Tokenizer::new(4);
+        prefixed.normalization.space_prefix = true;
+        prefixed.train(&text, 40);
+        assert_eq!(prefixed.encode_bpe(" the").len(), 1);
+        assert_eq!(prefixed.decode(&prefixed.encode_bpe(" the")), " the");
+        // Whitespace only ever starts a token
+        assert!(prefixed.tokens.keys().all(|token| !token.trim_start().contains(' ')));
+        assert_eq!(prefixed.decode(&prefixed.encode_bpe(&text)), text);
+
+        let mut plain = Tokenizer::new(4);
+        plain.train(&text, 40);
+        assert!(plain.encode_bpe(" the").len() > 1);
+    }
+
+    #[test]
+    fn test_whitespace_modes() {
+        let text = "if x\n    then y\n    else z\n";
+
+        let mut preserving = Tokenizer::new(4);
+        preserving.normalization = NormalizationConfig {
+            whitespace: WhitespaceMode::Preserve,
+            merge_across_spaces: true,
+            ..NormalizationConfig::default()
+        };
+        preserving.train(&text.repeat(10), 16);
+        let tokens = preserving.encode_fast_opt(text.to_string(), true);
+        assert_eq!(preserving.decode(&tokens), text);
+        assert!(preserving.tokens.keys().any(|token| token.contains("  ")));
+
+        let mut collapsing = Tokenizer::new(4);
+        collapsing.normalization.whitespace = WhitespaceMode::Collapse;
+        assert_eq!(
+            collapsing.normalize(text).into_iter().collect::<String>(),
+            "if x then y else z "
+        );
+
+        let default = Tokenizer::new(4);
+        assert_eq!(
+            default.normalize(text).into_iter().collect::<String>(),
+            "if x    then y    else z"
+        );
+    }
+
+    #[test]
+    fn test_newline_boundary_token() {
+        let text = "first line\n\nsecond line\r\nthird line\n";
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.normalization.newline_boundary = true;
+        tokenizer.normalization.whitespace = WhitespaceMode::Collapse;
+        tokenizer.train(&text.repeat(10), 20);
+
+        let boundary = tokenizer.tokens[BOUNDARY_TOKEN].clone();
+        let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+        assert_eq!(tokens.iter().filter(|token| **token == boundary).count(), 3);
+        assert_eq!(tokenizer.decode(&tokens), "first line\nsecond line\nthird line\n");
+        assert!(tokenizer.tokens.keys().all(|token| token == BOUNDARY_TOKEN || !token.contains('\n')));
+    }
+
+    #[test]
+    fn test_chars_per_token() {
+        let mut tokenizer = Tokenizer::new(4);
+        for token in ["x", "y", "z", " ", "xy"] {
+            tokenizer.insert_token(token.to_string(), tokenizer.compute_token_code(token, 4));
+        }
+        // "xy", "z", " ", "xy" cover 6 characters
+        assert_eq!(tokenizer.chars_per_token("xyz xy"), 1.5);
+        assert_eq!(tokenizer.chars_per_token(""), 0.0);
+    }
+
+    #[test]
+    fn test_tokenization_agreement() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("abab", 4);
+        // Merges "a"+"b", then "ab"+"ab"
+        assert_eq!(tokenizer.encode_bpe("abab"), tokenizer.encode_fast("abab".to_string()));
+        assert_eq!(tokenizer.tokenization_agreement("abab"), 1.0);
+
+        // Greedy longest match splits "xyz" as "xy", "z" while the merges are "y"+"z", then "x"+"yz"
+        let mut tokenizer = Tokenizer::new(4);
+        for token in ["x", "y", "z", " ", "xy", "yz"] {
+            tokenizer.insert_token(token.to_string(), tokenizer.compute_token_code(token, 4));
+        }
+        tokenizer.merges = vec![("y".to_string(), "z".to_string()), ("x".to_string(), "y".to_string())];
+
+        let bpe = tokenizer.encode_bpe("xyz");
+        assert_eq!(tokenizer.decode_with_separator(&bpe, "|"), "x|yz");
+        // Only the space is split the same way
+        assert_eq!(tokenizer.tokenization_agreement("xyz xyz"), 0.2);
+    }
+
+    #[test]
+    fn test_reverse_map_follows_tokens() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("abab", 3);
+        let code = tokenizer.tokens["ab"].clone();
+        assert_eq!(tokenizer.reverse_map()[&code], "ab");
+
+        let tokens = tokenizer.encode_fast_opt("ab".to_string(), true);
+        assert_eq!(tokenizer.decode(&tokens), "ab");
+
+        tokenizer.remove_token("ab");
+        assert!(!tokenizer.reverse_map().contains_key(&code));
+        assert_eq!(tokenizer.decode(&tokens), "[UNK]");
+        tokenizer.insert_token("ba".to_string(), code);
+        assert_eq!(tokenizer.decode(&tokens), "ba");
+    }
+
+    #[test]
+    fn test_token_cache_is_stable() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("the cat and the hat", 12);
+
+        let first = tokenizer.get_tokens();
+        let second = tokenizer.get_tokens();
+        assert_eq!(first, second);
+        assert!(std::ptr::eq(first, second));
+
+        let str_tokens = tokenizer.get_str_tokens();
+        assert!(str_tokens.windows(2).all(|pair| pair[0] < pair[1]));
+        for (content, code) in str_tokens.iter().zip(first) {
+            assert_eq!(&tokenizer.tokens[content], code);
+        }
+
+        // Retraining or changing a token rebuilds the cache
+        tokenizer.train("xyz", 3);
+        assert_eq!(tokenizer.get_tokens().len(), 3);
+        tokenizer.insert_token("xy".to_string(), vec![1, 2, 3, 4]);
+        assert_eq!(tokenizer.get_tokens().len(), 4);
+        assert!(tokenizer.get_tokens().contains(&vec![1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_prune_removes_rare_tokens() {
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train("the cat sat on the mat with the hat, xylophone zebra quiz", 40);
+        let vocab_before = tokenizer.tokens.len();
+
+        let corpus = "the cat at the hat, the mat";
+        let report = tokenizer.prune(2, corpus);
+
+        assert!(report.removed > 0);
+        assert_eq!(report.removed + report.remaining, vocab_before);
+        assert_eq!(tokenizer.tokens.len(), report.remaining);
+        for token in &report.removed_tokens {
+            assert!(!tokenizer.tokens.contains_key(token));
+            assert!(token.chars().count() > 1);
+        }
+        for (first, second) in &tokenizer.merges {
+            assert!(tokenizer.tokens.contains_key(&format!("{}{}", first, second)));
+        }
+
+        // All characters are still covered
+        let encoded = tokenizer.encode_fast_opt(corpus.to_string(), true);
+        assert_eq!(tokenizer.decode(&encoded), corpus);
+    }
+
+    #[test]
+    fn test_variable_width_codes_are_uniquely_decodable() {
+        let text = "the cat sat on the mat, the hat sat on the cat.";
+        let mut tokenizer = Tokenizer::new(3);
+        tokenizer.code_layout = CodeLayout::VariableWidth;
+        tokenizer.train(text, 30);
+
+        for (content, code) in &tokenizer.tokens {
+            assert_eq!(code.len(), 3 + content.len() - 1);
+            assert_eq!(code[0] as usize, code.len());
+        }
+
+        let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+        let bytes: Vec<u8> = tokens.iter().flatten().copied().collect();
+        assert_eq!(tokenizer.split_codes(&bytes), tokens);
+        assert_eq!(tokenizer.decode(&tokens), text);
+    }
+
+    #[test]
+    fn test_structured_codes_share_prefixes() {
+        let text = "sing a song, sing along, the singing king sings";
+        let mut tokenizer = Tokenizer::new(6);
+        tokenizer.code_layout = CodeLayout::Structured;
+        tokenizer.train(text, 40);
+
+        let code = |content: &str| tokenizer.tokens[content].clone();
+        assert!(tokenizer.merges.iter().any(|(first, _)| first == "s"));
+        for (first, second) in &tokenizer.merges {
+            let merged = code(&format!("{}{}", first, second));
+            assert_eq!(merged.len(), 6);
+            assert_eq!(merged[..3], code(first)[..3]);
+        }
+        let codes: std::collections::HashSet<&Token> = tokenizer.tokens.values().collect();
+        assert_eq!(codes.len(), tokenizer.tokens.len());
+
+        let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+        let bytes: Vec<u8> = tokens.iter().flatten().copied().collect();
+        assert_eq!(tokenizer.split_codes(&bytes), tokens);
+        assert_eq!(tokenizer.decode(&tokens), text);
+    }
+
+    #[test]
+    fn test_codes_avoid_reserved_byte() {
+        let text = "sing a song, sing along, the singing king sings";
+        for layout in [CodeLayout::Fixed, CodeLayout::VariableWidth, CodeLayout::Structured] {
+            let mut plain = Tokenizer::new(4);
+            plain.code_layout = layout;
+            plain.train(text, 40);
+            // Any byte the codes use without the reservation
+            let reserved = *plain.tokens.values().flatten().next().unwrap();
+
+            let mut tokenizer = Tokenizer::new(4);
+            tokenizer.code_layout = layout;
+            tokenizer.reserved_byte = Some(reserved);
+            tokenizer.train(text, 40);
+            assert!(tokenizer.tokens.values().all(|code| !code.contains(&reserved)), "{:?}", layout);
+
+            let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+            let bytes: Vec<u8> = tokens.iter().flatten().copied().collect();
+            assert_eq!(tokenizer.split_codes(&bytes), tokens);
+            assert_eq!(tokenizer.decode(&tokens), text);
+        }
+    }
+
+    #[test]
+    fn test_encode_into_reuses_buffer() {
+        let text = "the cat sat on the mat";
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(text, 20);
+
+        let mut buffer = Vec::new();
+        tokenizer.encode_into(text, &mut buffer);
+        assert_eq!(buffer, tokenizer.encode_fast(text.to_string()));
+
+        // Encoding the same text again writes into the existing codes
+        let pointers: Vec<*const u8> = buffer.iter().map(|code| code.as_ptr()).collect();
+        let outer = buffer.as_ptr();
+        tokenizer.encode_into(text, &mut buffer);
+        assert_eq!(buffer.as_ptr(), outer);
+        assert_eq!(buffer.iter().map(|code| code.as_ptr()).collect::<Vec<_>>(), pointers);
+
+        tokenizer.encode_into("mat", &mut buffer);
+        assert_eq!(buffer, tokenizer.encode_fast("mat".to_string()));
+    }
+
+    #[test]
+    fn test_token_id_dump_round_trip() {
+        let text = "the cat sat on the mat";
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(text, 20);
+        let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+
+        let path = std::env::temp_dir().join(format!("chatclm-token-ids-{}.txt", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        tokenizer.write_token_ids(&tokens, &mut file).unwrap();
+        drop(file);
+        let dumped = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(dumped.split_whitespace().count(), tokens.len());
+        assert_eq!(tokenizer.read_token_ids(&dumped).unwrap(), tokens);
+        assert!(tokenizer.read_token_ids("100000").is_err());
+    }
+
+    #[test]
+    fn test_stale_trie_is_rebuilt() {
+        let text = "abab abab";
+        let mut tokenizer = Tokenizer::new(4);
+        tokenizer.train(text, 4);
+        tokenizer.precompute_trie();
+
+        // Changing the vocabulary invalidates the stored trie
+        tokenizer.insert_token("abab".to_string(), vec![9, 9, 9, 9]);
+        let tokens = tokenizer.encode_fast_opt(text.to_string(), true);
+        assert_eq!(tokens[0], vec![9, 9, 9, 9]);
+    }
+}
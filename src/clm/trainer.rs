@@ -1,30 +1,132 @@
+use crate::clm::clm_model::flatten_tokens;
+use crate::clm::error::ClmError;
 use crate::clm::tokenizer::Token;
-use crate::clm::training_options::TrainingOptions;
+use crate::clm::training_options::{SampleStrategy, TrainingOptions};
 use itertools::Itertools;
 use std::ffi::{c_uint, c_void};
 use zstd_sys::{ZDICT_isError, ZDICT_optimizeTrainFromBuffer_fastCover};
 
-pub fn train_model(input_tokens: &[Token], training_options: &TrainingOptions) -> Vec<u8> {
-    if input_tokens.is_empty() {
-        panic!("Input tokens are empty");
+/// zstd's fastCover trainer rejects fewer samples than this
+const ZSTD_MIN_SAMPLES: usize = 5;
+
+/// The configured `training_chunk_size`, shrunk if needed so the tokens still split into
+/// `min_training_chunks` chunks. Fewer tokens than that can't be split far enough.
+fn training_chunk_size(token_count: usize, training_options: &TrainingOptions) -> usize {
+    let configured = training_options.training_chunk_size.max(1);
+    if token_count.div_ceil(configured) >= training_options.min_training_chunks {
+        return configured;
     }
+    let adjusted = (token_count / training_options.min_training_chunks).max(1);
+    log::info!(
+        "Reducing training chunk size from {} to {} to get {} chunks",
+        configured, adjusted, training_options.min_training_chunks
+    );
+    adjusted
+}
 
-    let chunks = input_tokens
-        .chunks(training_options.training_chunk_size)
-        .map(|chunk| chunk.iter().flatten().copied().collect_vec())
-        .collect_vec();
+/// The samples zstd trains a dictionary on, concatenated
+struct TrainingSamples {
+    sizes: Vec<usize>,
+    raw_data: Vec<u8>,
+    /// Bytes of the flattened tokens, less than `raw_data` if samples overlap
+    corpus_bytes: usize,
+}
+
+fn training_samples(input_tokens: &[Token], training_options: &TrainingOptions) -> Result<TrainingSamples, ClmError> {
+    match training_options.sample_strategy {
+        SampleStrategy::TokenChunks => {
+            let chunk_size = training_chunk_size(input_tokens.len(), training_options);
+            let chunks = input_tokens
+                .chunks(chunk_size)
+                .map(|chunk| flatten_tokens(chunk, training_options.token_delimiter))
+                .collect_vec();
+            let raw_data = chunks.iter().flat_map(|x| x.iter()).copied().collect_vec();
+            Ok(TrainingSamples {
+                sizes: chunks.iter().map(|x| x.len()).collect_vec(),
+                corpus_bytes: raw_data.len(),
+                raw_data,
+            })
+        }
+        SampleStrategy::ByteWindows { size, stride } => {
+            if size == 0 || stride == 0 {
+                return Err(ClmError::Config(format!(
+                    "byte windows need a positive size and stride, got {} and {}",
+                    size, stride
+                )));
+            }
+            if stride > size {
+                return Err(ClmError::Config(format!(
+                    "byte window stride {} is larger than the window size {}, the bytes in between would be skipped",
+                    stride, size
+                )));
+            }
+            let bytes = flatten_tokens(input_tokens, training_options.token_delimiter);
+            let mut starts = (0..=bytes.len().saturating_sub(size)).step_by(stride).collect_vec();
+            // A last window ending at the last byte, so the tail is sampled too
+            let last_start = bytes.len().saturating_sub(size);
+            if starts.last() != Some(&last_start) {
+                starts.push(last_start);
+            }
+            if starts.len() < ZSTD_MIN_SAMPLES {
+                return Err(ClmError::Config(format!(
+                    "{} bytes only fit {} windows of {} bytes, zstd needs at least {} samples",
+                    bytes.len(),
+                    starts.len(),
+                    size,
+                    ZSTD_MIN_SAMPLES
+                )));
+            }
+            let mut sizes = Vec::new();
+            let mut raw_data = Vec::new();
+            for start in starts {
+                let window = &bytes[start..(start + size).min(bytes.len())];
+                sizes.push(window.len());
+                raw_data.extend_from_slice(window);
+            }
+            Ok(TrainingSamples {
+                sizes,
+                raw_data,
+                corpus_bytes: bytes.len(),
+            })
+        }
+    }
+}
 
-    let sizes = chunks.iter().map(|x| x.len()).collect_vec();
+pub fn train_model(input_tokens: &[Token], training_options: &TrainingOptions) -> Result<Vec<u8>, ClmError> {
+    if input_tokens.is_empty() {
+        return Err(ClmError::Config("input tokens are empty".to_string()));
+    }
 
-    let raw_data = chunks.iter().flat_map(|x| x.iter()).copied().collect_vec();
+    if training_options.min_training_chunks < ZSTD_MIN_SAMPLES {
+        return Err(ClmError::Config(format!(
+            "min_training_chunks is {}, zstd needs at least {}",
+            training_options.min_training_chunks, ZSTD_MIN_SAMPLES
+        )));
+    }
+    let TrainingSamples {
+        sizes,
+        raw_data,
+        corpus_bytes,
+    } = training_samples(input_tokens, training_options)?;
 
+    if training_options.min_dictionary_bytes < 256 {
+        return Err(ClmError::Config(format!(
+            "min_dictionary_bytes is {}, zstd needs at least 256",
+            training_options.min_dictionary_bytes
+        )));
+    }
     let buffer_size = std::cmp::max(
-        (raw_data.len() as f64 * training_options.dictionary_size_percentage) as usize,
-        256,
+        (corpus_bytes as f64 * training_options.dictionary_size_percentage) as usize,
+        training_options.min_dictionary_bytes,
     );
 
-    assert!(buffer_size >= 256, "Buffer size is too small");
-    assert!(sizes.len() >= 5, "Not enough chunks to train the model");
+    if sizes.len() < training_options.min_training_chunks {
+        return Err(ClmError::Config(format!(
+            "not enough chunks to train the model: {} (at least {} are needed)",
+            sizes.len(),
+            training_options.min_training_chunks
+        )));
+    }
 
     assert_eq!(
         sizes.iter().sum::<usize>(),
@@ -46,9 +148,119 @@ pub fn train_model(input_tokens: &[Token], training_options: &TrainingOptions) -
         );
 
         if ZDICT_isError(size) != 0 {
-            panic!("Failed to train dictionary");
+            let name = std::ffi::CStr::from_ptr(zstd_sys::ZDICT_getErrorName(size));
+            return Err(ClmError::Zstd(format!(
+                "failed to train dictionary: {}",
+                name.to_string_lossy()
+            )));
         }
     }
     buffer.resize(size, 0);
-    buffer
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_train_model_errors() {
+        let options = TrainingOptions::default();
+        assert!(matches!(train_model(&[], &options), Err(ClmError::Config(_))));
+
+        let tokens: Vec<Token> = vec![b"ab".to_vec(); 3];
+        let options = TrainingOptions {
+            training_chunk_size: 1,
+            ..TrainingOptions::default()
+        };
+        assert!(matches!(train_model(&tokens, &options), Err(ClmError::Config(_))));
+    }
+
+    #[test]
+    fn test_chunk_size_shrinks_for_short_input() {
+        let mut state = 54321u32;
+        let tokens: Vec<Token> = (0..700)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                vec![b'a' + (state >> 16) as u8 % 26]
+            })
+            .collect();
+        // 700 tokens make only 3 chunks of the default 256
+        let options = TrainingOptions {
+            k: 64,
+            nb_threads: 1,
+            ..TrainingOptions::default()
+        };
+        assert_eq!(tokens.len().div_ceil(options.training_chunk_size), 3);
+        assert_eq!(training_chunk_size(tokens.len(), &options), 140);
+        assert!(!train_model(&tokens, &options).unwrap().is_empty());
+
+        let options = TrainingOptions {
+            min_training_chunks: 4,
+            ..options
+        };
+        assert!(matches!(train_model(&tokens, &options), Err(ClmError::Config(_))));
+    }
+
+    #[test]
+    fn test_byte_window_samples() {
+        // 10 tokens of 3 bytes
+        let tokens: Vec<Token> = (0..10u8).map(|i| vec![i; 3]).collect();
+        let options = |size, stride| TrainingOptions {
+            sample_strategy: SampleStrategy::ByteWindows { size, stride },
+            ..TrainingOptions::default()
+        };
+
+        let samples = training_samples(&tokens, &options(8, 4)).unwrap();
+        // Windows start at 0, 4, ..., 20, and a last one at 22 ends at the last byte
+        assert_eq!(samples.sizes, vec![8; 7]);
+        assert_eq!(samples.raw_data.len(), 56);
+        assert_eq!(samples.corpus_bytes, 30);
+        // The second window starts at byte 4
+        assert_eq!(samples.raw_data[8..16], [1, 1, 2, 2, 2, 3, 3, 3]);
+        assert_eq!(samples.raw_data[48..], flatten_tokens(&tokens, None)[22..]);
+
+        let samples = training_samples(&tokens, &options(6, 6)).unwrap();
+        assert_eq!(samples.sizes, vec![6; 5]);
+        assert_eq!(samples.raw_data, flatten_tokens(&tokens, None));
+
+        // Too few windows for zstd, or bytes between the windows skipped
+        assert!(matches!(training_samples(&tokens, &options(10, 10)), Err(ClmError::Config(_))));
+        assert!(matches!(training_samples(&tokens, &options(64, 4)), Err(ClmError::Config(_))));
+        assert!(matches!(training_samples(&tokens, &options(4, 6)), Err(ClmError::Config(_))));
+        assert!(matches!(training_samples(&tokens, &options(8, 0)), Err(ClmError::Config(_))));
+
+        let chunks = training_samples(&tokens, &TrainingOptions::default()).unwrap();
+        assert_eq!(chunks.sizes.iter().sum::<usize>(), 30);
+    }
+
+    #[test]
+    fn test_min_dictionary_bytes_floor() {
+        // Varied pseudo-random bytes, so the trainer has enough distinct segments to fill the buffer
+        let mut state = 12345u32;
+        let tokens: Vec<Token> = (0..60_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                vec![b'a' + (state >> 16) as u8 % 26]
+            })
+            .collect();
+        let options = TrainingOptions {
+            training_chunk_size: 512,
+            dictionary_size_percentage: 0.0,
+            min_dictionary_bytes: 4096,
+            shrink_dict: 0,
+            steps: 4,
+            k: 200,
+            nb_threads: 1,
+            ..TrainingOptions::default()
+        };
+        let dictionary = train_model(&tokens, &options).unwrap();
+        assert!(dictionary.len() >= 4096, "dictionary has {} bytes", dictionary.len());
+
+        let options = TrainingOptions {
+            min_dictionary_bytes: 100,
+            ..options
+        };
+        assert!(matches!(train_model(&tokens, &options), Err(ClmError::Config(_))));
+    }
 }
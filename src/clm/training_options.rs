@@ -2,6 +2,93 @@ use std::ffi::c_int;
 
 use serde::{Deserialize, Serialize};
 
+use crate::clm::error::ClmError;
+use crate::clm::inference::Sampler;
+use crate::clm::tokenizer::CodeLayout;
+use crate::clm::ValidationError;
+
+/// How the regularization floor is added to the predicted distribution
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RegularizationMode {
+    /// Return the softmax of the size deltas as is
+    None,
+    /// Add `regularization / vocab size` to every likelihood
+    #[default]
+    UniformFloor,
+    /// Add `regularization` times the token's frequency in the training data
+    UnigramPrior,
+}
+
+/// How `ClmModel` turns compressed sizes into a token's score
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InferenceMode {
+    /// Bytes the token adds to the compressed context, i.e. -log p(token | context)
+    #[default]
+    MarginalSize,
+    /// Bytes the token adds to the compressed context minus the bytes it compresses to
+    /// alone, combined with its training frequency, see `ClmModel::context_log_likelihoods`
+    Predictive,
+}
+
+/// What `BigramModel` predicts after a token it never saw followed by anything
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FallbackKind {
+    /// Every token is equally likely
+    #[default]
+    Uniform,
+    /// Back off to the smoothed token frequencies in the training data
+    Unigram,
+}
+
+/// What `context_window` counts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContextWindowUnit {
+    /// The context is the last `context_window` tokens
+    #[default]
+    Tokens,
+    /// The context is the longest run of last tokens whose codes fit in `context_window` bytes,
+    /// which keeps contexts comparable across token sizes
+    Bytes,
+}
+
+/// How the tokens of one dictionary are cut into the samples zstd trains on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SampleStrategy {
+    /// Consecutive chunks of `training_chunk_size` tokens
+    #[default]
+    TokenChunks,
+    /// Windows of `size` bytes over the flattened tokens, one starting every `stride` bytes,
+    /// so they overlap if the stride is smaller than the size, plus one ending at the last byte.
+    /// The stride may not exceed the size, and at least 5 windows must fit.
+    ByteWindows { size: usize, stride: usize },
+}
+
+/// Largest corpus, in bytes, trained at each level by `auto_compression_level`. Both fastCover
+/// and creating the compression dictionaries get much slower at the highest levels.
+const AUTO_COMPRESSION_LEVELS: [(usize, i32); 3] = [(16 << 20, 21), (128 << 20, 19), (1 << 30, 9)];
+
+/// Level of corpora larger than all of `AUTO_COMPRESSION_LEVELS`
+const AUTO_COMPRESSION_LEVEL_LARGE: i32 = 3;
+
+/// The compression level `auto_compression_level` trains a corpus of `corpus_bytes` at,
+/// lower for larger corpora to keep training tractable
+pub fn auto_compression_level(corpus_bytes: usize) -> i32 {
+    AUTO_COMPRESSION_LEVELS
+        .iter()
+        .find(|(max_bytes, _)| corpus_bytes <= *max_bytes)
+        .map_or(AUTO_COMPRESSION_LEVEL_LARGE, |(_, level)| *level)
+}
+
+/// How text files with invalid UTF-8 are read
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Utf8Handling {
+    /// Fail with an error naming the file
+    #[default]
+    Strict,
+    /// Replace invalid bytes with U+FFFD and log how many were replaced
+    Lossy,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrainingOptions {
     pub d: u32,
@@ -9,33 +96,165 @@ pub struct TrainingOptions {
     pub k: u32,
     pub steps: u32,
     pub nb_threads: u32,
+    #[serde(default)]
+    pub thread_budget: Option<usize>, // total training threads, split between parallel chunks and nb_threads
     pub split_point: f64,
     pub accel: u32,
     pub shrink_dict: u32,
     pub shrink_dict_max_regression: u32,
     pub train_compression_level: i32,
+    #[serde(default)]
+    pub auto_compression_level: bool, // pick train_compression_level from the corpus size, see auto_compression_level()
+    #[serde(default)]
+    pub inference_compression_level: Option<i32>, // level the dictionaries are compiled at for inference, train_compression_level if None
     pub dictionary_size_percentage: f64, // 0.0 to 1.0, how big the dictionary should be compared to the input data
+    #[serde(default)]
+    pub tune_dictionary_size: bool, // pick dictionary_size_percentage on held-out tokens before training
+    #[serde(default = "default_min_dictionary_bytes")]
+    pub min_dictionary_bytes: usize, // lower bound for the dictionary buffer, zstd needs at least 256
     pub ensemble_size: usize,            // number of models to train
+    #[serde(default)]
+    pub train_time_budget_secs: Option<f64>, // stop dispatching new waves of dictionaries after this many seconds
+    #[serde(default)]
+    pub dedup_chunks: bool,         // train only one dictionary for repeated ensemble chunks, even shifted or edited
     pub training_chunk_size: usize, // how many tokens to put in a chunk for training the dictionary
+    #[serde(default = "default_min_training_chunks")]
+    pub min_training_chunks: usize, // fewest chunks per dictionary, training_chunk_size shrinks to reach it
+    #[serde(default)]
+    pub sample_strategy: SampleStrategy, // how the tokens are cut into samples for zstd's trainer
     pub token_count: usize,         // how many tokens to use
     pub token_byte_size: usize,     // how many bytes to use for each token
+    #[serde(default)]
+    pub max_token_chars: usize,     // longest token the tokenizer may learn in characters, 0 for no limit
+    #[serde(default)]
+    pub code_layout: CodeLayout,    // byte layout of the token codes
     pub context_window: usize,      // how many tokens to look back during prediction
+    #[serde(default)]
+    pub context_window_unit: ContextWindowUnit, // whether context_window counts tokens or bytes
     pub dataset_percentage: f64,    // how much of the dataset to use for training
     pub regularization: f64,     // how much to regularize the model
+    #[serde(default)]
+    pub regularization_mode: RegularizationMode, // how the regularization is distributed over the tokens
+    #[serde(default = "default_prior_weight")]
+    pub prior_weight: f64,       // how strongly a prior injected with Model::with_prior is blended in
+    #[serde(default = "default_likelihood_clamp_epsilon")]
+    pub likelihood_clamp_epsilon: f32, // negative likelihoods down to -epsilon are rounding errors and set to 0
+    #[serde(default)]
+    pub bigram_fallback: FallbackKind, // what the bigram model predicts after an unseen token
+    #[serde(default = "default_interpolation_lambda")]
+    pub interpolation_lambda: f64, // weight of the bigram in the interpolated bigram+unigram model
+    #[serde(default)]
+    pub fit_interpolation_lambda: bool, // fit interpolation_lambda on held-out training tokens instead
     pub model_id: Option<String>, // model id for the model
     pub training_file: String,   // file to use for training
+    #[serde(default)]
+    pub training_files: Vec<String>, // files concatenated for training, replaces training_file if set
     pub test_file: String,       // file to use for testing
+    #[serde(default)]
+    pub utf8_handling: Utf8Handling, // whether invalid UTF-8 in the training and test files is an error
+    #[serde(default)]
+    pub pretokenized: bool,      // training and test files hold whitespace-separated token ids instead of text
     pub inference_basis: f64,    // basis in probability space for inference
+    #[serde(default)]
+    pub inference_mode: InferenceMode, // how compressed sizes become a token's score
+    #[serde(default)]
+    pub serialize_trie: bool,    // store the tokenizer's encoding trie with the saved run
+    #[serde(default)]
+    pub deterministic: bool,     // train single-threaded so repeated runs yield identical dictionaries
+    #[serde(default)]
+    pub adaptive_ensemble: bool, // weight each dictionary by how well it compresses the context
+    #[serde(default)]
+    pub adaptive_context_lengths: Vec<usize>, // context lengths in tokens blended by how well each context compresses, empty for the fixed context_window
+    #[serde(default)]
+    pub length_normalize: bool,  // divide each token's size delta by the length of its text in characters
+    #[serde(default)]
+    pub per_dict_normalize: bool, // standardize each dictionary's size deltas before averaging
+    #[serde(default)]
+    pub token_delimiter: Option<u8>, // byte inserted after every token in the compressed text
+    #[serde(default)]
+    pub default_sampler: Sampler, // recommended sampler for generation with its parameter, e.g. top-k:10
+    #[serde(default)]
+    pub default_seed: Option<u64>, // recommended seed, random if none
+    #[serde(default)]
+    pub eval_max_positions: Option<usize>, // score at most this many positions after the warmup
+    #[serde(default = "default_tokenizer_train_bytes")]
+    pub tokenizer_train_bytes: Option<usize>, // bytes of training text sampled for the tokenizer, None for all
+}
+
+fn default_prior_weight() -> f64 {
+    0.5
+}
+
+fn default_likelihood_clamp_epsilon() -> f32 {
+    1e-6
+}
+
+fn default_min_training_chunks() -> usize {
+    5
+}
+
+fn default_interpolation_lambda() -> f64 {
+    0.5
+}
+
+fn default_min_dictionary_bytes() -> usize {
+    256
+}
+
+fn default_tokenizer_train_bytes() -> Option<usize> {
+    Some(50_000)
 }
 
 impl TrainingOptions {
+    /// Rejects options that train or load fine but make every prediction meaningless
+    pub fn validate_inference(&self) -> Result<(), ValidationError> {
+        // likelihoods are inference_basis^-size, so a basis of at most 1 flattens or inverts the ranking
+        if self.inference_basis.is_nan() || self.inference_basis <= 1.0 {
+            return Err(ValidationError::InferenceBasis(self.inference_basis));
+        }
+        if !(0.0..=1.0).contains(&self.prior_weight) {
+            return Err(ValidationError::PriorWeight(self.prior_weight));
+        }
+        Ok(())
+    }
+
+    /// Rejects options a model can't be trained with, including those `validate_inference` rejects
+    pub fn validate(&self) -> Result<(), ClmError> {
+        self.validate_inference()?;
+        if let Some(budget) = self.train_time_budget_secs
+            && std::time::Duration::try_from_secs_f64(budget).is_err()
+        {
+            return Err(ClmError::Config(format!(
+                "train_time_budget_secs must be a non-negative number of seconds but is {}",
+                budget
+            )));
+        }
+        Ok(())
+    }
+
+    /// The level the dictionaries are compiled at for inference
+    pub fn inference_level(&self) -> i32 {
+        self.inference_compression_level.unwrap_or(self.train_compression_level)
+    }
+
+    /// Returns the training files in order, falling back to `training_file`
+    pub fn training_sources(&self) -> Vec<&str> {
+        if self.training_files.is_empty() {
+            vec![self.training_file.as_str()]
+        } else {
+            self.training_files.iter().map(String::as_str).collect()
+        }
+    }
+
     pub fn to_zdict_params(&self) -> zstd_sys::ZDICT_fastCover_params_t {
         zstd_sys::ZDICT_fastCover_params_t {
             k: self.k,
             d: self.d,
             f: self.f,
             steps: self.steps,
-            nbThreads: self.nb_threads,
+            // fastCover splits its work across threads, which can change the
+            // resulting dictionary between runs
+            nbThreads: if self.deterministic { 1 } else { self.nb_threads },
             splitPoint: self.split_point,
             accel: self.accel,
             shrinkDict: self.shrink_dict,
@@ -57,23 +276,56 @@ impl Default for TrainingOptions {
             k: 6078,
             steps: 0,
             nb_threads: 12,
+            thread_budget: None,
             split_point: 1.0,
             accel: 1,
             shrink_dict: 1,
             shrink_dict_max_regression: 3,
             train_compression_level: 21,
+            auto_compression_level: false,
+            inference_compression_level: None,
             dictionary_size_percentage: 0.08,
+            tune_dictionary_size: false,
+            min_dictionary_bytes: default_min_dictionary_bytes(),
             ensemble_size: 15,
+            train_time_budget_secs: None,
+            dedup_chunks: false,
             training_chunk_size: 256,
+            min_training_chunks: default_min_training_chunks(),
+            sample_strategy: SampleStrategy::default(),
             token_count: 210,
             token_byte_size: 5,
+            max_token_chars: 0,
+            code_layout: CodeLayout::default(),
             context_window: 32,
+            context_window_unit: ContextWindowUnit::default(),
             dataset_percentage: 1.0,
             regularization: 0.0,
+            regularization_mode: RegularizationMode::default(),
+            prior_weight: default_prior_weight(),
+            likelihood_clamp_epsilon: default_likelihood_clamp_epsilon(),
+            bigram_fallback: FallbackKind::default(),
+            interpolation_lambda: default_interpolation_lambda(),
+            fit_interpolation_lambda: false,
             model_id: Some(String::from("enwik9_token_size_6")),
             training_file: String::from("data/enwik9"),
+            training_files: Vec::new(),
             test_file: String::from("test.txt"),
+            utf8_handling: Utf8Handling::default(),
+            pretokenized: false,
             inference_basis: 1.55,
+            inference_mode: InferenceMode::default(),
+            serialize_trie: false,
+            deterministic: false,
+            adaptive_ensemble: false,
+            adaptive_context_lengths: Vec::new(),
+            length_normalize: false,
+            per_dict_normalize: false,
+            token_delimiter: None,
+            default_sampler: Sampler::default(),
+            default_seed: None,
+            eval_max_positions: None,
+            tokenizer_train_bytes: default_tokenizer_train_bytes(),
         }
     }
 }
@@ -2,15 +2,27 @@ use crate::clm::tokenizer::Token;
 use crate::clm::training_options::TrainingOptions;
 use std::collections::HashMap;
 
-use crate::clm::clm_model::Model;
+use crate::clm::clm_model::{blend_prior, Model, MIN_EVAL_WARMUP};
+use crate::clm::error::ClmError;
 
 /// A model that returns a uniform distribution over all tokens
-pub struct UniformModel;
+pub struct UniformModel {
+    /// Prior injected with `Model::with_prior`, empty if none
+    prior: HashMap<Token, f32>,
+    prior_weight: f32,
+    /// `TrainingOptions::likelihood_clamp_epsilon`
+    clamp_epsilon: f32,
+}
 
 impl Model for UniformModel {
     /// Creates a new uniform model (ignores training data)
-    fn train(_tokens: Vec<Token>, _options: TrainingOptions) -> Self {
-        UniformModel
+    fn train(_tokens: Vec<Token>, options: TrainingOptions) -> Result<Self, ClmError> {
+        options.validate_inference()?;
+        Ok(UniformModel {
+            prior: HashMap::new(),
+            prior_weight: options.prior_weight as f32,
+            clamp_epsilon: options.likelihood_clamp_epsilon,
+        })
     }
 
     /// Returns equal probability for all tokens
@@ -18,20 +30,42 @@ impl Model for UniformModel {
         &self,
         _current_text: Vec<Token>,
         all_tokens: &[Token],
-    ) -> HashMap<Token, f32> {
+    ) -> Result<HashMap<Token, f32>, ClmError> {
+        if all_tokens.is_empty() {
+            return Ok(HashMap::new());
+        }
         let uniform_probability = 1.0 / all_tokens.len() as f32;
 
         // Assign the same probability to every token
-        all_tokens
+        let mut likelihoods = all_tokens
             .iter()
             .map(|token| (token.clone(), uniform_probability))
-            .collect()
+            .collect();
+        blend_prior(&mut likelihoods, &self.prior, self.prior_weight, self.clamp_epsilon);
+        Ok(likelihoods)
+    }
+
+    fn memory_footprint(&self) -> usize {
+        std::mem::size_of::<Self>() + self.prior.capacity() * std::mem::size_of::<(Token, f32)>()
+    }
+
+    fn name(&self) -> String {
+        String::from("uniform")
+    }
+
+    fn recommended_warmup(&self) -> usize {
+        MIN_EVAL_WARMUP
+    }
+
+    fn with_prior(&mut self, prior: HashMap<Token, f32>) {
+        self.prior = prior;
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clm::ValidationError;
 
     #[test]
     fn test_uniform_distribution() {
@@ -39,10 +73,10 @@ mod tests {
         let tokens: Vec<Token> = vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]];
 
         // Create the model
-        let model = UniformModel::train(vec![], TrainingOptions::default());
+        let model = UniformModel::train(vec![], TrainingOptions::default()).unwrap();
 
         // Get likelihoods
-        let likelihoods = model.compute_likelihoods(vec![], &tokens);
+        let likelihoods = model.compute_likelihoods(vec![], &tokens).unwrap();
 
         // Check that we have the right number of tokens
         assert_eq!(likelihoods.len(), tokens.len());
@@ -57,4 +91,54 @@ mod tests {
         let sum: f32 = likelihoods.values().sum();
         assert!((sum - 1.0).abs() < 1e-6);
     }
+
+    #[test]
+    fn test_empty_vocabulary_gives_empty_distribution() {
+        let model = UniformModel::train(vec![], TrainingOptions::default()).unwrap();
+        assert!(model.compute_likelihoods(vec![vec![1]], &[]).unwrap().is_empty());
+        assert_eq!(model.name(), "uniform");
+    }
+
+    #[test]
+    fn test_batch_likelihoods_match_single() {
+        let tokens: Vec<Token> = vec![vec![1], vec![2], vec![3]];
+        let model = UniformModel::train(vec![], TrainingOptions::default()).unwrap();
+        let contexts = vec![vec![], vec![vec![1]], vec![vec![2], vec![3]]];
+
+        let batch = model.compute_likelihoods_batch(&contexts, &tokens).unwrap();
+        assert_eq!(batch.len(), contexts.len());
+        for (context, likelihoods) in contexts.iter().zip(batch) {
+            assert_eq!(likelihoods, model.compute_likelihoods(context.clone(), &tokens).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_prior_shifts_distribution() {
+        let tokens: Vec<Token> = vec![vec![1], vec![2], vec![3], vec![4]];
+        let mut model = UniformModel::train(vec![], TrainingOptions::default()).unwrap();
+        model.with_prior(HashMap::from([(vec![1], 0.9), (vec![2], 0.1)]));
+
+        let likelihoods = model.compute_likelihoods(vec![], &tokens).unwrap();
+        // Half uniform, half prior
+        assert!((likelihoods[&vec![1]] - (0.5 * 0.25 + 0.5 * 0.9)).abs() < 1e-6);
+        assert!((likelihoods[&vec![3]] - 0.5 * 0.25).abs() < 1e-6);
+        assert!(likelihoods[&vec![1]] > likelihoods[&vec![2]]);
+        assert!(likelihoods[&vec![2]] > likelihoods[&vec![3]]);
+        assert!((likelihoods.values().sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_out_of_range_prior_weight_is_rejected() {
+        // Above 1 the likelihood's share turns negative, below 0 the prior's does
+        for prior_weight in [1.0000001, -0.1, f64::NAN] {
+            let options = TrainingOptions {
+                prior_weight,
+                ..TrainingOptions::default()
+            };
+            assert!(matches!(
+                UniformModel::train(vec![], options),
+                Err(ClmError::Validation(ValidationError::PriorWeight(_)))
+            ));
+        }
+    }
 }